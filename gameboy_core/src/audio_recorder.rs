@@ -0,0 +1,91 @@
+use crate::sound::audio_channel::AUDIO_CHANNELS;
+use crate::sound::high_pass_filter::HighPassFilter;
+use crate::sound::wav_writer::WavWriter;
+use crate::Gameboy;
+use std::io::{Seek, Write};
+
+struct ChannelTrack<W: Write + Seek> {
+    writer: WavWriter<W>,
+    // the channel outputs aren't centered around 0 like the mixed output is
+    filter: HighPassFilter,
+    samples: Vec<f32>,
+}
+
+// records the stereo output to a WAV file, and optionally every channel to its own mono WAV
+// file. the files are written at the sample rate of the gameboy at the time the recording
+// started
+pub struct AudioRecorder<W: Write + Seek> {
+    writer: WavWriter<W>,
+    channel_tracks: Vec<ChannelTrack<W>>,
+}
+
+impl<W: Write + Seek> AudioRecorder<W> {
+    pub fn new(gameboy: &Gameboy, writer: W) -> Result<AudioRecorder<W>, String> {
+        AudioRecorder::with_channel_tracks(gameboy, writer, Vec::new())
+    }
+
+    // the channel writers are in the order of AUDIO_CHANNELS, either all four or none
+    pub fn with_channel_tracks(
+        gameboy: &Gameboy,
+        writer: W,
+        channel_writers: Vec<W>,
+    ) -> Result<AudioRecorder<W>, String> {
+        if !channel_writers.is_empty() && channel_writers.len() != AUDIO_CHANNELS.len() {
+            return Err(format!(
+                "Expected {} channel tracks, got {}",
+                AUDIO_CHANNELS.len(),
+                channel_writers.len()
+            ));
+        }
+        let sample_rate = gameboy.get_audio_sample_rate();
+        let writer = WavWriter::new(writer, 2, sample_rate)?;
+        let mut channel_tracks = Vec::new();
+        for channel_writer in channel_writers {
+            channel_tracks.push(ChannelTrack {
+                writer: WavWriter::new(channel_writer, 1, sample_rate)?,
+                filter: HighPassFilter::new(sample_rate),
+                samples: Vec::new(),
+            });
+        }
+        Ok(AudioRecorder {
+            writer,
+            channel_tracks,
+        })
+    }
+
+    /// Records the audio of the last run_frame call
+    pub fn record_frame(&mut self, gameboy: &Gameboy) -> Result<(), String> {
+        self.writer.write_samples(gameboy.get_frame_audio())?;
+        for (track, channel) in self.channel_tracks.iter_mut().zip(AUDIO_CHANNELS.iter()) {
+            track.write_samples(gameboy.get_frame_channel_audio(*channel))?;
+        }
+        Ok(())
+    }
+
+    /// Records the audio buffer, for frontends that call emulate and handle AudioBufferFull
+    pub fn record_audio_buffer(&mut self, gameboy: &Gameboy) -> Result<(), String> {
+        self.writer.write_samples(gameboy.get_audio_buffer())?;
+        for (track, channel) in self.channel_tracks.iter_mut().zip(AUDIO_CHANNELS.iter()) {
+            track.write_samples(gameboy.get_channel_audio_buffer(*channel))?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.writer.finish()?;
+        for track in self.channel_tracks {
+            track.writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> ChannelTrack<W> {
+    fn write_samples(&mut self, samples: &[f32]) -> Result<(), String> {
+        let filter = &mut self.filter;
+        self.samples.clear();
+        self.samples
+            .extend(samples.iter().map(|sample| filter.apply(*sample)));
+        self.writer.write_samples(&self.samples)
+    }
+}
@@ -0,0 +1,29 @@
+use crate::mmu::Memory;
+
+// the CPU's view of the system, every read and write takes one M-cycle during which the rest
+// of the hardware keeps running
+pub trait Bus {
+    fn read_byte(&mut self, address: u16) -> u8;
+
+    fn write_byte(&mut self, address: u16, value: u8);
+
+    // an M-cycle in which the CPU doesn't access memory
+    fn tick(&mut self);
+
+    // brings the rest of the hardware up to date, before the CPU changes its state directly
+    fn sync_all(&mut self);
+
+    // access to memory that doesn't take any time, for registers the CPU is wired to directly
+    fn get_memory(&mut self) -> &mut Memory;
+
+    fn read_word(&mut self, address: u16) -> u16 {
+        let low = u16::from(self.read_byte(address));
+        let high = u16::from(self.read_byte(address.wrapping_add(1)));
+        (high << 8) | low
+    }
+
+    fn write_word(&mut self, address: u16, value: u16) {
+        self.write_byte(address, value as u8);
+        self.write_byte(address.wrapping_add(1), (value >> 8) as u8);
+    }
+}
@@ -1,9 +1,14 @@
+pub mod bus;
 mod registers;
 
+use self::bus::Bus;
 use self::registers::flag::Flag;
 use self::registers::Registers;
 use crate::bit_utils;
-use crate::mmu::Memory;
+use crate::mmu;
+use crate::save_state::{StateReader, StateWriter};
+
+const SPEED_SWITCH_CYCLES: i32 = 2050 * 4;
 
 const INSTRUCTION_TIMINGS: [i32; 256] = [
     4, 12, 8, 8, 4, 4, 8, 4, 20, 8, 8, 8, 4, 4, 8, 4, 4, 12, 8, 8, 4, 4, 8, 4, 12, 8, 8, 8, 4, 4,
@@ -32,10 +37,12 @@ const CB_INSTRUCTION_TIMINGS: [i32; 256] = [
 pub struct Cpu {
     registers: Registers,
     halted: bool,
+    halt_bug: bool,
+    stopped: bool,
+    speed_switch_cycles: i32,
     interrupt_enabled: bool,
     pending_enable_interrupts: i32,
     pending_disable_interrupts: i32,
-    unhalt_cycles: i32,
     instruction_cycle: i32,
     is_cgb: bool,
     cgb_speed: bool,
@@ -58,87 +65,168 @@ impl Cpu {
         Cpu {
             registers,
             halted: false,
+            halt_bug: false,
+            stopped: false,
+            speed_switch_cycles: 0,
             interrupt_enabled: false,
             pending_enable_interrupts: -1,
             pending_disable_interrupts: -1,
-            unhalt_cycles: 0,
             instruction_cycle: 0,
             is_cgb,
             cgb_speed: false,
         }
     }
 
-    pub fn unhalt(&mut self) {
-        if self.halted && self.unhalt_cycles == 0 {
-            self.unhalt_cycles = 12;
-        }
-    }
-
-    pub fn are_interrupts_enabled(&self) -> bool {
-        self.interrupt_enabled
-    }
-
-    pub fn disable_interrupts(&mut self) {
-        self.interrupt_enabled = false;
-    }
-
-    fn get_n(&mut self, memory: &Memory) -> u8 {
+    fn get_n(&mut self, memory: &mut dyn Bus) -> u8 {
         let byte = memory.read_byte(self.registers.pc);
         self.registers.pc += 1;
 
         byte
     }
 
-    fn get_nn(&mut self, memory: &Memory) -> u16 {
+    fn get_nn(&mut self, memory: &mut dyn Bus) -> u16 {
         let word = memory.read_word(self.registers.pc);
         self.registers.pc += 2;
 
         word
     }
 
-    pub fn step(&mut self, memory: &mut Memory) -> i32 {
+    // returns the cycles that have passed at the CPU clock, which runs twice as fast in
+    // CGB double speed mode
+    pub fn step(&mut self, memory: &mut dyn Bus) -> i32 {
         self.instruction_cycle = 0;
-        if !self.halted {
-            if self.pending_enable_interrupts != -1 {
-                let pending_enable_interrupts = self.pending_enable_interrupts;
-                self.pending_enable_interrupts -= 1;
-                if pending_enable_interrupts == 0 {
-                    self.pending_enable_interrupts = -1;
-                    self.interrupt_enabled = true;
-                }
+        if self.stopped {
+            // only a button press on a selected joypad line wakes the CPU up again
+            self.instruction_cycle = 4;
+            if memory.get_memory().read_byte(mmu::JOYPAD_INDEX) & 0x0F != 0x0F {
+                self.stopped = false;
             }
+            return self.instruction_cycle;
+        }
 
-            if self.pending_disable_interrupts != -1 {
-                let pending_disable_interrupts = self.pending_disable_interrupts;
-                self.pending_disable_interrupts -= 1;
-                if pending_disable_interrupts == 0 {
-                    self.pending_disable_interrupts = -1;
-                    self.interrupt_enabled = false;
-                }
+        if self.speed_switch_cycles > 0 {
+            self.instruction_cycle = 4;
+            self.speed_switch_cycles -= self.instruction_cycle;
+            return self.instruction_cycle;
+        }
+
+        if self.halted {
+            if memory.get_memory().get_interrupts().is_none() {
+                self.instruction_cycle = 4;
+                return self.instruction_cycle;
             }
+            // waking up from halt takes one M-cycle, with or without an interrupt dispatch.
+            // it passes before the dispatch or the fetch
+            self.halted = false;
+            self.instruction_cycle += 4;
+            memory.tick();
+        }
 
-            let opcode = self.get_n(memory);
-            self.instruction_cycle += INSTRUCTION_TIMINGS[opcode as usize];
-            self.execute_opcode(opcode, memory);
-        } else {
-            self.instruction_cycle = 4;
-            if self.unhalt_cycles > 0 {
-                self.unhalt_cycles -= self.instruction_cycle;
-                if self.unhalt_cycles <= 0 {
-                    self.unhalt_cycles = 0;
-                    self.halted = false;
-                }
+        if self.pending_enable_interrupts != -1 {
+            let pending_enable_interrupts = self.pending_enable_interrupts;
+            self.pending_enable_interrupts -= 1;
+            if pending_enable_interrupts == 0 {
+                self.pending_enable_interrupts = -1;
+                self.interrupt_enabled = true;
             }
         }
 
-        if self.cgb_speed {
-            self.instruction_cycle / 2
-        } else {
-            self.instruction_cycle
+        if self.pending_disable_interrupts != -1 {
+            let pending_disable_interrupts = self.pending_disable_interrupts;
+            self.pending_disable_interrupts -= 1;
+            if pending_disable_interrupts == 0 {
+                self.pending_disable_interrupts = -1;
+                self.interrupt_enabled = false;
+            }
         }
+
+        if self.interrupt_enabled && memory.get_memory().get_interrupts().is_some() {
+            self.dispatch_interrupt(memory);
+            return self.instruction_cycle;
+        }
+
+        let opcode = self.get_n(memory);
+        if self.halt_bug {
+            // the halt bug makes the CPU read the byte after HALT twice
+            self.halt_bug = false;
+            self.registers.pc = self.registers.pc.wrapping_sub(1);
+        }
+        self.instruction_cycle += INSTRUCTION_TIMINGS[opcode as usize];
+        self.execute_opcode(opcode, memory);
+
+        self.instruction_cycle
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.cgb_speed
+    }
+
+    // true while the CPU and the divider are stopped, either in STOP mode or during a speed switch
+    pub fn is_stopped(&self) -> bool {
+        self.stopped || self.speed_switch_cycles > 0
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.registers.get_af());
+        writer.write_u16(self.registers.get_bc());
+        writer.write_u16(self.registers.get_de());
+        writer.write_u16(self.registers.get_hl());
+        writer.write_u16(self.registers.pc);
+        writer.write_u16(self.registers.sp);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.halt_bug);
+        writer.write_bool(self.stopped);
+        writer.write_i32(self.speed_switch_cycles);
+        writer.write_bool(self.interrupt_enabled);
+        writer.write_i32(self.pending_enable_interrupts);
+        writer.write_i32(self.pending_disable_interrupts);
+        writer.write_bool(self.cgb_speed);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.registers.set_af(reader.read_u16());
+        self.registers.set_bc(reader.read_u16());
+        self.registers.set_de(reader.read_u16());
+        self.registers.set_hl(reader.read_u16());
+        self.registers.pc = reader.read_u16();
+        self.registers.sp = reader.read_u16();
+        self.halted = reader.read_bool();
+        self.halt_bug = reader.read_bool();
+        self.stopped = reader.read_bool();
+        self.speed_switch_cycles = reader.read_i32();
+        self.interrupt_enabled = reader.read_bool();
+        self.pending_enable_interrupts = reader.read_i32();
+        self.pending_disable_interrupts = reader.read_i32();
+        self.cgb_speed = reader.read_bool();
+    }
+
+    // dispatching takes 5 M-cycles: two wait states, two pushes and the jump to the vector.
+    // the interrupt is picked after the high byte of PC has been pushed, so a push onto IE
+    // can change it or cancel the dispatch entirely, which jumps to 0x0000
+    fn dispatch_interrupt(&mut self, memory: &mut dyn Bus) {
+        self.interrupt_enabled = false;
+        self.instruction_cycle += 20;
+        memory.tick();
+        memory.tick();
+
+        let pc = self.registers.pc;
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        memory.write_byte(self.registers.sp, (pc >> 8) as u8);
+        let interrupt = memory.get_memory().get_interrupts();
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        memory.write_byte(self.registers.sp, pc as u8);
+
+        self.registers.pc = match interrupt {
+            Some(interrupt) => {
+                let vector = 0x40 + 8 * (interrupt.clone() as u16);
+                memory.get_memory().remove_interrupt(interrupt);
+                vector
+            }
+            None => 0x0000,
+        };
     }
 
-    fn execute_opcode(&mut self, opcode: u8, memory: &mut Memory) {
+    fn execute_opcode(&mut self, opcode: u8, memory: &mut dyn Bus) {
         match opcode {
             0x00 => self.nop(),
             0x01 => {
@@ -312,7 +400,7 @@ impl Cpu {
             0x73 => self.ld_hl_e(memory),
             0x74 => self.ld_hl_h(memory),
             0x75 => self.ld_hl_l(memory),
-            0x76 => self.halt(),
+            0x76 => self.halt(memory),
             0x77 => self.ld_hl_a(memory),
             0x78 => self.ld_a_b(),
             0x79 => self.ld_a_c(),
@@ -537,7 +625,7 @@ impl Cpu {
         self.registers.set_bc(bc);
     }
 
-    fn ld_bc_a(&mut self, memory: &mut Memory) {
+    fn ld_bc_a(&mut self, memory: &mut dyn Bus) {
         let bc = self.registers.get_bc();
         self.ld_rr_r(bc, self.registers.a, memory);
     }
@@ -563,7 +651,7 @@ impl Cpu {
         self.add_hl_rr(self.registers.get_bc());
     }
 
-    fn ld_a_bc(&mut self, memory: &Memory) {
+    fn ld_a_bc(&mut self, memory: &mut dyn Bus) {
         self.registers.a = self.ld_r_rr(self.registers.get_bc(), memory);
     }
 
@@ -584,19 +672,24 @@ impl Cpu {
         self.registers.c = self.ld_r_n(n);
     }
 
-    fn stop(&mut self, memory: &mut Memory) {
-        if self.is_cgb {
-            let current_key1 = memory.load(0xFF4D);
+    fn stop(&mut self, memory: &mut dyn Bus) {
+        // STOP is followed by a padding byte that gets skipped
+        let _ = self.get_n(memory);
+        memory.sync_all();
+        memory.get_memory().set_divider(0);
 
-            if current_key1 & 1 == 1 {
-                self.cgb_speed = !self.cgb_speed;
+        let current_key1 = memory.get_memory().load(0xFF4D);
+        if self.is_cgb && current_key1 & 1 == 1 {
+            self.cgb_speed = !self.cgb_speed;
 
-                if self.cgb_speed {
-                    memory.store(0xFF4D, 0x80);
-                } else {
-                    memory.store(0xFF4D, 0x00);
-                }
+            if self.cgb_speed {
+                memory.get_memory().store(0xFF4D, 0x80);
+            } else {
+                memory.get_memory().store(0xFF4D, 0x00);
             }
+            self.speed_switch_cycles = SPEED_SWITCH_CYCLES;
+        } else {
+            self.stopped = true;
         }
     }
 
@@ -605,7 +698,7 @@ impl Cpu {
         self.registers.set_de(de);
     }
 
-    fn ld_de_a(&mut self, memory: &mut Memory) {
+    fn ld_de_a(&mut self, memory: &mut dyn Bus) {
         let de = self.registers.get_de();
         self.ld_rr_r(de, self.registers.a, memory);
     }
@@ -635,7 +728,7 @@ impl Cpu {
         self.add_hl_rr(self.registers.get_de());
     }
 
-    fn ld_a_de(&mut self, memory: &Memory) {
+    fn ld_a_de(&mut self, memory: &mut dyn Bus) {
         self.registers.a = self.ld_r_rr(self.registers.get_de(), memory);
     }
 
@@ -812,7 +905,7 @@ impl Cpu {
         self.registers.b = self.ld_r_r(self.registers.l);
     }
 
-    fn ld_b_hl(&mut self, memory: &Memory) {
+    fn ld_b_hl(&mut self, memory: &mut dyn Bus) {
         self.registers.b = self.ld_r_hl(memory);
     }
 
@@ -844,7 +937,7 @@ impl Cpu {
         self.registers.c = self.ld_r_r(self.registers.l);
     }
 
-    fn ld_c_hl(&mut self, memory: &Memory) {
+    fn ld_c_hl(&mut self, memory: &mut dyn Bus) {
         self.registers.c = self.ld_r_hl(memory);
     }
 
@@ -876,7 +969,7 @@ impl Cpu {
         self.registers.d = self.ld_r_r(self.registers.l);
     }
 
-    fn ld_d_hl(&mut self, memory: &Memory) {
+    fn ld_d_hl(&mut self, memory: &mut dyn Bus) {
         self.registers.d = self.ld_r_hl(memory);
     }
 
@@ -908,7 +1001,7 @@ impl Cpu {
         self.registers.e = self.ld_r_r(self.registers.l);
     }
 
-    fn ld_e_hl(&mut self, memory: &Memory) {
+    fn ld_e_hl(&mut self, memory: &mut dyn Bus) {
         self.registers.e = self.ld_r_hl(memory);
     }
 
@@ -940,7 +1033,7 @@ impl Cpu {
         self.registers.h = self.ld_r_r(self.registers.l);
     }
 
-    fn ld_h_hl(&mut self, memory: &Memory) {
+    fn ld_h_hl(&mut self, memory: &mut dyn Bus) {
         self.registers.h = self.ld_r_hl(memory);
     }
 
@@ -972,7 +1065,7 @@ impl Cpu {
         self.registers.l = self.ld_r_r(self.registers.l);
     }
 
-    fn ld_l_hl(&mut self, memory: &Memory) {
+    fn ld_l_hl(&mut self, memory: &mut dyn Bus) {
         self.registers.l = self.ld_r_hl(memory);
     }
 
@@ -980,31 +1073,31 @@ impl Cpu {
         self.registers.l = self.ld_r_r(self.registers.a);
     }
 
-    fn ld_hl_b(&mut self, memory: &mut Memory) {
+    fn ld_hl_b(&mut self, memory: &mut dyn Bus) {
         self.ld_hl_r(self.registers.b, memory);
     }
 
-    fn ld_hl_c(&mut self, memory: &mut Memory) {
+    fn ld_hl_c(&mut self, memory: &mut dyn Bus) {
         self.ld_hl_r(self.registers.c, memory);
     }
 
-    fn ld_hl_d(&mut self, memory: &mut Memory) {
+    fn ld_hl_d(&mut self, memory: &mut dyn Bus) {
         self.ld_hl_r(self.registers.d, memory);
     }
 
-    fn ld_hl_e(&mut self, memory: &mut Memory) {
+    fn ld_hl_e(&mut self, memory: &mut dyn Bus) {
         self.ld_hl_r(self.registers.e, memory);
     }
 
-    fn ld_hl_h(&mut self, memory: &mut Memory) {
+    fn ld_hl_h(&mut self, memory: &mut dyn Bus) {
         self.ld_hl_r(self.registers.h, memory);
     }
 
-    fn ld_hl_l(&mut self, memory: &mut Memory) {
+    fn ld_hl_l(&mut self, memory: &mut dyn Bus) {
         self.ld_hl_r(self.registers.l, memory);
     }
 
-    fn halt(&mut self) {
+    fn halt(&mut self, memory: &mut dyn Bus) {
         // if interrupt_enabled is about to be set, set it and repeat the halt instruction
         if self.pending_enable_interrupts != -1 {
             self.interrupt_enabled = true;
@@ -1014,12 +1107,16 @@ impl Cpu {
             self.interrupt_enabled = false;
             self.pending_enable_interrupts = -1;
             self.registers.pc -= 1;
+        } else if !self.interrupt_enabled && memory.get_memory().get_interrupts().is_some() {
+            // with IME off and an interrupt already pending HALT doesn't halt at all,
+            // instead PC fails to increment after the next opcode fetch
+            self.halt_bug = true;
         } else {
             self.halted = true;
         }
     }
 
-    fn ld_hl_a(&mut self, memory: &mut Memory) {
+    fn ld_hl_a(&mut self, memory: &mut dyn Bus) {
         self.ld_hl_r(self.registers.a, memory);
     }
 
@@ -1047,7 +1144,7 @@ impl Cpu {
         self.registers.a = self.ld_r_r(self.registers.l);
     }
 
-    fn ld_a_hl(&mut self, memory: &Memory) {
+    fn ld_a_hl(&mut self, memory: &mut dyn Bus) {
         self.registers.a = self.ld_r_hl(memory);
     }
 
@@ -1279,12 +1376,12 @@ impl Cpu {
         self.cp_r(self.registers.a);
     }
 
-    fn ret_nz(&mut self, memory: &Memory) {
+    fn ret_nz(&mut self, memory: &mut dyn Bus) {
         let cc = !self.registers.f.contains(Flag::ZERO);
         self.ret_cc(cc, memory);
     }
 
-    fn pop_bc(&mut self, memory: &Memory) {
+    fn pop_bc(&mut self, memory: &mut dyn Bus) {
         let bc = self.pop_nn(memory);
         self.registers.set_bc(bc);
     }
@@ -1298,12 +1395,12 @@ impl Cpu {
         self.registers.pc = nn;
     }
 
-    fn call_nz_nn(&mut self, memory: &mut Memory, nn: u16) {
+    fn call_nz_nn(&mut self, memory: &mut dyn Bus, nn: u16) {
         let cc = !self.registers.f.contains(Flag::ZERO);
         self.call_cc_nn(cc, nn, memory);
     }
 
-    fn push_bc(&mut self, memory: &mut Memory) {
+    fn push_bc(&mut self, memory: &mut dyn Bus) {
         let bc = self.registers.get_bc();
         self.push_nn(bc, memory);
     }
@@ -1312,11 +1409,11 @@ impl Cpu {
         self.add(n);
     }
 
-    fn rst_0(&mut self, memory: &mut Memory) {
+    fn rst_0(&mut self, memory: &mut dyn Bus) {
         self.rst_n(0x0, memory);
     }
 
-    fn ret_z(&mut self, memory: &Memory) {
+    fn ret_z(&mut self, memory: &mut dyn Bus) {
         let cc = self.registers.f.contains(Flag::ZERO);
         self.ret_cc(cc, memory);
     }
@@ -1326,8 +1423,10 @@ impl Cpu {
         self.jp_cc_nn(cc, nn);
     }
 
-    fn ext_ops(&mut self, opcode: u8, memory: &mut Memory) {
-        self.instruction_cycle = CB_INSTRUCTION_TIMINGS[opcode as usize];
+    fn ext_ops(&mut self, opcode: u8, memory: &mut dyn Bus) {
+        // the timings include the prefix, which is already counted
+        self.instruction_cycle +=
+            CB_INSTRUCTION_TIMINGS[opcode as usize] - INSTRUCTION_TIMINGS[0xCB];
 
         match opcode {
             0x00 => self.rlc_b(),
@@ -1589,7 +1688,7 @@ impl Cpu {
         }
     }
 
-    fn call_z_nn(&mut self, nn: u16, memory: &mut Memory) {
+    fn call_z_nn(&mut self, nn: u16, memory: &mut dyn Bus) {
         let cc = self.registers.f.contains(Flag::ZERO);
         self.call_cc_nn(cc, nn, memory);
     }
@@ -1598,16 +1697,16 @@ impl Cpu {
         self.adc(n);
     }
 
-    fn rst_8(&mut self, memory: &mut Memory) {
+    fn rst_8(&mut self, memory: &mut dyn Bus) {
         self.rst_n(0x8, memory);
     }
 
-    fn ret_nc(&mut self, memory: &Memory) {
+    fn ret_nc(&mut self, memory: &mut dyn Bus) {
         let cc = !self.registers.f.contains(Flag::FULL_CARRY);
         self.ret_cc(cc, memory);
     }
 
-    fn pop_de(&mut self, memory: &Memory) {
+    fn pop_de(&mut self, memory: &mut dyn Bus) {
         let de = self.pop_nn(memory);
         self.registers.set_de(de);
     }
@@ -1621,12 +1720,12 @@ impl Cpu {
         println!("Undefined Opcode: {:02X}!", opcode);
     }
 
-    fn call_nc_nn(&mut self, nn: u16, memory: &mut Memory) {
+    fn call_nc_nn(&mut self, nn: u16, memory: &mut dyn Bus) {
         let cc = !self.registers.f.contains(Flag::FULL_CARRY);
         self.call_cc_nn(cc, nn, memory);
     }
 
-    fn push_de(&mut self, memory: &mut Memory) {
+    fn push_de(&mut self, memory: &mut dyn Bus) {
         let de = self.registers.get_de();
         self.push_nn(de, memory);
     }
@@ -1635,27 +1734,26 @@ impl Cpu {
         self.sub(n);
     }
 
-    fn rst_10(&mut self, memory: &mut Memory) {
+    fn rst_10(&mut self, memory: &mut dyn Bus) {
         self.rst_n(0x10, memory);
     }
 
-    fn ret_c(&mut self, memory: &Memory) {
+    fn ret_c(&mut self, memory: &mut dyn Bus) {
         self.ret_cc(self.registers.f.contains(Flag::FULL_CARRY), memory);
     }
 
-    fn ret_i(&mut self, memory: &Memory) {
+    fn ret_i(&mut self, memory: &mut dyn Bus) {
         self.registers.pc = self.pop(memory);
         self.pending_disable_interrupts = -1;
         self.pending_enable_interrupts = -1;
         self.interrupt_enabled = true;
-        self.instruction_cycle = 16;
     }
 
     fn jp_c_nn(&mut self, nn: u16) {
         self.jp_cc_nn(self.registers.f.contains(Flag::FULL_CARRY), nn);
     }
 
-    fn call_c_nn(&mut self, nn: u16, memory: &mut Memory) {
+    fn call_c_nn(&mut self, nn: u16, memory: &mut dyn Bus) {
         self.call_cc_nn(self.registers.f.contains(Flag::FULL_CARRY), nn, memory);
     }
 
@@ -1663,21 +1761,21 @@ impl Cpu {
         self.sbc(n);
     }
 
-    fn rst_18(&mut self, memory: &mut Memory) {
+    fn rst_18(&mut self, memory: &mut dyn Bus) {
         self.rst_n(0x18, memory);
     }
 
-    fn pop_hl(&mut self, memory: &Memory) {
+    fn pop_hl(&mut self, memory: &mut dyn Bus) {
         let hl = self.pop_nn(memory);
         self.registers.set_hl(hl);
     }
 
-    fn push_hl(&mut self, memory: &mut Memory) {
+    fn push_hl(&mut self, memory: &mut dyn Bus) {
         let hl = self.registers.get_hl();
         self.push_nn(hl, memory);
     }
 
-    fn rst_20(&mut self, memory: &mut Memory) {
+    fn rst_20(&mut self, memory: &mut dyn Bus) {
         self.rst_n(0x20, memory);
     }
 
@@ -1686,7 +1784,7 @@ impl Cpu {
         self.registers.pc = hl;
     }
 
-    fn rst_28(&mut self, memory: &mut Memory) {
+    fn rst_28(&mut self, memory: &mut dyn Bus) {
         self.rst_n(0x28, memory);
     }
 
@@ -1697,12 +1795,12 @@ impl Cpu {
         }
     }
 
-    fn push_af(&mut self, memory: &mut Memory) {
+    fn push_af(&mut self, memory: &mut dyn Bus) {
         let af = self.registers.get_af();
         self.push_nn(af, memory);
     }
 
-    fn rst_30(&mut self, memory: &mut Memory) {
+    fn rst_30(&mut self, memory: &mut dyn Bus) {
         self.rst_n(0x30, memory);
     }
 
@@ -1713,7 +1811,7 @@ impl Cpu {
         }
     }
 
-    fn rst_38(&mut self, memory: &mut Memory) {
+    fn rst_38(&mut self, memory: &mut dyn Bus) {
         self.rst_n(0x38, memory);
     }
 
@@ -1967,7 +2065,7 @@ impl Cpu {
         self.bit_i_r(self.registers.l, 0);
     }
 
-    fn bit_0_hl(&mut self, memory: &Memory) {
+    fn bit_0_hl(&mut self, memory: &mut dyn Bus) {
         self.bit_i_hl(0, memory);
     }
 
@@ -1999,7 +2097,7 @@ impl Cpu {
         self.bit_i_r(self.registers.l, 1);
     }
 
-    fn bit_1_hl(&mut self, memory: &Memory) {
+    fn bit_1_hl(&mut self, memory: &mut dyn Bus) {
         self.bit_i_hl(1, memory);
     }
 
@@ -2031,7 +2129,7 @@ impl Cpu {
         self.bit_i_r(self.registers.l, 2);
     }
 
-    fn bit_2_hl(&mut self, memory: &Memory) {
+    fn bit_2_hl(&mut self, memory: &mut dyn Bus) {
         self.bit_i_hl(2, memory);
     }
 
@@ -2063,7 +2161,7 @@ impl Cpu {
         self.bit_i_r(self.registers.l, 3);
     }
 
-    fn bit_3_hl(&mut self, memory: &Memory) {
+    fn bit_3_hl(&mut self, memory: &mut dyn Bus) {
         self.bit_i_hl(3, memory);
     }
 
@@ -2095,7 +2193,7 @@ impl Cpu {
         self.bit_i_r(self.registers.l, 4);
     }
 
-    fn bit_4_hl(&mut self, memory: &Memory) {
+    fn bit_4_hl(&mut self, memory: &mut dyn Bus) {
         self.bit_i_hl(4, memory);
     }
 
@@ -2127,7 +2225,7 @@ impl Cpu {
         self.bit_i_r(self.registers.l, 5);
     }
 
-    fn bit_5_hl(&mut self, memory: &Memory) {
+    fn bit_5_hl(&mut self, memory: &mut dyn Bus) {
         self.bit_i_hl(5, memory);
     }
 
@@ -2159,7 +2257,7 @@ impl Cpu {
         self.bit_i_r(self.registers.l, 6);
     }
 
-    fn bit_6_hl(&mut self, memory: &Memory) {
+    fn bit_6_hl(&mut self, memory: &mut dyn Bus) {
         self.bit_i_hl(6, memory);
     }
 
@@ -2191,7 +2289,7 @@ impl Cpu {
         self.bit_i_r(self.registers.l, 7);
     }
 
-    fn bit_7_hl(&mut self, memory: &Memory) {
+    fn bit_7_hl(&mut self, memory: &mut dyn Bus) {
         self.bit_i_hl(7, memory);
     }
 
@@ -2223,7 +2321,7 @@ impl Cpu {
         self.registers.l = self.res_i_r(self.registers.l, 0);
     }
 
-    fn res_0_hl(&mut self, memory: &mut Memory) {
+    fn res_0_hl(&mut self, memory: &mut dyn Bus) {
         self.res_i_hl(0, memory);
     }
 
@@ -2255,7 +2353,7 @@ impl Cpu {
         self.registers.l = self.res_i_r(self.registers.l, 1);
     }
 
-    fn res_1_hl(&mut self, memory: &mut Memory) {
+    fn res_1_hl(&mut self, memory: &mut dyn Bus) {
         self.res_i_hl(1, memory);
     }
 
@@ -2287,7 +2385,7 @@ impl Cpu {
         self.registers.l = self.res_i_r(self.registers.l, 2);
     }
 
-    fn res_2_hl(&mut self, memory: &mut Memory) {
+    fn res_2_hl(&mut self, memory: &mut dyn Bus) {
         self.res_i_hl(2, memory);
     }
 
@@ -2319,7 +2417,7 @@ impl Cpu {
         self.registers.l = self.res_i_r(self.registers.l, 3);
     }
 
-    fn res_3_hl(&mut self, memory: &mut Memory) {
+    fn res_3_hl(&mut self, memory: &mut dyn Bus) {
         self.res_i_hl(3, memory);
     }
 
@@ -2351,7 +2449,7 @@ impl Cpu {
         self.registers.l = self.res_i_r(self.registers.l, 4);
     }
 
-    fn res_4_hl(&mut self, memory: &mut Memory) {
+    fn res_4_hl(&mut self, memory: &mut dyn Bus) {
         self.res_i_hl(4, memory);
     }
 
@@ -2383,7 +2481,7 @@ impl Cpu {
         self.registers.l = self.res_i_r(self.registers.l, 5);
     }
 
-    fn res_5_hl(&mut self, memory: &mut Memory) {
+    fn res_5_hl(&mut self, memory: &mut dyn Bus) {
         self.res_i_hl(5, memory);
     }
 
@@ -2415,7 +2513,7 @@ impl Cpu {
         self.registers.l = self.res_i_r(self.registers.l, 6);
     }
 
-    fn res_6_hl(&mut self, memory: &mut Memory) {
+    fn res_6_hl(&mut self, memory: &mut dyn Bus) {
         self.res_i_hl(6, memory);
     }
 
@@ -2447,7 +2545,7 @@ impl Cpu {
         self.registers.l = self.res_i_r(self.registers.l, 7);
     }
 
-    fn res_7_hl(&mut self, memory: &mut Memory) {
+    fn res_7_hl(&mut self, memory: &mut dyn Bus) {
         self.res_i_hl(7, memory);
     }
 
@@ -2479,7 +2577,7 @@ impl Cpu {
         self.registers.l = self.set_i_r(self.registers.l, 0);
     }
 
-    fn set_0_hl(&mut self, memory: &mut Memory) {
+    fn set_0_hl(&mut self, memory: &mut dyn Bus) {
         self.set_i_hl(0, memory);
     }
 
@@ -2511,7 +2609,7 @@ impl Cpu {
         self.registers.l = self.set_i_r(self.registers.l, 1);
     }
 
-    fn set_1_hl(&mut self, memory: &mut Memory) {
+    fn set_1_hl(&mut self, memory: &mut dyn Bus) {
         self.set_i_hl(1, memory);
     }
 
@@ -2543,7 +2641,7 @@ impl Cpu {
         self.registers.l = self.set_i_r(self.registers.l, 2);
     }
 
-    fn set_2_hl(&mut self, memory: &mut Memory) {
+    fn set_2_hl(&mut self, memory: &mut dyn Bus) {
         self.set_i_hl(2, memory);
     }
 
@@ -2575,7 +2673,7 @@ impl Cpu {
         self.registers.l = self.set_i_r(self.registers.l, 3);
     }
 
-    fn set_3_hl(&mut self, memory: &mut Memory) {
+    fn set_3_hl(&mut self, memory: &mut dyn Bus) {
         self.set_i_hl(3, memory);
     }
 
@@ -2607,7 +2705,7 @@ impl Cpu {
         self.registers.l = self.set_i_r(self.registers.l, 4);
     }
 
-    fn set_4_hl(&mut self, memory: &mut Memory) {
+    fn set_4_hl(&mut self, memory: &mut dyn Bus) {
         self.set_i_hl(4, memory);
     }
 
@@ -2639,7 +2737,7 @@ impl Cpu {
         self.registers.l = self.set_i_r(self.registers.l, 5);
     }
 
-    fn set_5_hl(&mut self, memory: &mut Memory) {
+    fn set_5_hl(&mut self, memory: &mut dyn Bus) {
         self.set_i_hl(5, memory);
     }
 
@@ -2671,7 +2769,7 @@ impl Cpu {
         self.registers.l = self.set_i_r(self.registers.l, 6);
     }
 
-    fn set_6_hl(&mut self, memory: &mut Memory) {
+    fn set_6_hl(&mut self, memory: &mut dyn Bus) {
         self.set_i_hl(6, memory);
     }
 
@@ -2703,7 +2801,7 @@ impl Cpu {
         self.registers.l = self.set_i_r(self.registers.l, 7);
     }
 
-    fn set_7_hl(&mut self, memory: &mut Memory) {
+    fn set_7_hl(&mut self, memory: &mut dyn Bus) {
         self.set_i_hl(7, memory);
     }
 
@@ -2711,28 +2809,6 @@ impl Cpu {
         self.registers.a = self.set_i_r(self.registers.a, 7);
     }
 
-    //interrupts
-
-    pub fn rst_40(&mut self, memory: &mut Memory) {
-        self.rst_n(0x40, memory);
-    }
-
-    pub fn rst_48(&mut self, memory: &mut Memory) {
-        self.rst_n(0x48, memory);
-    }
-
-    pub fn rst_50(&mut self, memory: &mut Memory) {
-        self.rst_n(0x50, memory);
-    }
-
-    pub fn rst_58(&mut self, memory: &mut Memory) {
-        self.rst_n(0x58, memory);
-    }
-
-    pub fn rst_60(&mut self, memory: &mut Memory) {
-        self.rst_n(0x60, memory);
-    }
-
     // LOADS
     fn ld_r_r(&mut self, r: u8) -> u8 {
         r
@@ -2742,35 +2818,35 @@ impl Cpu {
         n
     }
 
-    fn ld_rr_r(&mut self, rr: u16, r: u8, memory: &mut Memory) {
+    fn ld_rr_r(&mut self, rr: u16, r: u8, memory: &mut dyn Bus) {
         memory.write_byte(rr, r);
     }
 
-    fn ld_a_nn(&mut self, nn: u16, memory: &Memory) {
+    fn ld_a_nn(&mut self, nn: u16, memory: &mut dyn Bus) {
         self.registers.a = memory.read_byte(nn);
     }
 
-    fn ld_r_rr(&mut self, rr: u16, memory: &Memory) -> u8 {
+    fn ld_r_rr(&mut self, rr: u16, memory: &mut dyn Bus) -> u8 {
         memory.read_byte(rr)
     }
 
-    fn ld_hl_r(&mut self, r: u8, memory: &mut Memory) {
+    fn ld_hl_r(&mut self, r: u8, memory: &mut dyn Bus) {
         memory.write_byte(self.registers.get_hl(), r);
     }
 
-    fn ld_hl_n(&mut self, n: u8, memory: &mut Memory) {
+    fn ld_hl_n(&mut self, n: u8, memory: &mut dyn Bus) {
         memory.write_byte(self.registers.get_hl(), n);
     }
 
-    fn ld_r_hl(&mut self, memory: &Memory) -> u8 {
+    fn ld_r_hl(&mut self, memory: &mut dyn Bus) -> u8 {
         memory.read_byte(self.registers.get_hl())
     }
 
-    fn ld_nn_a(&mut self, nn: u16, memory: &mut Memory) {
+    fn ld_nn_a(&mut self, nn: u16, memory: &mut dyn Bus) {
         memory.write_byte(nn, self.registers.a);
     }
 
-    fn ldi_hl_a(&mut self, memory: &mut Memory) {
+    fn ldi_hl_a(&mut self, memory: &mut dyn Bus) {
         memory.write_byte(self.registers.get_hl(), self.registers.a);
         let hl = self.registers.get_hl().wrapping_add(1);
         self.registers.set_hl(hl);
@@ -2781,7 +2857,7 @@ impl Cpu {
         self.registers.set_hl(hl);
     }
 
-    fn ldi_a_hl(&mut self, memory: &Memory) {
+    fn ldi_a_hl(&mut self, memory: &mut dyn Bus) {
         self.registers.a = memory.read_byte(self.registers.get_hl());
         let hl = self.registers.get_hl().wrapping_add(1);
         self.registers.set_hl(hl);
@@ -2792,31 +2868,31 @@ impl Cpu {
         self.registers.set_hl(hl);
     }
 
-    fn ldd_hl_a(&mut self, memory: &mut Memory) {
+    fn ldd_hl_a(&mut self, memory: &mut dyn Bus) {
         memory.write_byte(self.registers.get_hl(), self.registers.a);
         let hl = self.registers.get_hl().wrapping_sub(1);
         self.registers.set_hl(hl);
     }
 
-    fn ldd_a_hl(&mut self, memory: &Memory) {
+    fn ldd_a_hl(&mut self, memory: &mut dyn Bus) {
         self.registers.a = memory.read_byte(self.registers.get_hl());
         let hl = self.registers.get_hl().wrapping_sub(1);
         self.registers.set_hl(hl);
     }
 
-    fn ldh_a_n(&mut self, n: u8, memory: &Memory) {
+    fn ldh_a_n(&mut self, n: u8, memory: &mut dyn Bus) {
         self.registers.a = memory.read_byte(0xFF00 + u16::from(n));
     }
 
-    fn ldh_n_a(&mut self, n: u8, memory: &mut Memory) {
+    fn ldh_n_a(&mut self, n: u8, memory: &mut dyn Bus) {
         memory.write_byte(0xFF00 + u16::from(n), self.registers.a);
     }
 
-    fn ldh_a_c(&mut self, memory: &mut Memory) {
+    fn ldh_a_c(&mut self, memory: &mut dyn Bus) {
         memory.write_byte(0xFF00 + u16::from(self.registers.c), self.registers.a);
     }
 
-    fn ldh_c_a(&mut self, memory: &mut Memory) {
+    fn ldh_c_a(&mut self, memory: &mut dyn Bus) {
         self.registers.a = memory.read_byte(0xFF00 + u16::from(self.registers.c));
     }
 
@@ -2833,31 +2909,35 @@ impl Cpu {
         self.registers.set_hl(hl);
     }
 
-    fn ld_nn_sp(&mut self, nn: u16, memory: &mut Memory) {
+    fn ld_nn_sp(&mut self, nn: u16, memory: &mut dyn Bus) {
         memory.write_word(nn, self.registers.sp);
     }
 
-    fn push(&mut self, nn: u16, memory: &mut Memory) {
-        self.registers.sp -= 2;
-        memory.write_word(self.registers.sp, nn);
+    // the internal M-cycle that decrements SP comes before the high byte is written
+    fn push(&mut self, nn: u16, memory: &mut dyn Bus) {
+        memory.tick();
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        memory.write_byte(self.registers.sp, (nn >> 8) as u8);
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        memory.write_byte(self.registers.sp, nn as u8);
     }
 
-    fn push_nn(&mut self, rr: u16, memory: &mut Memory) {
+    fn push_nn(&mut self, rr: u16, memory: &mut dyn Bus) {
         self.push(rr, memory);
     }
 
-    fn pop(&mut self, memory: &Memory) -> u16 {
+    fn pop(&mut self, memory: &mut dyn Bus) -> u16 {
         let word = memory.read_word(self.registers.sp);
         self.registers.sp += 2;
 
         word
     }
 
-    fn pop_nn(&mut self, memory: &Memory) -> u16 {
+    fn pop_nn(&mut self, memory: &mut dyn Bus) -> u16 {
         self.pop(memory)
     }
 
-    fn pop_af(&mut self, memory: &Memory) {
+    fn pop_af(&mut self, memory: &mut dyn Bus) {
         let nn = self.pop(memory);
         self.registers.set_af(nn);
     }
@@ -2880,7 +2960,7 @@ impl Cpu {
         self.add(r);
     }
 
-    fn add_hl(&mut self, memory: &Memory) {
+    fn add_hl(&mut self, memory: &mut dyn Bus) {
         let n = memory.read_byte(self.registers.get_hl());
         self.add(n);
     }
@@ -2902,7 +2982,7 @@ impl Cpu {
         self.sub(r);
     }
 
-    fn sub_hl(&mut self, memory: &Memory) {
+    fn sub_hl(&mut self, memory: &mut dyn Bus) {
         let n = memory.read_byte(self.registers.get_hl());
         self.sub(n);
     }
@@ -2932,7 +3012,7 @@ impl Cpu {
         self.sbc(r);
     }
 
-    fn sbc_hl(&mut self, memory: &Memory) {
+    fn sbc_hl(&mut self, memory: &mut dyn Bus) {
         let n = memory.read_byte(self.registers.get_hl());
         self.sbc(n);
     }
@@ -2960,7 +3040,7 @@ impl Cpu {
         self.adc(r);
     }
 
-    fn adc_hl(&mut self, memory: &Memory) {
+    fn adc_hl(&mut self, memory: &mut dyn Bus) {
         let n = memory.read_byte(self.registers.get_hl());
         self.adc(n);
     }
@@ -2981,7 +3061,7 @@ impl Cpu {
         self.and(n);
     }
 
-    fn and_hl(&mut self, memory: &Memory) {
+    fn and_hl(&mut self, memory: &mut dyn Bus) {
         let n = memory.read_byte(self.registers.get_hl());
         self.and(n);
     }
@@ -3003,7 +3083,7 @@ impl Cpu {
         self.or(n);
     }
 
-    fn or_hl(&mut self, memory: &Memory) {
+    fn or_hl(&mut self, memory: &mut dyn Bus) {
         let n = memory.read_byte(self.registers.get_hl());
         self.or(n);
     }
@@ -3025,7 +3105,7 @@ impl Cpu {
         self.xor(n);
     }
 
-    fn xor_hl(&mut self, memory: &Memory) {
+    fn xor_hl(&mut self, memory: &mut dyn Bus) {
         let n = memory.read_byte(self.registers.get_hl());
         self.xor(n);
     }
@@ -3048,7 +3128,7 @@ impl Cpu {
         self.cp(n);
     }
 
-    fn cp_hl(&mut self, memory: &Memory) {
+    fn cp_hl(&mut self, memory: &mut dyn Bus) {
         let n = memory.read_byte(self.registers.get_hl());
         self.cp(n);
     }
@@ -3069,7 +3149,7 @@ impl Cpu {
         self.inc(r)
     }
 
-    fn inc_hl_ref(&mut self, memory: &mut Memory) {
+    fn inc_hl_ref(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.inc(n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3095,7 +3175,7 @@ impl Cpu {
         self.dec(r)
     }
 
-    fn dec_hl_ref(&mut self, memory: &mut Memory) {
+    fn dec_hl_ref(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.dec(n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3164,7 +3244,7 @@ impl Cpu {
         r
     }
 
-    fn rlc_hl(&mut self, memory: &mut Memory) {
+    fn rlc_hl(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.rlc(n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3198,7 +3278,7 @@ impl Cpu {
         r
     }
 
-    fn rrc_hl(&mut self, memory: &mut Memory) {
+    fn rrc_hl(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.rrc(n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3235,7 +3315,7 @@ impl Cpu {
         r
     }
 
-    fn rr_hl(&mut self, memory: &mut Memory) {
+    fn rr_hl(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.rr(n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3271,7 +3351,7 @@ impl Cpu {
         r
     }
 
-    fn rl_hl(&mut self, memory: &mut Memory) {
+    fn rl_hl(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.rl(n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3295,7 +3375,7 @@ impl Cpu {
         self.sla(r)
     }
 
-    fn sla_hl(&mut self, memory: &mut Memory) {
+    fn sla_hl(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.sla(n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3319,7 +3399,7 @@ impl Cpu {
         self.sra(r)
     }
 
-    fn sra_hl(&mut self, memory: &mut Memory) {
+    fn sra_hl(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.sra(n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3340,7 +3420,7 @@ impl Cpu {
         self.srl(r)
     }
 
-    fn srl_hl(&mut self, memory: &mut Memory) {
+    fn srl_hl(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.srl(n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3358,7 +3438,7 @@ impl Cpu {
         self.bit_i(i, r);
     }
 
-    fn bit_i_hl(&mut self, i: u8, memory: &Memory) {
+    fn bit_i_hl(&mut self, i: u8, memory: &mut dyn Bus) {
         let n = memory.read_byte(self.registers.get_hl());
         self.bit_i(i, n);
     }
@@ -3371,7 +3451,7 @@ impl Cpu {
         self.set_i(i, r)
     }
 
-    fn set_i_hl(&mut self, i: u8, memory: &mut Memory) {
+    fn set_i_hl(&mut self, i: u8, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.set_i(i, n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3385,7 +3465,7 @@ impl Cpu {
         self.res_i(i, r)
     }
 
-    fn res_i_hl(&mut self, i: u8, memory: &mut Memory) {
+    fn res_i_hl(&mut self, i: u8, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.res_i(i, n);
         memory.write_byte(self.registers.get_hl(), n);
@@ -3393,47 +3473,48 @@ impl Cpu {
 
     // functions
 
-    fn call_nn(&mut self, nn: u16, memory: &mut Memory) {
+    fn call_nn(&mut self, nn: u16, memory: &mut dyn Bus) {
         self.push(self.registers.pc, memory);
         self.registers.pc = nn;
     }
 
-    fn call_cc_nn(&mut self, cc: bool, nn: u16, memory: &mut Memory) {
+    fn call_cc_nn(&mut self, cc: bool, nn: u16, memory: &mut dyn Bus) {
         if cc {
             self.push(self.registers.pc, memory);
             self.registers.pc = nn;
-            self.instruction_cycle = 24;
+            self.instruction_cycle += 12;
         }
     }
 
-    fn rst_n(&mut self, n: u8, memory: &mut Memory) {
+    fn rst_n(&mut self, n: u8, memory: &mut dyn Bus) {
         self.push(self.registers.pc, memory);
         self.registers.pc = u16::from(n);
     }
 
-    fn ret(&mut self, memory: &Memory) {
+    fn ret(&mut self, memory: &mut dyn Bus) {
         self.registers.pc = self.pop(memory);
-        self.instruction_cycle = 16;
     }
 
-    fn ret_cc(&mut self, cc: bool, memory: &Memory) {
+    // the condition is checked in an internal M-cycle before the pops
+    fn ret_cc(&mut self, cc: bool, memory: &mut dyn Bus) {
+        memory.tick();
         if cc {
             self.registers.pc = self.pop(memory);
-            self.instruction_cycle = 20;
+            self.instruction_cycle += 12;
         }
     }
 
     fn jp_cc_nn(&mut self, cc: bool, nn: u16) {
         if cc {
             self.registers.pc = nn;
-            self.instruction_cycle = 16;
+            self.instruction_cycle += 4;
         }
     }
 
     fn jr_cc_n(&mut self, cc: bool, n: u8) {
         if cc {
             self.registers.pc = self.registers.pc.wrapping_add(i16::from(n as i8) as u16);
-            self.instruction_cycle = 12;
+            self.instruction_cycle += 4;
         }
     }
 
@@ -3457,7 +3538,7 @@ impl Cpu {
         self.swap(r)
     }
 
-    fn swap_hl(&mut self, memory: &mut Memory) {
+    fn swap_hl(&mut self, memory: &mut dyn Bus) {
         let mut n = memory.read_byte(self.registers.get_hl());
         n = self.swap(n);
         memory.write_byte(self.registers.get_hl(), n);
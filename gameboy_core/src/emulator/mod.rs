@@ -1,14 +1,21 @@
+mod scheduler;
 pub mod step_result;
+mod system_bus;
 pub mod traits;
 
-use self::step_result::StepResult;
+use self::scheduler::{Component, Scheduler};
+use self::step_result::StepEvents;
+use self::system_bus::SystemBus;
 use self::traits::{PixelMapper, RTC};
+use crate::cpu::bus::Bus;
 use crate::cpu::Cpu;
+use crate::gpu::color_correction::ColorCorrection;
 use crate::gpu::GPU;
 use crate::joypad::Controller;
 use crate::mmu::cartridge::Cartridge;
-use crate::mmu::interrupt::Interrupt;
 use crate::mmu::Memory;
+use crate::save_state::{StateReader, StateWriter};
+use crate::sound::Sound;
 use crate::timer::Timer;
 
 pub struct Emulator {
@@ -16,6 +23,7 @@ pub struct Emulator {
     gpu: GPU,
     timer: Timer,
     memory: Memory,
+    scheduler: Scheduler,
 }
 
 impl Emulator {
@@ -26,6 +34,7 @@ impl Emulator {
             gpu: GPU::new(is_cgb),
             timer: Timer::new(),
             memory: Memory::from_cartridge(cartridge, rtc, is_cgb),
+            scheduler: Scheduler::new(),
         }
     }
 
@@ -33,45 +42,64 @@ impl Emulator {
         &mut self,
         system: &mut impl PixelMapper,
         controller: &mut Controller,
-    ) -> StepResult {
-        let cycles = self.cpu.step(&mut self.memory);
-        self.timer.update(cycles, &mut self.memory);
-        let audio_buffer_full = self.memory.get_sound_mut().step(cycles);
-        let vblank = self.gpu.step(cycles, &mut self.memory, system);
+    ) -> StepEvents {
+        let mut bus = SystemBus::new(
+            &mut self.memory,
+            &mut self.gpu,
+            &mut self.timer,
+            &mut self.scheduler,
+            system,
+        );
+        bus.set_cpu_state(self.cpu.is_double_speed(), self.cpu.is_stopped());
+        match bus.memory.take_dma_stall_cycles() {
+            Some(cycles) => bus.tick_cycles(cycles),
+            None => {
+                // memory accesses already ticked the system, the internal cycles of the
+                // instruction are left
+                let cycles = self.cpu.step(&mut bus);
+                if cycles > bus.cycles {
+                    bus.tick_cycles(cycles - bus.cycles);
+                }
+                bus.set_cpu_state(self.cpu.is_double_speed(), self.cpu.is_stopped());
+            }
+        }
+        // the audio of the frame is complete when it gets handed out at VBlank
+        if bus.vblank {
+            bus.sync_all();
+        }
+        let vblank = bus.vblank;
+        let audio_buffer_full = bus.audio_buffer_full;
+
         controller.update(&mut self.memory);
-        self.handle_interrupts();
-
-        if audio_buffer_full {
-            StepResult::AudioBufferFull
-        } else if vblank {
-            StepResult::VBlank
-        } else {
-            StepResult::Nothing
+        // turbo buttons toggle between frames, the game sees the change with the next one
+        if vblank {
+            controller.next_frame();
         }
+
+        let mut events = StepEvents::empty();
+        events.set(StepEvents::VBLANK, vblank);
+        events.set(StepEvents::AUDIO_BUFFER_FULL, audio_buffer_full);
+        events
     }
 
-    fn handle_interrupts(&mut self) {
-        if let Some(interrupt) = self.memory.get_interrupts() {
-            self.process_interrupt(interrupt);
-        }
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.cpu.save_state(writer);
+        self.gpu.save_state(writer);
+        self.memory.save_state(writer);
+        self.scheduler.save_state(writer);
     }
-    fn process_interrupt(&mut self, interrupt: Interrupt) {
-        if self.cpu.are_interrupts_enabled() {
-            self.cpu.disable_interrupts();
-            match interrupt {
-                Interrupt::Vblank => self.cpu.rst_40(&mut self.memory),
-                Interrupt::Lcd => self.cpu.rst_48(&mut self.memory),
-                Interrupt::Timer => self.cpu.rst_50(&mut self.memory),
-                Interrupt::Serial => self.cpu.rst_58(&mut self.memory),
-                Interrupt::Joypad => self.cpu.rst_60(&mut self.memory),
-            }
-            self.memory.remove_interrupt(interrupt);
-        }
-        self.cpu.unhalt();
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.cpu.load_state(reader);
+        self.gpu.load_state(reader);
+        self.memory.load_state(reader);
+        self.scheduler.load_state(reader);
+        // the audio buffer isn't part of the state, its next event has to be worked out again
+        self.scheduler.schedule(Component::Sound, 0);
     }
 
     pub fn get_cartridge(&self) -> &Cartridge {
-        &self.memory.get_cartridge()
+        self.memory.get_cartridge()
     }
 
     pub fn set_ram_change_callback(&mut self, f: Box<dyn FnMut(usize, u8)>) {
@@ -82,7 +110,46 @@ impl Emulator {
         self.memory.get_cartridge_mut()
     }
 
+    pub fn get_sound(&self) -> &Sound {
+        self.memory.get_sound()
+    }
+
+    pub fn get_sound_mut(&mut self) -> &mut Sound {
+        self.memory.get_sound_mut()
+    }
+
+    pub fn get_checksum(&self) -> u32 {
+        self.memory.get_checksum()
+    }
+
     pub fn get_audio_buffer(&self) -> &[f32] {
         self.memory.get_sound().get_audio_buffer()
     }
+
+    // the sound gets to run before its next event, so the new rate takes effect right away
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.memory.get_sound_mut().set_sample_rate(sample_rate);
+        self.scheduler.schedule(Component::Sound, 0);
+    }
+
+    pub fn get_audio_sample_rate(&self) -> u32 {
+        self.memory.get_sound().get_sample_rate()
+    }
+
+    pub fn set_audio_rate_ratio(&mut self, ratio: f64) {
+        self.memory.get_sound_mut().set_rate_ratio(ratio);
+        self.scheduler.schedule(Component::Sound, 0);
+    }
+
+    pub fn take_audio_samples(&mut self) -> &[f32] {
+        self.memory.get_sound_mut().take_audio_samples()
+    }
+
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        self.gpu.set_color_correction(color_correction);
+    }
+
+    pub fn get_color_correction(&self) -> ColorCorrection {
+        self.gpu.get_color_correction()
+    }
 }
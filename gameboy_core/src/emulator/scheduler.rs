@@ -0,0 +1,72 @@
+use crate::save_state::{StateReader, StateWriter};
+
+// the serial port isn't emulated, so it doesn't have any events
+#[derive(Copy, Clone)]
+pub enum Component {
+    Timer = 0,
+    Sound = 1,
+    Gpu = 2,
+    OamDma = 3,
+}
+
+// the OAM DMA comes before the GPU, which reads the OAM it writes to
+pub const COMPONENTS: [Component; 4] = [
+    Component::OamDma,
+    Component::Timer,
+    Component::Sound,
+    Component::Gpu,
+];
+
+// keeps track of how many cycles every component is behind the CPU and when it has to catch
+// up next. components only run when their next event is due, or when the CPU accesses them
+pub struct Scheduler {
+    pending: [i32; 4],
+    deadlines: [i32; 4],
+    pub double_speed: bool,
+    pub stopped: bool,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            pending: [0; 4],
+            deadlines: [0; 4],
+            double_speed: false,
+            stopped: false,
+        }
+    }
+
+    // returns true when the component has reached its next event
+    pub fn add_cycles(&mut self, component: Component, cycles: i32) -> bool {
+        let index = component as usize;
+        self.pending[index] += cycles;
+        self.pending[index] >= self.deadlines[index]
+    }
+
+    pub fn take_pending(&mut self, component: Component) -> i32 {
+        let index = component as usize;
+        let pending = self.pending[index];
+        self.pending[index] = 0;
+        pending
+    }
+
+    pub fn schedule(&mut self, component: Component, cycles: i32) {
+        self.deadlines[component as usize] = cycles;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for cycles in self.pending.iter().chain(self.deadlines.iter()) {
+            writer.write_i32(*cycles);
+        }
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.stopped);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        for cycles in self.pending.iter_mut().chain(self.deadlines.iter_mut()) {
+            *cycles = reader.read_i32();
+        }
+        self.double_speed = reader.read_bool();
+        self.stopped = reader.read_bool();
+    }
+}
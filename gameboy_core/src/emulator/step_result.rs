@@ -1,6 +1,29 @@
+use bitflags::bitflags;
+
 #[derive(Eq, PartialEq)]
 pub enum StepResult {
     VBlank,
     AudioBufferFull,
     Nothing,
 }
+
+bitflags! {
+    // a step can reach VBlank and fill the audio buffer at the same time
+    pub struct StepEvents : u8 {
+        const VBLANK            = 0b0000_0001;
+        const AUDIO_BUFFER_FULL = 0b0000_0010;
+    }
+}
+
+// the full audio buffer comes first, it has to be read before the next step
+impl From<StepEvents> for StepResult {
+    fn from(events: StepEvents) -> StepResult {
+        if events.contains(StepEvents::AUDIO_BUFFER_FULL) {
+            StepResult::AudioBufferFull
+        } else if events.contains(StepEvents::VBLANK) {
+            StepResult::VBlank
+        } else {
+            StepResult::Nothing
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::cpu::bus::Bus;
+use crate::emulator::scheduler::{Component, Scheduler, COMPONENTS};
+use crate::emulator::traits::PixelMapper;
+use crate::gpu::GPU;
+use crate::mmu::Memory;
+use crate::timer::Timer;
+
+// runs the rest of the hardware alongside the memory accesses of a single CPU step
+pub struct SystemBus<'a, P: PixelMapper> {
+    pub memory: &'a mut Memory,
+    gpu: &'a mut GPU,
+    timer: &'a mut Timer,
+    scheduler: &'a mut Scheduler,
+    pixel_mapper: &'a mut P,
+    pub cycles: i32,
+    pub vblank: bool,
+    pub audio_buffer_full: bool,
+}
+
+impl<'a, P: PixelMapper> SystemBus<'a, P> {
+    pub fn new(
+        memory: &'a mut Memory,
+        gpu: &'a mut GPU,
+        timer: &'a mut Timer,
+        scheduler: &'a mut Scheduler,
+        pixel_mapper: &'a mut P,
+    ) -> SystemBus<'a, P> {
+        SystemBus {
+            memory,
+            gpu,
+            timer,
+            scheduler,
+            pixel_mapper,
+            cycles: 0,
+            vblank: false,
+            audio_buffer_full: false,
+        }
+    }
+
+    // the pending cycles are counted at the old speed, so everything catches up before the
+    // switch and gets rescheduled at the new speed afterwards
+    pub fn set_cpu_state(&mut self, double_speed: bool, stopped: bool) {
+        if self.scheduler.double_speed != double_speed || self.scheduler.stopped != stopped {
+            self.sync_all();
+            self.scheduler.double_speed = double_speed;
+            self.scheduler.stopped = stopped;
+            self.sync_all();
+        }
+    }
+
+    // cycles are counted at the CPU clock. the timer and the OAM DMA run at that clock,
+    // the PPU and APU don't speed up in CGB double speed mode
+    pub fn tick_cycles(&mut self, cycles: i32) {
+        self.cycles += cycles;
+
+        if !self.scheduler.stopped {
+            if self.scheduler.add_cycles(Component::OamDma, cycles) {
+                self.sync(Component::OamDma);
+            }
+            if self.scheduler.add_cycles(Component::Timer, cycles) {
+                self.sync(Component::Timer);
+            }
+        }
+        if self.scheduler.add_cycles(Component::Sound, cycles) {
+            self.sync(Component::Sound);
+        }
+        if self.scheduler.add_cycles(Component::Gpu, cycles) {
+            self.sync(Component::Gpu);
+        }
+    }
+
+    // catches the component up with the CPU and schedules its next event
+    fn sync(&mut self, component: Component) {
+        if let Component::Gpu = component {
+            self.sync_oam_dma();
+        }
+        let cycles = self.scheduler.take_pending(component);
+        let next_event = match component {
+            Component::Timer => {
+                self.timer.update(cycles, self.memory);
+                self.timer.get_cycles_until_event(self.memory)
+            }
+            Component::Sound => {
+                let system_cycles = self.to_system_cycles(cycles);
+                if self.memory.get_sound_mut().step(system_cycles) {
+                    self.audio_buffer_full = true;
+                }
+                let next_event = self.memory.get_sound().get_cycles_until_buffer_full();
+                self.to_cpu_cycles(next_event)
+            }
+            Component::Gpu => {
+                let system_cycles = self.to_system_cycles(cycles);
+                if self.gpu.step(system_cycles, self.memory, self.pixel_mapper) {
+                    self.vblank = true;
+                }
+                let next_event = self.gpu.get_cycles_until_event(self.memory);
+                self.to_cpu_cycles(next_event)
+            }
+            Component::OamDma => {
+                self.memory.step_oam_dma(cycles);
+                self.memory.get_oam_dma_cycles_until_event()
+            }
+        };
+        self.scheduler.schedule(component, next_event);
+    }
+
+    // a running transfer is only caught up when its end is due or when something could
+    // notice it, the cycles that pass while it's idle are dropped
+    fn sync_oam_dma(&mut self) {
+        if !self.memory.is_oam_dma_idle() {
+            self.sync(Component::OamDma);
+        }
+    }
+
+    // the accesses of the CPU to the bus conflict with a running OAM DMA
+    fn sync_address(&mut self, address: u16) {
+        if address == 0xFF46 {
+            self.sync(Component::OamDma);
+        } else if address < 0xFF00 {
+            self.sync_oam_dma();
+        }
+        match address {
+            0x8000..=0x9FFF
+            | 0xFE00..=0xFEFF
+            | 0xFF40..=0xFF4F
+            | 0xFF51..=0xFF55
+            | 0xFF68..=0xFF6B => self.sync(Component::Gpu),
+            0xFF04..=0xFF07 => self.sync(Component::Timer),
+            0xFF10..=0xFF3F | 0xFF76..=0xFF77 => self.sync(Component::Sound),
+            _ => (),
+        }
+    }
+
+    fn to_system_cycles(&self, cycles: i32) -> i32 {
+        if self.scheduler.double_speed {
+            cycles / 2
+        } else {
+            cycles
+        }
+    }
+
+    fn to_cpu_cycles(&self, cycles: i32) -> i32 {
+        if self.scheduler.double_speed {
+            cycles * 2
+        } else {
+            cycles
+        }
+    }
+}
+
+impl<'a, P: PixelMapper> Bus for SystemBus<'a, P> {
+    fn read_byte(&mut self, address: u16) -> u8 {
+        self.tick_cycles(4);
+        self.sync_address(address);
+        self.memory.read_byte(address)
+    }
+
+    // a write can move the next event of the component, so it gets rescheduled afterwards
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.tick_cycles(4);
+        self.sync_address(address);
+        self.memory.write_byte(address, value);
+        self.sync_address(address);
+    }
+
+    fn tick(&mut self) {
+        self.tick_cycles(4);
+    }
+
+    fn sync_all(&mut self) {
+        for component in COMPONENTS.iter() {
+            self.sync(*component);
+        }
+    }
+
+    fn get_memory(&mut self) -> &mut Memory {
+        self.memory
+    }
+}
@@ -1,5 +1,6 @@
 use crate::gpu::cgb_color::CGBColor;
 use crate::gpu::color::Color;
+use std::rc::Rc;
 
 pub trait PixelMapper {
     fn map_pixel(&mut self, pixel: usize, color: Color);
@@ -10,3 +11,10 @@ pub trait RTC {
     // get the current unix timestamp in seconds
     fn get_current_time(&self) -> u64;
 }
+
+// lets the gameboy hold on to its clock, so it can build the cartridge again on a reset
+impl<T: RTC + ?Sized> RTC for Rc<T> {
+    fn get_current_time(&self) -> u64 {
+        (**self).get_current_time()
+    }
+}
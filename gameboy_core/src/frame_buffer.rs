@@ -0,0 +1,93 @@
+use crate::emulator::traits::PixelMapper;
+use crate::gpu::cgb_color::CGBColor;
+use crate::gpu::color::Color;
+use crate::save_state::{StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    // 4 bytes per pixel, in R, G, B, A byte order
+    Rgba8888,
+    // 2 bytes per pixel, a little endian u16 with red in the top 5 bits
+    Rgb565,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgba8888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct FrameBuffer {
+    format: PixelFormat,
+    pixels: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(format: PixelFormat) -> FrameBuffer {
+        FrameBuffer {
+            format,
+            pixels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * format.bytes_per_pixel()],
+        }
+    }
+
+    pub fn get_format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn get_pixels(&self) -> &[u8] {
+        self.pixels.as_ref()
+    }
+
+    // number of bytes in a single row of pixels
+    pub fn get_pitch(&self) -> usize {
+        SCREEN_WIDTH * self.format.bytes_per_pixel()
+    }
+
+    // the screen at the time, so it can be shown right after loading
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.pixels);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        reader.read_bytes(&mut self.pixels);
+    }
+
+    fn set_pixel(&mut self, pixel: usize, red: u8, green: u8, blue: u8) {
+        match self.format {
+            PixelFormat::Rgba8888 => {
+                let offset = pixel * 4;
+                self.pixels[offset..offset + 4].copy_from_slice(&[red, green, blue, 255]);
+            }
+            PixelFormat::Rgb565 => {
+                let offset = pixel * 2;
+                let value = (u16::from(red >> 3) << 11)
+                    | (u16::from(green >> 2) << 5)
+                    | u16::from(blue >> 3);
+                self.pixels[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+}
+
+impl PixelMapper for FrameBuffer {
+    fn map_pixel(&mut self, pixel: usize, color: Color) {
+        let shade = match color {
+            Color::White => 255,
+            Color::LightGray => 178,
+            Color::DarkGray => 102,
+            Color::Black => 0,
+        };
+        self.set_pixel(pixel, shade, shade, shade);
+    }
+
+    fn cgb_map_pixel(&mut self, pixel: usize, color: CGBColor) {
+        self.set_pixel(pixel, color.red, color.green, color.blue);
+    }
+}
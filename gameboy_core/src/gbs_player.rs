@@ -0,0 +1,315 @@
+use crate::audio_recorder::AudioRecorder;
+use crate::emulator::traits::RTC;
+use crate::sound::audio_channel::AUDIO_CHANNELS;
+use crate::Gameboy;
+use std::io::{Seek, Write};
+
+const HEADER_SIZE: usize = 0x70;
+const ENTRY_ADDRESS: u16 = 0x0150;
+const MIN_LOAD_ADDRESS: u16 = 0x0400;
+const FRAME_CYCLES: u64 = 70224;
+const CLOCK_RATE: u64 = 4_194_304;
+const TIMER_INTERRUPT_ENABLE: u8 = 0x04;
+const VBLANK_INTERRUPT_ENABLE: u8 = 0x01;
+
+// header of a Game Boy Sound System file, the code and data of the music driver follow it
+pub struct GbsFile {
+    song_count: u8,
+    first_song: u8,
+    load_address: u16,
+    init_address: u16,
+    play_address: u16,
+    stack_pointer: u16,
+    timer_modulo: u8,
+    timer_control: u8,
+    // the bytes as they are in the header, for the title of the cartridge
+    title_bytes: Vec<u8>,
+    title: String,
+    author: String,
+    copyright: String,
+    data: Vec<u8>,
+}
+
+impl GbsFile {
+    pub fn from_bytes(bytes: &[u8]) -> Result<GbsFile, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..3] != b"GBS" {
+            return Err("Not a GBS file".to_string());
+        }
+        if bytes[0x03] != 1 {
+            return Err(format!("Unsupported GBS version: {}", bytes[0x03]));
+        }
+        let read_word = |index: usize| u16::from(bytes[index]) | (u16::from(bytes[index + 1]) << 8);
+        let read_bytes = |index: usize| {
+            bytes[index..index + 32]
+                .iter()
+                .take_while(|c| **c != 0)
+                .cloned()
+                .collect::<Vec<u8>>()
+        };
+        let read_string = |index: usize| {
+            read_bytes(index)
+                .iter()
+                .map(|c| *c as char)
+                .collect::<String>()
+        };
+
+        let load_address = read_word(0x06);
+        if !(MIN_LOAD_ADDRESS..0x8000).contains(&load_address) {
+            return Err(format!("Invalid load address: 0x{:04X}", load_address));
+        }
+        let song_count = bytes[0x04];
+        if song_count == 0 {
+            return Err("GBS file has no songs".to_string());
+        }
+        // counted from 1, 0 is taken as the first song
+        if bytes[0x05] > song_count {
+            return Err(format!("Unknown first song: {}", bytes[0x05]));
+        }
+
+        Ok(GbsFile {
+            song_count,
+            first_song: bytes[0x05].max(1) - 1,
+            load_address,
+            init_address: read_word(0x08),
+            play_address: read_word(0x0A),
+            stack_pointer: read_word(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title_bytes: read_bytes(0x10),
+            title: read_string(0x10),
+            author: read_string(0x30),
+            copyright: read_string(0x50),
+            data: bytes[HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    pub fn get_song_count(&self) -> u8 {
+        self.song_count
+    }
+
+    // songs are counted from 0
+    pub fn get_first_song(&self) -> u8 {
+        self.first_song
+    }
+
+    pub fn get_title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn get_author(&self) -> &str {
+        &self.author
+    }
+
+    pub fn get_copyright(&self) -> &str {
+        &self.copyright
+    }
+
+    fn uses_timer(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    fn uses_double_speed(&self) -> bool {
+        self.timer_control & 0x80 != 0
+    }
+
+    // a cartridge that runs the driver on the real hardware. the RST vectors are moved to
+    // the load address, the VBlank or timer interrupt calls PLAY and the entry point calls
+    // INIT and then halts between the interrupts
+    fn build_rom(&self, song: u8) -> Vec<u8> {
+        let load_address = usize::from(self.load_address);
+        let rom_size = (load_address + self.data.len()).max(0x8000);
+        let rom_size = rom_size.next_power_of_two();
+        let mut rom = vec![0xFF; rom_size];
+        rom[load_address..load_address + self.data.len()].copy_from_slice(&self.data);
+
+        for vector in (0x00..0x40u16).step_by(8) {
+            let code = GbsFile::jp(self.load_address + vector);
+            GbsFile::write_code(&mut rom, usize::from(vector), &code);
+        }
+        // RETI after the CALL
+        let mut handler = GbsFile::call(self.play_address);
+        handler.push(0xD9);
+        GbsFile::write_code(&mut rom, 0x40, &handler);
+        GbsFile::write_code(&mut rom, 0x50, &handler);
+
+        GbsFile::write_code(&mut rom, 0x100, &GbsFile::jp(ENTRY_ADDRESS));
+        let title = &self.title_bytes[..self.title_bytes.len().min(15)];
+        GbsFile::write_code(&mut rom, 0x134, title);
+        rom[0x134 + title.len()] = 0x00;
+        rom[0x143] = if self.uses_double_speed() { 0x80 } else { 0x00 };
+        // MBC5 with RAM, so the driver can switch banks and use 0xA000-0xBFFF
+        rom[0x147] = 0x1A;
+        // 32 KB shifted left by the value
+        rom[0x148] = (rom_size.trailing_zeros() - 15) as u8;
+        rom[0x149] = 0x02;
+
+        let mut entry = vec![
+            // DI, LD SP,nn
+            0xF3,
+            0x31,
+            self.stack_pointer as u8,
+            (self.stack_pointer >> 8) as u8,
+            // enable the cartridge RAM
+            0x3E,
+            0x0A,
+            0xEA,
+            0x00,
+            0x00,
+        ];
+        if self.uses_double_speed() {
+            // LD A,1 / LDH (KEY1),A / STOP
+            entry.extend_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+        }
+        let interrupt_enable = if self.uses_timer() {
+            TIMER_INTERRUPT_ENABLE
+        } else {
+            VBLANK_INTERRUPT_ENABLE
+        };
+        entry.extend_from_slice(&[
+            // LD A,TMA / LDH (TMA),A / LD A,TAC / LDH (TAC),A
+            0x3E,
+            self.timer_modulo,
+            0xE0,
+            0x06,
+            0x3E,
+            self.timer_control & 0x07,
+            0xE0,
+            0x07,
+            // LD A,song
+            0x3E,
+            song,
+        ]);
+        entry.extend_from_slice(&GbsFile::call(self.init_address));
+        entry.extend_from_slice(&[
+            // XOR A / LDH (IF),A / LD A,IE / LDH (IE),A / EI
+            0xAF,
+            0xE0,
+            0x0F,
+            0x3E,
+            interrupt_enable,
+            0xE0,
+            0xFF,
+            0xFB,
+            // HALT / JR -3
+            0x76,
+            0x18,
+            0xFD,
+        ]);
+        GbsFile::write_code(&mut rom, usize::from(ENTRY_ADDRESS), &entry);
+        rom
+    }
+
+    fn write_code(rom: &mut [u8], address: usize, code: &[u8]) {
+        rom[address..address + code.len()].copy_from_slice(code);
+    }
+
+    fn jp(address: u16) -> Vec<u8> {
+        vec![0xC3, address as u8, (address >> 8) as u8]
+    }
+
+    fn call(address: u16) -> Vec<u8> {
+        vec![0xCD, address as u8, (address >> 8) as u8]
+    }
+}
+
+// the music drivers have no use for a real time clock
+struct NoRtc;
+
+impl RTC for NoRtc {
+    fn get_current_time(&self) -> u64 {
+        0
+    }
+}
+
+// plays the songs of a GBS file on a gameboy built around the music driver
+pub struct GbsPlayer {
+    file: GbsFile,
+    gameboy: Gameboy,
+    song: u8,
+}
+
+impl GbsPlayer {
+    pub fn new(file: GbsFile) -> Result<GbsPlayer, String> {
+        let song = file.get_first_song();
+        let gameboy = Gameboy::from_rom(file.build_rom(song), Box::new(NoRtc))?;
+        Ok(GbsPlayer {
+            file,
+            gameboy,
+            song,
+        })
+    }
+
+    /// Restarts the gameboy with the given song, counted from 0. The audio settings are kept
+    pub fn select_song(&mut self, song: u8) -> Result<(), String> {
+        if song >= self.file.get_song_count() {
+            return Err(format!("Unknown song: {}", song));
+        }
+        let mut gameboy = Gameboy::from_rom(self.file.build_rom(song), Box::new(NoRtc))?;
+        gameboy.set_audio_sample_rate(self.gameboy.get_audio_sample_rate());
+        for channel in AUDIO_CHANNELS.iter() {
+            gameboy.set_channel_gain(*channel, self.gameboy.get_channel_gain(*channel));
+            gameboy.set_channel_muted(*channel, self.gameboy.is_channel_muted(*channel));
+            gameboy.set_channel_solo(*channel, self.gameboy.is_channel_solo(*channel));
+        }
+        self.gameboy = gameboy;
+        self.song = song;
+        Ok(())
+    }
+
+    pub fn next_song(&mut self) -> Result<(), String> {
+        let song = if self.song + 1 >= self.file.get_song_count() {
+            0
+        } else {
+            self.song + 1
+        };
+        self.select_song(song)
+    }
+
+    pub fn previous_song(&mut self) -> Result<(), String> {
+        let song_count = self.file.get_song_count();
+        let song = if self.song == 0 {
+            song_count - 1
+        } else {
+            self.song - 1
+        };
+        self.select_song(song)
+    }
+
+    pub fn get_song(&self) -> u8 {
+        self.song
+    }
+
+    pub fn get_file(&self) -> &GbsFile {
+        &self.file
+    }
+
+    /// Access to the audio settings, channel controls and frame audio of the gameboy
+    pub fn get_gameboy(&self) -> &Gameboy {
+        &self.gameboy
+    }
+
+    pub fn get_gameboy_mut(&mut self) -> &mut Gameboy {
+        &mut self.gameboy
+    }
+
+    /// Runs for one frame and returns the interleaved stereo samples it produced
+    pub fn run_frame(&mut self) -> &[f32] {
+        self.gameboy.run_frame();
+        self.gameboy.get_frame_audio()
+    }
+
+    /// Renders the given number of seconds of the current song without any frontend
+    pub fn render_to_wav<W: Write + Seek>(
+        &mut self,
+        writer: W,
+        seconds: u32,
+    ) -> Result<(), String> {
+        let frames = u64::from(seconds) * CLOCK_RATE / FRAME_CYCLES;
+        let mut recorder = AudioRecorder::new(&self.gameboy, writer)?;
+        for _ in 0..frames {
+            self.gameboy.run_frame();
+            recorder.record_frame(&self.gameboy)?;
+        }
+        recorder.finish()
+    }
+}
@@ -0,0 +1,100 @@
+use crate::gpu::cgb_color::CGBColor;
+
+const OUTPUT_GAMMA: f32 = 2.2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum ColorCorrection {
+    // straight 5-bit to 8-bit expansion
+    #[default]
+    None,
+    // approximates the CGB LCD: gamma curve plus the crosstalk between sub-pixels
+    Accurate,
+    // approximates a CGB game running on the darker GBA LCD
+    Gba,
+}
+
+impl ColorCorrection {
+    pub fn next(self) -> ColorCorrection {
+        match self {
+            ColorCorrection::None => ColorCorrection::Accurate,
+            ColorCorrection::Accurate => ColorCorrection::Gba,
+            ColorCorrection::Gba => ColorCorrection::None,
+        }
+    }
+
+    // builds a lookup table indexed by the raw 15-bit CGB color (red | green << 5 | blue << 10)
+    pub fn build_table(self) -> Vec<CGBColor> {
+        (0..0x8000u16)
+            .map(|raw| {
+                let color = CGBColor {
+                    red: (raw & 0x1F) as u8,
+                    green: ((raw >> 5) & 0x1F) as u8,
+                    blue: ((raw >> 10) & 0x1F) as u8,
+                };
+                self.correct(color)
+            })
+            .collect()
+    }
+
+    pub fn correct(self, color: CGBColor) -> CGBColor {
+        // lcd gamma, luminance and the sub-pixel mixing matrix (rows are output channels)
+        let (lcd_gamma, luminance, matrix) = match self {
+            ColorCorrection::None => {
+                return CGBColor {
+                    red: expand_5_bit(color.red),
+                    green: expand_5_bit(color.green),
+                    blue: expand_5_bit(color.blue),
+                };
+            }
+            ColorCorrection::Accurate => (
+                2.2,
+                0.91,
+                [
+                    [0.788, 0.122, 0.0],
+                    [0.025, 0.729, 0.275],
+                    [0.120, 0.122, 0.82],
+                ],
+            ),
+            ColorCorrection::Gba => (
+                4.0,
+                0.93,
+                [
+                    [0.80, 0.275, -0.075],
+                    [0.135, 0.64, 0.225],
+                    [0.195, 0.155, 0.65],
+                ],
+            ),
+        };
+
+        let linear = [
+            linearize(color.red, lcd_gamma) * luminance,
+            linearize(color.green, lcd_gamma) * luminance,
+            linearize(color.blue, lcd_gamma) * luminance,
+        ];
+
+        let mut output = [0u8; 3];
+        for (channel, row) in output.iter_mut().zip(matrix.iter()) {
+            let mixed = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+            *channel = encode(mixed);
+        }
+
+        CGBColor {
+            red: output[0],
+            green: output[1],
+            blue: output[2],
+        }
+    }
+}
+
+fn expand_5_bit(color: u8) -> u8 {
+    ((color as i32 * 255) / 31) as u8
+}
+
+fn linearize(color: u8, gamma: f32) -> f32 {
+    (f32::from(color & 0x1F) / 31.0).powf(gamma)
+}
+
+fn encode(linear: f32) -> u8 {
+    let clamped = linear.clamp(0.0, 1.0);
+    (clamped.powf(1.0 / OUTPUT_GAMMA) * 255.0).round() as u8
+}
@@ -1,18 +1,23 @@
 mod bg_attributes;
 pub mod cgb_color;
 pub mod color;
+pub mod color_correction;
 pub mod lcd_control_flag;
+mod pixel_fifo;
 mod sprite_attributes;
 
 use self::bg_attributes::BgAttributes;
 use self::cgb_color::CGBColor;
 use self::color::Color;
+use self::color_correction::ColorCorrection;
 use self::lcd_control_flag::LcdControlFlag;
+use self::pixel_fifo::{BgPixel, FetcherStep, LineSprite, PixelFifo, SpritePixel};
 use self::sprite_attributes::SpriteAttributes;
 use crate::bit_utils;
 use crate::emulator::traits::PixelMapper;
 use crate::mmu::interrupt::Interrupt;
 use crate::mmu::{self, Memory};
+use crate::save_state::{StateReader, StateWriter};
 
 const HBLANK: u8 = 0b00;
 const VBLANK: u8 = 0b01;
@@ -20,29 +25,57 @@ const OAM_SCAN: u8 = 0b10;
 const LCD_TRANSFER: u8 = 0b11;
 
 const GAMEBOY_WIDTH: i32 = 160;
-const GAMEBOY_HEIGHT: i32 = 144;
+const LINE_DOTS: i32 = 456;
+const MAX_SPRITES_PER_LINE: usize = 10;
 
 pub struct GPU {
     is_cgb: bool,
-    background: [u8; (GAMEBOY_HEIGHT * GAMEBOY_WIDTH) as usize],
     hide_frames: i32,
-    scan_line_transferred: bool,
     vblank_line: i32,
-    tile_cycles_counter: i32,
+    hblank_dots: i32,
+    fifo: PixelFifo,
+    color_correction: ColorCorrection,
+    color_table: Vec<CGBColor>,
 }
 
 impl GPU {
     pub fn new(is_cgb: bool) -> GPU {
         GPU {
             is_cgb,
-            background: [0; (GAMEBOY_WIDTH * GAMEBOY_HEIGHT) as usize],
             hide_frames: 0,
-            scan_line_transferred: false,
             vblank_line: 0,
-            tile_cycles_counter: 0,
+            hblank_dots: 204,
+            fifo: PixelFifo::new(),
+            color_correction: ColorCorrection::None,
+            color_table: ColorCorrection::None.build_table(),
         }
     }
 
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        if self.color_correction != color_correction {
+            self.color_correction = color_correction;
+            self.color_table = color_correction.build_table();
+        }
+    }
+
+    pub fn get_color_correction(&self) -> ColorCorrection {
+        self.color_correction
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_i32(self.hide_frames);
+        writer.write_i32(self.vblank_line);
+        writer.write_i32(self.hblank_dots);
+        self.fifo.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.hide_frames = reader.read_i32();
+        self.vblank_line = reader.read_i32();
+        self.hblank_dots = reader.read_i32();
+        self.fifo.load_state(reader);
+    }
+
     // return value indicated whether a vblank has happened
     // true -> vblank has happened, render the frame buffer
     // false -> no vblank, continue stepping
@@ -51,6 +84,54 @@ impl GPU {
         cycles: i32,
         memory: &mut Memory,
         pixel_mapper: &mut impl PixelMapper,
+    ) -> bool {
+        // the cycles are split up at every mode or LY change, so this can catch up on any
+        // number of cycles at once
+        let mut vblank = false;
+        let mut cycles = cycles;
+        while cycles > 0 {
+            let chunk = std::cmp::min(cycles, self.get_cycles_until_event(memory));
+            cycles -= chunk;
+            if self.step_chunk(chunk, memory, pixel_mapper) {
+                vblank = true;
+            }
+        }
+        vblank
+    }
+
+    // cycles until the GPU changes its mode, LY or requests an interrupt, the end of mode 3
+    // isn't known in advance but it can't come before every remaining pixel got pushed
+    pub fn get_cycles_until_event(&self, memory: &Memory) -> i32 {
+        let counter = memory.gpu_cycles.cycles_counter;
+        let cycles = if memory.screen_disabled {
+            if memory.gpu_cycles.screen_enable_delay_cycles > 0 {
+                memory.gpu_cycles.screen_enable_delay_cycles
+            } else {
+                70224 - counter
+            }
+        } else {
+            match memory.lcd_status_mode {
+                HBLANK => self.hblank_dots - counter,
+                OAM_SCAN => 80 - counter,
+                LCD_TRANSFER => GAMEBOY_WIDTH - self.fifo.pixel_x,
+                _ => {
+                    let aux = memory.gpu_cycles.aux_cycles_counter;
+                    let mut cycles = std::cmp::min(456 - aux, 4560 - counter);
+                    if memory.scan_line == 153 && aux < 4 {
+                        cycles = std::cmp::min(cycles, 4 - aux);
+                    }
+                    cycles
+                }
+            }
+        };
+        std::cmp::max(cycles, 1)
+    }
+
+    fn step_chunk(
+        &mut self,
+        cycles: i32,
+        memory: &mut Memory,
+        pixel_mapper: &mut impl PixelMapper,
     ) -> bool {
         let mut vblank = false;
         memory.gpu_cycles.cycles_counter += cycles;
@@ -60,7 +141,7 @@ impl GPU {
                 HBLANK => vblank = self.step_hblank(memory),
                 VBLANK => self.step_vblank(memory, cycles),
                 OAM_SCAN => self.step_oam_scan(memory),
-                LCD_TRANSFER => self.step_lcd_transfer(memory, cycles, pixel_mapper),
+                LCD_TRANSFER => self.step_lcd_transfer(memory, pixel_mapper),
                 _ => unreachable!(),
             }
         } else if memory.gpu_cycles.screen_enable_delay_cycles > 0 {
@@ -69,7 +150,7 @@ impl GPU {
             if memory.gpu_cycles.screen_enable_delay_cycles <= 0 {
                 self.hide_frames = 3;
                 self.vblank_line = 0;
-                self.tile_cycles_counter = 0;
+                self.hblank_dots = 204;
                 memory.screen_disabled = false;
                 memory.lcd_status_mode = 0;
                 memory.scan_line = 0;
@@ -78,7 +159,7 @@ impl GPU {
                 memory.gpu_cycles.cycles_counter = 0;
                 memory.gpu_cycles.aux_cycles_counter = 0;
                 memory.gpu_cycles.window_line = 0;
-                memory.gpu_cycles.pixel_counter = 0;
+                self.fifo.window_y_triggered = false;
 
                 let stat = memory.load(mmu::LCD_INDEX);
                 if bit_utils::is_set(stat, 5) {
@@ -97,18 +178,13 @@ impl GPU {
 
     fn step_hblank(&mut self, memory: &mut Memory) -> bool {
         let mut vblank = false;
-        if memory.gpu_cycles.cycles_counter >= 204 {
-            memory.gpu_cycles.cycles_counter -= 204;
+        if memory.gpu_cycles.cycles_counter >= self.hblank_dots {
+            memory.gpu_cycles.cycles_counter -= self.hblank_dots;
             memory.lcd_status_mode = OAM_SCAN;
 
             memory.scan_line += 1;
             memory.compare_ly_to_lyc();
 
-            if self.is_cgb && memory.is_hdma_enabled() {
-                let _cycles = memory.do_hdma();
-                // memory.gpu_cycles.cycles_counter += cycles;
-            }
-
             if memory.scan_line == 144 {
                 memory.lcd_status_mode = VBLANK;
                 self.vblank_line = 0;
@@ -135,6 +211,7 @@ impl GPU {
                 }
 
                 memory.gpu_cycles.window_line = 0;
+                self.fifo.window_y_triggered = false;
             } else {
                 memory.irq48_signal &= 0x09;
                 let stat = memory.load(mmu::LCD_INDEX);
@@ -193,54 +270,43 @@ impl GPU {
     fn step_oam_scan(&mut self, memory: &mut Memory) {
         if memory.gpu_cycles.cycles_counter >= 80 {
             memory.gpu_cycles.cycles_counter -= 80;
-            memory.lcd_status_mode = 0b11;
+            memory.lcd_status_mode = LCD_TRANSFER;
             memory.irq48_signal &= 0x08;
-            self.scan_line_transferred = false;
+
+            if memory.scan_line == memory.load(mmu::WINDOW_Y_INDEX) {
+                self.fifo.window_y_triggered = true;
+            }
+            self.select_line_sprites(memory);
+            self.fifo.start_line(memory.load(mmu::SCROLL_X_INDEX));
+
             self.update_stat_register(memory);
         }
     }
 
-    fn step_lcd_transfer(
-        &mut self,
-        memory: &mut Memory,
-        cycles: i32,
-        pixel_mapper: &mut impl PixelMapper,
-    ) {
-        if memory.gpu_cycles.pixel_counter < 160 {
-            self.tile_cycles_counter += cycles;
-
-            let lcdc = LcdControlFlag::from_bits_truncate(memory.load(mmu::LCD_CONTROL_INDEX));
-            if !memory.screen_disabled && lcdc.contains(LcdControlFlag::DISPLAY) {
-                while self.tile_cycles_counter >= 3 {
-                    self.render_background(
-                        memory,
-                        i32::from(memory.scan_line),
-                        memory.gpu_cycles.pixel_counter,
-                        4,
-                        pixel_mapper,
-                    );
-                    memory.gpu_cycles.pixel_counter += 4;
-                    self.tile_cycles_counter -= 3;
-
-                    if memory.gpu_cycles.pixel_counter >= 160 {
-                        break;
-                    }
-                }
-            }
+    fn step_lcd_transfer(&mut self, memory: &mut Memory, pixel_mapper: &mut impl PixelMapper) {
+        while self.fifo.line_dots < memory.gpu_cycles.cycles_counter
+            && self.fifo.pixel_x < GAMEBOY_WIDTH
+        {
+            self.fifo.line_dots += 1;
+            self.tick_dot(memory, pixel_mapper);
         }
 
-        if memory.gpu_cycles.cycles_counter >= 160 && !self.scan_line_transferred {
-            self.scan_line(memory, i32::from(memory.scan_line), pixel_mapper);
-            self.scan_line_transferred = true;
-        }
+        if self.fifo.pixel_x >= GAMEBOY_WIDTH {
+            // mode 3 length varies with SCX, the window and sprites, hblank takes up the rest
+            let transfer_dots = self.fifo.line_dots;
+            self.hblank_dots = std::cmp::max(LINE_DOTS - 80 - transfer_dots, 0);
+            if self.fifo.window_drawn {
+                memory.gpu_cycles.window_line += 1;
+            }
 
-        if memory.gpu_cycles.cycles_counter >= 172 {
-            memory.gpu_cycles.pixel_counter = 0;
-            memory.gpu_cycles.cycles_counter -= 172;
-            memory.lcd_status_mode = 0;
-            self.tile_cycles_counter = 0;
+            memory.gpu_cycles.cycles_counter -= transfer_dots;
+            memory.lcd_status_mode = HBLANK;
             self.update_stat_register(memory);
 
+            if self.is_cgb && memory.is_hdma_enabled() {
+                memory.do_hdma();
+            }
+
             memory.irq48_signal &= 0x08;
             let stat = memory.load(mmu::LCD_INDEX);
             if bit_utils::is_set(stat, 3) {
@@ -260,463 +326,343 @@ impl GPU {
         );
     }
 
-    fn scan_line(&mut self, memory: &mut Memory, line: i32, pixel_mapper: &mut impl PixelMapper) {
+    fn select_line_sprites(&mut self, memory: &Memory) {
         let lcd_control = LcdControlFlag::from_bits_truncate(memory.load(mmu::LCD_CONTROL_INDEX));
-        if !memory.screen_disabled && lcd_control.contains(LcdControlFlag::DISPLAY) {
-            self.render_window(memory, line, pixel_mapper);
-            self.render_sprites(memory, line, pixel_mapper);
+        let sprite_height = if lcd_control.contains(LcdControlFlag::SPRITES_SIZE) {
+            16
         } else {
-            let line_width = line * GAMEBOY_WIDTH;
-            for x in 0..GAMEBOY_WIDTH {
-                let index = (line_width + x) as usize;
-                if self.is_cgb {
-                    let white = CGBColor {
-                        red: 0,
-                        green: 0,
-                        blue: 0,
-                    };
-                    pixel_mapper.cgb_map_pixel(index, white);
-                } else {
-                    pixel_mapper.map_pixel(index, Color::White);
-                }
+            8
+        };
+        let line = i32::from(memory.scan_line);
+
+        // the OAM scan only keeps the first 10 sprites that overlap the line, sprites that are
+        // hidden off screen horizontally still count towards that limit
+        self.fifo.sprites.clear();
+        for sprite in 0..40 {
+            if self.fifo.sprites.len() == MAX_SPRITES_PER_LINE {
+                break;
+            }
+            let sprite_4 = sprite * 4;
+            let y = memory.read_oam(mmu::SPRITES_START_INDEX + sprite_4);
+            let x = memory.read_oam(mmu::SPRITES_START_INDEX + sprite_4 + 1);
+            let sprite_y = i32::from(y) - 16;
+            if line >= sprite_y && line < sprite_y + sprite_height {
+                self.fifo.sprites.push(LineSprite {
+                    oam_index: sprite as u8,
+                    y,
+                    x,
+                    fetched: false,
+                });
             }
         }
-    }
-
-    fn render_background(
-        &mut self,
-        memory: &Memory,
-        line: i32,
-        pixel: i32,
-        count: i32,
-        pixel_mapper: &mut impl PixelMapper,
-    ) {
-        let offset_x_start = pixel % 8;
-        let offset_x_end = offset_x_start + count;
-        let screen_tile = pixel / 8;
-        let lcd_control = LcdControlFlag::from_bits_truncate(memory.load(mmu::LCD_CONTROL_INDEX));
-        let line_width = line * GAMEBOY_WIDTH;
-
-        if self.is_cgb || lcd_control.contains(LcdControlFlag::DISPLAY) {
-            let tile_start_addr = if lcd_control.contains(LcdControlFlag::BACKGROUND_TILE_SET) {
-                0x8000
-            } else {
-                0x8800
-            };
-
-            let map_start_addr = if lcd_control.contains(LcdControlFlag::BACKGROUND_TILE_MAP) {
-                0x9C00
-            } else {
-                0x9800
-            };
-
-            let scroll_x = memory.load(mmu::SCROLL_X_INDEX);
-            let scroll_y = memory.load(mmu::SCROLL_Y_INDEX);
-            let line_scrolled = scroll_y.wrapping_add(line as u8);
-            let line_scrolled_32 = (i32::from(line_scrolled) / 8) * 32;
-            let tile_pixel_y = i32::from(line_scrolled % 8);
-            let tile_pixel_y_2 = tile_pixel_y * 2;
-            let tile_pixel_y_flip_2 = (7 - tile_pixel_y) * 2;
-
-            for offset_x in offset_x_start..offset_x_end {
-                let screen_pixel_x = (screen_tile * 8) + offset_x;
-                let map_pixel_x = scroll_x.wrapping_add(screen_pixel_x as u8);
-                let map_tile_x = i32::from(map_pixel_x / 8);
-                let map_tile_offset_x = map_pixel_x % 8;
-                let map_tile_addr = (map_start_addr + line_scrolled_32 + map_tile_x) as u16;
-
-                let map_tile = if lcd_control.contains(LcdControlFlag::BACKGROUND_TILE_SET) {
-                    i32::from(memory.read_cgb_lcd_ram(map_tile_addr, 0))
-                } else {
-                    i32::from(memory.read_cgb_lcd_ram(map_tile_addr, 0) as i8) + 128
-                };
-
-                let cgb_tile_attrs = if self.is_cgb {
-                    BgAttributes::from_bits_truncate(memory.read_cgb_lcd_ram(map_tile_addr, 1))
-                } else {
-                    BgAttributes::empty()
-                };
-                let cgb_tile_pal = if self.is_cgb {
-                    cgb_tile_attrs.bits() & 0x07
-                } else {
-                    0
-                };
-                let cgb_tile_bank = if self.is_cgb {
-                    cgb_tile_attrs.contains(BgAttributes::VRAM_BANK)
-                } else {
-                    false
-                };
-                let cgb_tile_xflip = if self.is_cgb {
-                    cgb_tile_attrs.contains(BgAttributes::XFLIP)
-                } else {
-                    false
-                };
-                let cgb_tile_yflip = if self.is_cgb {
-                    cgb_tile_attrs.contains(BgAttributes::YFLIP)
-                } else {
-                    false
-                };
-                let cgb_tile_priority = if self.is_cgb {
-                    cgb_tile_attrs.contains(BgAttributes::BG_PRIORITY)
-                } else {
-                    false
-                };
-                let map_tile_16 = map_tile * 16;
-                let final_pixely_2 = if self.is_cgb && cgb_tile_yflip {
-                    tile_pixel_y_flip_2
-                } else {
-                    tile_pixel_y_2
-                };
-                let tile_address = (tile_start_addr + map_tile_16 + final_pixely_2) as u16;
-
-                let (byte1, byte2) = if self.is_cgb && cgb_tile_bank {
-                    (
-                        memory.read_cgb_lcd_ram(tile_address, 1),
-                        memory.read_cgb_lcd_ram(tile_address + 1, 1),
-                    )
-                } else {
-                    (
-                        memory.read_cgb_lcd_ram(tile_address, 0),
-                        memory.read_cgb_lcd_ram(tile_address + 1, 0),
-                    )
-                };
-                let mut pixel_x_in_tile = i32::from(map_tile_offset_x);
-                if self.is_cgb && cgb_tile_xflip {
-                    pixel_x_in_tile = 7 - pixel_x_in_tile;
-                }
-                let pixel_x_in_tile_bit = 1 << (7 - pixel_x_in_tile) as u8;
 
-                let mut pixel = 0;
-                if byte1 & pixel_x_in_tile_bit != 0 {
-                    pixel = bit_utils::set_bit(pixel, 0);
-                }
-                if byte2 & pixel_x_in_tile_bit != 0 {
-                    pixel = bit_utils::set_bit(pixel, 1);
-                }
-
-                let index = (line_width + screen_pixel_x) as usize;
-                self.background[index] = pixel & 0x03;
-
-                if self.is_cgb {
-                    if cgb_tile_priority && (pixel != 0) {
-                        self.background[index] = bit_utils::set_bit(self.background[index], 2);
-                    }
-                    let color =
-                        memory.cgb_background_palettes[cgb_tile_pal as usize][pixel as usize];
-                    pixel_mapper.cgb_map_pixel(index, GPU::cgb_color_to_rgb_color(color));
-                } else {
-                    let palette = memory.load(mmu::BACKGROUND_PALETTE_INDEX);
-                    let color = GPU::gb_color_from_palette(palette, pixel);
-                    pixel_mapper.map_pixel(index, color);
-                }
-            }
-        } else {
-            for x in 0..GAMEBOY_WIDTH {
-                let index = (line_width + x) as usize;
-                self.background[index] = 0;
-
-                if self.is_cgb {
-                    let white = CGBColor {
-                        red: 0,
-                        green: 0,
-                        blue: 0,
-                    };
-                    pixel_mapper.cgb_map_pixel(index, white);
-                } else {
-                    pixel_mapper.map_pixel(index, Color::White);
-                }
-            }
+        // on the DMG the sprite with the smaller x coordinate is drawn on top (the stable sort
+        // keeps OAM order for equal x), the CGB only looks at the OAM index
+        if !self.is_cgb {
+            self.fifo.sprites.sort_by_key(|sprite| sprite.x);
         }
     }
 
-    fn render_window(
-        &mut self,
-        memory: &mut Memory,
-        line: i32,
-        pixel_mapper: &mut impl PixelMapper,
-    ) {
-        if memory.gpu_cycles.window_line > 143 {
+    fn tick_dot(&mut self, memory: &Memory, pixel_mapper: &mut impl PixelMapper) {
+        if self.fifo.startup_dots > 0 {
+            self.fifo.startup_dots -= 1;
             return;
         }
 
         let lcd_control = LcdControlFlag::from_bits_truncate(memory.load(mmu::LCD_CONTROL_INDEX));
-        if !lcd_control.contains(LcdControlFlag::WINDOW) {
-            return;
-        }
 
-        let wx = i32::from(memory.load(mmu::WINDOW_X_INDEX)) - 7;
-        if wx > 159 {
+        // a sprite fetch first lets the background fetcher finish its current tile,
+        // then stalls the pipeline for another 6 dots
+        if let Some(sprite) = self.fifo.pending_sprite {
+            if self.fifo.step != FetcherStep::Push {
+                self.step_fetcher(memory, lcd_control);
+            } else {
+                self.fifo.sprite_fetch_dots -= 1;
+                if self.fifo.sprite_fetch_dots <= 0 {
+                    self.fetch_sprite(memory, lcd_control, sprite);
+                    self.fifo.pending_sprite = None;
+                }
+            }
             return;
         }
 
-        let wy = i32::from(memory.load(mmu::WINDOW_Y_INDEX));
-        if (wy > 143) || (wy > line) {
-            return;
-        }
+        let pixel_x = self.fifo.pixel_x;
 
-        let tiles = if lcd_control.contains(LcdControlFlag::BACKGROUND_TILE_SET) {
-            0x8000
-        } else {
-            0x8800
-        };
+        if !self.fifo.window_active && self.is_window_triggered(memory, lcd_control, pixel_x) {
+            self.fifo.start_window(memory.load(mmu::WINDOW_X_INDEX));
+        }
 
-        let map = if lcd_control.contains(LcdControlFlag::WINDOW_TILE_MAP) {
-            0x9C00
-        } else {
-            0x9800
-        };
+        if lcd_control.contains(LcdControlFlag::SPRITES) {
+            let next_sprite = self
+                .fifo
+                .sprites
+                .iter()
+                .position(|sprite| !sprite.fetched && i32::from(sprite.x) <= pixel_x + 8);
+            if let Some(sprite) = next_sprite {
+                self.fifo.sprites[sprite].fetched = true;
+                self.fifo.pending_sprite = Some(sprite);
+                self.fifo.sprite_fetch_dots = 6;
+                return;
+            }
+        }
 
-        let line_adjusted = memory.gpu_cycles.window_line as i32;
-        let y_32 = (line_adjusted / 8) * 32;
-        let pixely = line_adjusted % 8;
-        let pixely_2 = pixely * 2;
-        let pixely_2_flip = (7 - pixely) * 2;
-        let line_width = line * GAMEBOY_WIDTH;
+        self.step_fetcher(memory, lcd_control);
+        self.push_pixel(memory, lcd_control, pixel_mapper);
+    }
 
-        for x in 0..32 {
-            let tile = if lcd_control.contains(LcdControlFlag::BACKGROUND_TILE_SET) {
-                i32::from(memory.read_cgb_lcd_ram((map + y_32 + x) as u16, 0))
-            } else {
-                i32::from(memory.read_cgb_lcd_ram((map + y_32 + x) as u16, 0) as i8) + 128
-            };
+    fn is_window_triggered(
+        &self,
+        memory: &Memory,
+        lcd_control: LcdControlFlag,
+        pixel_x: i32,
+    ) -> bool {
+        if !lcd_control.contains(LcdControlFlag::WINDOW) || !self.fifo.window_y_triggered {
+            return false;
+        }
+        if !self.is_cgb && !lcd_control.contains(LcdControlFlag::BACKGROUND) {
+            return false;
+        }
 
-            let cgb_tile_attrs = if self.is_cgb {
-                BgAttributes::from_bits_truncate(
-                    memory.read_cgb_lcd_ram((map + y_32 + x) as u16, 1),
-                )
-            } else {
-                BgAttributes::empty()
-            };
-            let cgb_tile_pal = if self.is_cgb {
-                cgb_tile_attrs.bits() & 0x07
-            } else {
-                0
-            };
-            let cgb_tile_bank = if self.is_cgb {
-                cgb_tile_attrs.contains(BgAttributes::VRAM_BANK)
-            } else {
-                false
-            };
-            let cgb_tile_xflip = if self.is_cgb {
-                cgb_tile_attrs.contains(BgAttributes::XFLIP)
-            } else {
-                false
-            };
-            let cgb_tile_yflip = if self.is_cgb {
-                cgb_tile_attrs.contains(BgAttributes::YFLIP)
-            } else {
-                false
-            };
-            let cgb_tile_priority = if self.is_cgb {
-                cgb_tile_attrs.contains(BgAttributes::BG_PRIORITY)
-            } else {
-                false
-            };
-            let map_offset_x = x * 8;
-            let tile_16 = tile * 16;
-            let final_pixely_2 = if self.is_cgb && cgb_tile_yflip {
-                pixely_2_flip
-            } else {
-                pixely_2
-            };
-            let tile_address = (tiles + tile_16 + final_pixely_2) as u16;
-            let (byte1, byte2) = if self.is_cgb && cgb_tile_bank {
-                (
-                    memory.read_cgb_lcd_ram(tile_address, 1),
-                    memory.read_cgb_lcd_ram(tile_address + 1, 1),
-                )
-            } else {
-                (
-                    memory.read_cgb_lcd_ram(tile_address, 0),
-                    memory.read_cgb_lcd_ram(tile_address + 1, 0),
-                )
-            };
+        let wx = i32::from(memory.load(mmu::WINDOW_X_INDEX));
+        wx <= 166 && pixel_x + 7 >= wx
+    }
 
-            for pixelx in 0..8 {
-                let buffer_x = map_offset_x + pixelx + wx;
-                if buffer_x < 0 || buffer_x >= GAMEBOY_WIDTH {
-                    continue;
+    fn step_fetcher(&mut self, memory: &Memory, lcd_control: LcdControlFlag) {
+        match self.fifo.step {
+            FetcherStep::GetTile => {
+                self.fifo.step_dots += 1;
+                if self.fifo.step_dots == 2 {
+                    let map_address = self.tile_map_address(memory, lcd_control);
+                    self.fifo.tile_number = memory.read_cgb_lcd_ram(map_address, 0);
+                    self.fifo.tile_attributes = if self.is_cgb {
+                        memory.read_cgb_lcd_ram(map_address, 1)
+                    } else {
+                        0
+                    };
+                    self.fifo.step = FetcherStep::GetTileDataLow;
+                    self.fifo.step_dots = 0;
                 }
-
-                let pixelx_pos = if self.is_cgb && cgb_tile_xflip {
-                    7 - pixelx as u8
-                } else {
-                    pixelx as u8
-                };
-
-                let mut pixel = 0;
-                if (byte1 & (0x1 << (7 - pixelx_pos))) != 0 {
-                    pixel = bit_utils::set_bit(pixel, 0);
+            }
+            FetcherStep::GetTileDataLow => {
+                self.fifo.step_dots += 1;
+                if self.fifo.step_dots == 2 {
+                    let (address, bank) = self.tile_data_address(memory, lcd_control);
+                    self.fifo.tile_data_low = memory.read_cgb_lcd_ram(address, bank);
+                    self.fifo.step = FetcherStep::GetTileDataHigh;
+                    self.fifo.step_dots = 0;
                 }
-                if (byte2 & (0x1 << (7 - pixelx_pos))) != 0 {
-                    pixel = bit_utils::set_bit(pixel, 1);
-                };
-
-                let position = (line_width + buffer_x) as usize;
-                self.background[position] = pixel & 0x03;
-
-                if self.is_cgb {
-                    if cgb_tile_priority && pixel != 0 {
-                        self.background[position] =
-                            bit_utils::set_bit(self.background[position], 2);
+            }
+            FetcherStep::GetTileDataHigh => {
+                self.fifo.step_dots += 1;
+                if self.fifo.step_dots == 2 {
+                    let (address, bank) = self.tile_data_address(memory, lcd_control);
+                    self.fifo.tile_data_high = memory.read_cgb_lcd_ram(address + 1, bank);
+                    self.fifo.step = FetcherStep::Push;
+                    self.fifo.step_dots = 0;
+                }
+            }
+            FetcherStep::Push => {
+                if self.fifo.bg_fifo.is_empty() {
+                    let attributes = BgAttributes::from_bits_truncate(self.fifo.tile_attributes);
+                    let xflip = attributes.contains(BgAttributes::XFLIP);
+                    for pixel_x in 0..8 {
+                        let bit = if xflip { pixel_x } else { 7 - pixel_x };
+                        let color = ((self.fifo.tile_data_low >> bit) & 0x01)
+                            | (((self.fifo.tile_data_high >> bit) & 0x01) << 1);
+                        self.fifo.bg_fifo.push_back(BgPixel {
+                            color,
+                            palette: attributes.bits() & 0x07,
+                            priority: attributes.contains(BgAttributes::BG_PRIORITY),
+                        });
                     }
-                    let color =
-                        memory.cgb_background_palettes[cgb_tile_pal as usize][pixel as usize];
-                    pixel_mapper.cgb_map_pixel(position, GPU::cgb_color_to_rgb_color(color));
-                } else {
-                    let palette = memory.load(mmu::BACKGROUND_PALETTE_INDEX);
-                    let color = GPU::gb_color_from_palette(palette, pixel);
-                    pixel_mapper.map_pixel(position, color);
+                    self.fifo.fetcher_x = self.fifo.fetcher_x.wrapping_add(1);
+                    self.fifo.reset_fetcher();
                 }
             }
         }
-        memory.gpu_cycles.window_line += 1;
     }
 
-    fn render_sprites(&mut self, memory: &Memory, line: i32, pixel_mapper: &mut impl PixelMapper) {
-        let lcd_control = LcdControlFlag::from_bits_truncate(memory.load(mmu::LCD_CONTROL_INDEX));
-
-        if !lcd_control.contains(LcdControlFlag::SPRITES) {
-            return;
-        }
-
-        let sprite_height = if lcd_control.contains(LcdControlFlag::SPRITES_SIZE) {
-            16
+    fn tile_map_address(&self, memory: &Memory, lcd_control: LcdControlFlag) -> u16 {
+        let (map_start_addr, tile_x, tile_y) = if self.fifo.window_active {
+            let map_start_addr = if lcd_control.contains(LcdControlFlag::WINDOW_TILE_MAP) {
+                0x9C00
+            } else {
+                0x9800
+            };
+            let window_line = memory.gpu_cycles.window_line as u16;
+            (
+                map_start_addr,
+                u16::from(self.fifo.fetcher_x) & 0x1F,
+                (window_line / 8) & 0x1F,
+            )
         } else {
-            8
+            let map_start_addr = if lcd_control.contains(LcdControlFlag::BACKGROUND_TILE_MAP) {
+                0x9C00
+            } else {
+                0x9800
+            };
+            let scroll_x = memory.load(mmu::SCROLL_X_INDEX);
+            let scroll_y = memory.load(mmu::SCROLL_Y_INDEX);
+            let line_scrolled = scroll_y.wrapping_add(memory.scan_line);
+            (
+                map_start_addr,
+                (u16::from(scroll_x / 8) + u16::from(self.fifo.fetcher_x)) & 0x1F,
+                u16::from(line_scrolled / 8),
+            )
         };
+        map_start_addr + tile_y * 32 + tile_x
+    }
 
-        let line_width = line * GAMEBOY_WIDTH;
+    fn tile_data_address(&self, memory: &Memory, lcd_control: LcdControlFlag) -> (u16, i32) {
+        let tile_pixel_y = if self.fifo.window_active {
+            (memory.gpu_cycles.window_line % 8) as u16
+        } else {
+            let scroll_y = memory.load(mmu::SCROLL_Y_INDEX);
+            u16::from(scroll_y.wrapping_add(memory.scan_line) % 8)
+        };
 
-        for sprite in (0..40).rev() {
-            let sprite_4 = sprite * 4;
+        let attributes = BgAttributes::from_bits_truncate(self.fifo.tile_attributes);
+        let tile_pixel_y = if attributes.contains(BgAttributes::YFLIP) {
+            7 - tile_pixel_y
+        } else {
+            tile_pixel_y
+        };
 
-            let sprite_y = i32::from(memory.read_byte(mmu::SPRITES_START_INDEX + sprite_4)) - 16;
-            if (sprite_y > line) || (sprite_y + sprite_height) <= line {
-                continue;
-            }
+        let tile_start_addr = if lcd_control.contains(LcdControlFlag::BACKGROUND_TILE_SET) {
+            0x8000 + u16::from(self.fifo.tile_number) * 16
+        } else {
+            (0x9000 + i32::from(self.fifo.tile_number as i8) * 16) as u16
+        };
+        let bank = if attributes.contains(BgAttributes::VRAM_BANK) {
+            1
+        } else {
+            0
+        };
 
-            let sprite_x = i32::from(memory.read_byte(mmu::SPRITES_START_INDEX + sprite_4 + 1)) - 8;
-            if (sprite_x < -7) || (sprite_x >= GAMEBOY_WIDTH) {
-                continue;
-            }
+        (tile_start_addr + tile_pixel_y * 2, bank)
+    }
 
-            let sprite_tile_16 = if lcd_control.contains(LcdControlFlag::SPRITES_SIZE) {
-                i32::from(memory.read_byte((mmu::SPRITES_START_INDEX + sprite_4 + 2) as u16) & 0xFE)
-                    * 16
-            } else {
-                i32::from(memory.read_byte((mmu::SPRITES_START_INDEX + sprite_4 + 2) as u16)) * 16
-            };
+    fn fetch_sprite(&mut self, memory: &Memory, lcd_control: LcdControlFlag, sprite: usize) {
+        let sprite = self.fifo.sprites[sprite];
+        let sprite_4 = u16::from(sprite.oam_index) * 4;
+        let large_sprites = lcd_control.contains(LcdControlFlag::SPRITES_SIZE);
+        let sprite_height = if large_sprites { 16 } else { 8 };
 
-            let sprite_flags = SpriteAttributes::from_bits_truncate(
-                memory.read_byte((mmu::SPRITES_START_INDEX + sprite_4 + 3) as u16),
-            );
-
-            let sprite_pallette = sprite_flags.contains(SpriteAttributes::PALETTE);
-            let xflip = sprite_flags.contains(SpriteAttributes::X_FLIP);
-            let yflip = sprite_flags.contains(SpriteAttributes::Y_FLIP);
-            let behind_bg = sprite_flags.contains(SpriteAttributes::BACKGROUND_PRIORITY);
-            let cgb_tile_bank = sprite_flags.contains(SpriteAttributes::VRAM_BANK);
-            let cgb_tile_pal = sprite_flags.bits() & 0x07;
-            let tiles = 0x8000;
-
-            let pixel_y = if yflip {
-                let height = if lcd_control.contains(LcdControlFlag::SPRITES_SIZE) {
-                    15
-                } else {
-                    7
-                };
-                height - (line - sprite_y)
-            } else {
-                line - sprite_y
-            };
+        let tile = memory.read_oam(mmu::SPRITES_START_INDEX + sprite_4 + 2);
+        let tile = if large_sprites { tile & 0xFE } else { tile };
+        let sprite_flags = SpriteAttributes::from_bits_truncate(
+            memory.read_oam(mmu::SPRITES_START_INDEX + sprite_4 + 3),
+        );
 
-            let (pixel_y_2, offset) =
-                if lcd_control.contains(LcdControlFlag::SPRITES_SIZE) && pixel_y >= 8 {
-                    ((pixel_y - 8) * 2, 16)
-                } else {
-                    (pixel_y * 2, 0)
-                };
+        let sprite_line = i32::from(memory.scan_line) - (i32::from(sprite.y) - 16);
+        let pixel_y = if sprite_flags.contains(SpriteAttributes::Y_FLIP) {
+            sprite_height - 1 - sprite_line
+        } else {
+            sprite_line
+        };
 
-            let tile_address = (tiles + sprite_tile_16 + pixel_y_2 + offset) as u16;
+        let tile_address = 0x8000 + u16::from(tile) * 16 + (pixel_y * 2) as u16;
+        let bank = if self.is_cgb && sprite_flags.contains(SpriteAttributes::VRAM_BANK) {
+            1
+        } else {
+            0
+        };
+        let byte1 = memory.read_cgb_lcd_ram(tile_address, bank);
+        let byte2 = memory.read_cgb_lcd_ram(tile_address + 1, bank);
 
-            let (byte1, byte2) = if self.is_cgb && cgb_tile_bank {
-                (
-                    memory.read_cgb_lcd_ram(tile_address, 1),
-                    memory.read_cgb_lcd_ram(tile_address + 1, 1),
-                )
-            } else {
-                (
-                    memory.read_cgb_lcd_ram(tile_address, 0),
-                    memory.read_cgb_lcd_ram(tile_address + 1, 0),
-                )
+        let palette = if self.is_cgb {
+            sprite_flags.bits() & 0x07
+        } else if sprite_flags.contains(SpriteAttributes::PALETTE) {
+            1
+        } else {
+            0
+        };
+        let xflip = sprite_flags.contains(SpriteAttributes::X_FLIP);
+
+        let mut pixels = [SpritePixel::default(); 8];
+        for (pixel_x, pixel) in pixels.iter_mut().enumerate() {
+            let bit = if xflip { pixel_x } else { 7 - pixel_x };
+            *pixel = SpritePixel {
+                color: ((byte1 >> bit) & 0x01) | (((byte2 >> bit) & 0x01) << 1),
+                palette,
+                behind_bg: sprite_flags.contains(SpriteAttributes::BACKGROUND_PRIORITY),
+                oam_index: sprite.oam_index,
             };
+        }
 
-            for pixelx in 0..8 {
-                let mut pixel = 0;
-
-                if xflip {
-                    if byte1 & (0x01 << pixelx) != 0 {
-                        pixel = bit_utils::set_bit(pixel, 0);
-                    }
-                } else if byte1 & (0x01 << (7 - pixelx)) != 0 {
-                    pixel = bit_utils::set_bit(pixel, 0);
-                }
-
-                if xflip {
-                    if byte2 & (0x01 << pixelx) != 0 {
-                        pixel = bit_utils::set_bit(pixel, 1);
-                    }
-                } else if byte2 & (0x01 << (7 - pixelx)) != 0 {
-                    pixel = bit_utils::set_bit(pixel, 1);
-                }
-                if pixel == 0 {
-                    continue;
-                }
-
-                let buffer_x = sprite_x + pixelx as i32;
-                if buffer_x < 0 || buffer_x >= GAMEBOY_WIDTH {
-                    continue;
-                }
-
-                let position = (line_width + buffer_x) as usize;
-                let background_color = self.background[position];
+        let skip = 8usize.saturating_sub(usize::from(sprite.x));
+        self.fifo.merge_sprite(pixels, skip, self.is_cgb);
+    }
 
-                if self.is_cgb && bit_utils::is_set(background_color as u8, 2) {
-                    continue;
+    fn push_pixel(
+        &mut self,
+        memory: &Memory,
+        lcd_control: LcdControlFlag,
+        pixel_mapper: &mut impl PixelMapper,
+    ) {
+        let bg_pixel = match self.fifo.bg_fifo.pop_front() {
+            Some(bg_pixel) => bg_pixel,
+            None => return,
+        };
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        let sprite_pixel = self.fifo.sprite_fifo.pop_front();
+
+        let index = (i32::from(memory.scan_line) * GAMEBOY_WIDTH + self.fifo.pixel_x) as usize;
+        self.fifo.pixel_x += 1;
+
+        let bg_enabled = self.is_cgb || lcd_control.contains(LcdControlFlag::BACKGROUND);
+        let bg_color = if bg_enabled { bg_pixel.color } else { 0 };
+
+        // on the CGB, clearing LCDC bit 0 takes away all background priority instead of
+        // hiding the background, sprites are then always drawn on top
+        let master_priority = !self.is_cgb || lcd_control.contains(LcdControlFlag::BACKGROUND);
+        let bg_has_priority = self.is_cgb && master_priority && bg_pixel.priority && bg_color != 0;
+        let sprite_pixel = sprite_pixel.filter(|sprite_pixel| {
+            sprite_pixel.color != 0
+                && lcd_control.contains(LcdControlFlag::SPRITES)
+                && !bg_has_priority
+                && !(master_priority && sprite_pixel.behind_bg && bg_color != 0)
+        });
+
+        if self.is_cgb {
+            let color = match sprite_pixel {
+                Some(sprite_pixel) => {
+                    memory.cgb_sprite_palettes[sprite_pixel.palette as usize]
+                        [sprite_pixel.color as usize]
                 }
-
-                if behind_bg && (background_color & 0x03) != 0 {
-                    continue;
+                None => {
+                    memory.cgb_background_palettes[bg_pixel.palette as usize][bg_color as usize]
                 }
-                self.background[position] = bit_utils::set_bit(background_color, 3);
-
-                if self.is_cgb {
-                    let color = memory.cgb_sprite_palettes[cgb_tile_pal as usize][pixel as usize];
-                    pixel_mapper.cgb_map_pixel(position, GPU::cgb_color_to_rgb_color(color));
-                } else {
-                    let palette = if sprite_pallette {
+            };
+            pixel_mapper.cgb_map_pixel(index, self.cgb_color_to_rgb_color(color));
+        } else {
+            let color = match sprite_pixel {
+                Some(sprite_pixel) => {
+                    let palette = if sprite_pixel.palette == 1 {
                         memory.load(mmu::OBJECT_PALETTE_1_INDEX)
                     } else {
                         memory.load(mmu::OBJECT_PALETTE_0_INDEX)
                     };
-                    let color = GPU::gb_color_from_palette(palette, pixel);
-                    pixel_mapper.map_pixel(position, color);
+                    GPU::gb_color_from_palette(palette, sprite_pixel.color)
                 }
-            }
-        }
-    }
-
-    fn cgb_color_to_rgb_color(color: CGBColor) -> CGBColor {
-        CGBColor {
-            red: GPU::cgb_color_to_byte(color.red),
-            green: GPU::cgb_color_to_byte(color.green),
-            blue: GPU::cgb_color_to_byte(color.blue),
+                None if bg_enabled => {
+                    let palette = memory.load(mmu::BACKGROUND_PALETTE_INDEX);
+                    GPU::gb_color_from_palette(palette, bg_color)
+                }
+                None => Color::White,
+            };
+            pixel_mapper.map_pixel(index, color);
         }
     }
 
-    fn cgb_color_to_byte(color: u8) -> u8 {
-        ((color as i32 * 255) / 31) as u8
+    fn cgb_color_to_rgb_color(&self, color: CGBColor) -> CGBColor {
+        let index = usize::from(color.red & 0x1F)
+            | (usize::from(color.green & 0x1F) << 5)
+            | (usize::from(color.blue & 0x1F) << 10);
+        self.color_table[index]
     }
 
     fn gb_color_from_palette(palette: u8, pixel: u8) -> Color {
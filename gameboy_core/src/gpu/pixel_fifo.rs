@@ -0,0 +1,228 @@
+use crate::save_state::{StateReader, StateWriter};
+use std::collections::VecDeque;
+
+#[derive(Copy, Clone, Default)]
+pub struct BgPixel {
+    pub color: u8,
+    pub palette: u8,
+    pub priority: bool,
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct SpritePixel {
+    pub color: u8,
+    pub palette: u8,
+    pub behind_bg: bool,
+    pub oam_index: u8,
+}
+
+#[derive(Copy, Clone)]
+pub struct LineSprite {
+    pub oam_index: u8,
+    pub y: u8,
+    pub x: u8,
+    pub fetched: bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum FetcherStep {
+    GetTile,
+    GetTileDataLow,
+    GetTileDataHigh,
+    Push,
+}
+
+// state of the background/window fetcher and the two pixel FIFOs for the current scan line
+pub struct PixelFifo {
+    pub bg_fifo: VecDeque<BgPixel>,
+    pub sprite_fifo: VecDeque<SpritePixel>,
+    pub sprites: Vec<LineSprite>,
+    pub step: FetcherStep,
+    pub step_dots: i32,
+    pub fetcher_x: u8,
+    pub tile_number: u8,
+    pub tile_attributes: u8,
+    pub tile_data_low: u8,
+    pub tile_data_high: u8,
+    pub discard: u8,
+    pub startup_dots: i32,
+    pub sprite_fetch_dots: i32,
+    pub pending_sprite: Option<usize>,
+    pub window_active: bool,
+    pub window_drawn: bool,
+    pub window_y_triggered: bool,
+    pub line_dots: i32,
+    pub pixel_x: i32,
+}
+
+impl PixelFifo {
+    pub fn new() -> PixelFifo {
+        PixelFifo {
+            bg_fifo: VecDeque::with_capacity(16),
+            sprite_fifo: VecDeque::with_capacity(8),
+            sprites: Vec::with_capacity(40),
+            step: FetcherStep::GetTile,
+            step_dots: 0,
+            fetcher_x: 0,
+            tile_number: 0,
+            tile_attributes: 0,
+            tile_data_low: 0,
+            tile_data_high: 0,
+            discard: 0,
+            startup_dots: 0,
+            sprite_fetch_dots: 0,
+            pending_sprite: None,
+            window_active: false,
+            window_drawn: false,
+            window_y_triggered: false,
+            line_dots: 0,
+            pixel_x: 0,
+        }
+    }
+
+    // the first tile fetch of every line is thrown away, which costs 6 extra dots
+    pub fn start_line(&mut self, scroll_x: u8) {
+        self.bg_fifo.clear();
+        self.sprite_fifo.clear();
+        self.reset_fetcher();
+        self.fetcher_x = 0;
+        self.discard = scroll_x & 0x07;
+        self.startup_dots = 6;
+        self.sprite_fetch_dots = 0;
+        self.pending_sprite = None;
+        self.window_active = false;
+        self.window_drawn = false;
+        self.line_dots = 0;
+        self.pixel_x = 0;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.bg_fifo.len() as u8);
+        for pixel in self.bg_fifo.iter() {
+            writer.write_u8(pixel.color);
+            writer.write_u8(pixel.palette);
+            writer.write_bool(pixel.priority);
+        }
+        writer.write_u8(self.sprite_fifo.len() as u8);
+        for pixel in self.sprite_fifo.iter() {
+            writer.write_u8(pixel.color);
+            writer.write_u8(pixel.palette);
+            writer.write_bool(pixel.behind_bg);
+            writer.write_u8(pixel.oam_index);
+        }
+        writer.write_u8(self.sprites.len() as u8);
+        for sprite in self.sprites.iter() {
+            writer.write_u8(sprite.oam_index);
+            writer.write_u8(sprite.y);
+            writer.write_u8(sprite.x);
+            writer.write_bool(sprite.fetched);
+        }
+        writer.write_u8(match self.step {
+            FetcherStep::GetTile => 0,
+            FetcherStep::GetTileDataLow => 1,
+            FetcherStep::GetTileDataHigh => 2,
+            FetcherStep::Push => 3,
+        });
+        writer.write_i32(self.step_dots);
+        writer.write_u8(self.fetcher_x);
+        writer.write_u8(self.tile_number);
+        writer.write_u8(self.tile_attributes);
+        writer.write_u8(self.tile_data_low);
+        writer.write_u8(self.tile_data_high);
+        writer.write_u8(self.discard);
+        writer.write_i32(self.startup_dots);
+        writer.write_i32(self.sprite_fetch_dots);
+        // 0xFF for none
+        writer.write_u8(self.pending_sprite.map_or(0xFF, |sprite| sprite as u8));
+        writer.write_bool(self.window_active);
+        writer.write_bool(self.window_drawn);
+        writer.write_bool(self.window_y_triggered);
+        writer.write_i32(self.line_dots);
+        writer.write_i32(self.pixel_x);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.bg_fifo.clear();
+        for _ in 0..reader.read_u8() {
+            self.bg_fifo.push_back(BgPixel {
+                color: reader.read_u8(),
+                palette: reader.read_u8(),
+                priority: reader.read_bool(),
+            });
+        }
+        self.sprite_fifo.clear();
+        for _ in 0..reader.read_u8() {
+            self.sprite_fifo.push_back(SpritePixel {
+                color: reader.read_u8(),
+                palette: reader.read_u8(),
+                behind_bg: reader.read_bool(),
+                oam_index: reader.read_u8(),
+            });
+        }
+        self.sprites.clear();
+        for _ in 0..reader.read_u8() {
+            self.sprites.push(LineSprite {
+                oam_index: reader.read_u8(),
+                y: reader.read_u8(),
+                x: reader.read_u8(),
+                fetched: reader.read_bool(),
+            });
+        }
+        self.step = match reader.read_u8() {
+            1 => FetcherStep::GetTileDataLow,
+            2 => FetcherStep::GetTileDataHigh,
+            3 => FetcherStep::Push,
+            _ => FetcherStep::GetTile,
+        };
+        self.step_dots = reader.read_i32();
+        self.fetcher_x = reader.read_u8();
+        self.tile_number = reader.read_u8();
+        self.tile_attributes = reader.read_u8();
+        self.tile_data_low = reader.read_u8();
+        self.tile_data_high = reader.read_u8();
+        self.discard = reader.read_u8();
+        self.startup_dots = reader.read_i32();
+        self.sprite_fetch_dots = reader.read_i32();
+        self.pending_sprite = match usize::from(reader.read_u8()) {
+            sprite if sprite < self.sprites.len() => Some(sprite),
+            _ => None,
+        };
+        self.window_active = reader.read_bool();
+        self.window_drawn = reader.read_bool();
+        self.window_y_triggered = reader.read_bool();
+        self.line_dots = reader.read_i32();
+        self.pixel_x = reader.read_i32();
+    }
+
+    pub fn reset_fetcher(&mut self) {
+        self.step = FetcherStep::GetTile;
+        self.step_dots = 0;
+    }
+
+    pub fn start_window(&mut self, window_x: u8) {
+        self.bg_fifo.clear();
+        self.reset_fetcher();
+        self.fetcher_x = 0;
+        self.discard = 7u8.saturating_sub(window_x);
+        self.window_active = true;
+        self.window_drawn = true;
+    }
+
+    // overlays a fetched sprite row onto the sprite FIFO, skipping pixels that are off screen
+    // to the left. an already queued opaque pixel keeps its place, unless this is a CGB and
+    // the new pixel comes from a sprite with a lower OAM index
+    pub fn merge_sprite(&mut self, pixels: [SpritePixel; 8], skip: usize, oam_priority: bool) {
+        for (slot, pixel) in pixels.iter().skip(skip).enumerate() {
+            if slot >= self.sprite_fifo.len() {
+                self.sprite_fifo.push_back(*pixel);
+                continue;
+            }
+            let queued = self.sprite_fifo[slot];
+            let replace = queued.color == 0
+                || (oam_priority && pixel.color != 0 && pixel.oam_index < queued.oam_index);
+            if replace {
+                self.sprite_fifo[slot] = *pixel;
+            }
+        }
+    }
+}
@@ -5,8 +5,28 @@ use crate::button::Button;
 use crate::mmu::interrupt::Interrupt;
 use crate::mmu::Memory;
 
+// what the game does with left and right or up and down held down at the same time, which
+// the d-pad of a real Game Boy can't do
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OpposingDirections {
+    Allow,
+    // the direction pressed last is the one the game sees
+    LastWins,
+    // the game sees neither of them
+    Cancel,
+}
+
 pub struct Controller {
+    // the buttons as the game sees them
     released: Buttons,
+    // the buttons the player holds down, normally and with turbo
+    held: Buttons,
+    turbo: Buttons,
+    turbo_frames: u32,
+    turbo_counter: u32,
+    opposing_directions: OpposingDirections,
+    last_horizontal: Buttons,
+    last_vertical: Buttons,
     previously_unset_button_pressed: bool,
     previously_unset_direction_pressed: bool,
 }
@@ -28,6 +48,13 @@ impl Controller {
     pub const fn new() -> Controller {
         Controller {
             released: Buttons::all(),
+            held: Buttons::empty(),
+            turbo: Buttons::empty(),
+            turbo_frames: 2,
+            turbo_counter: 0,
+            opposing_directions: OpposingDirections::Allow,
+            last_horizontal: Buttons::empty(),
+            last_vertical: Buttons::empty(),
             previously_unset_button_pressed: false,
             previously_unset_direction_pressed: false,
         }
@@ -47,20 +74,127 @@ impl Controller {
     }
 
     pub fn press(&mut self, button: Button) {
-        let button = button_to_buttons(button);
-        if self.released.contains(button) {
-            let action_keys = Buttons::A | Buttons::B | Buttons::START | Buttons::SELECT;
-            self.previously_unset_button_pressed = action_keys.contains(button);
+        self.set_last_direction(button);
+        self.held.insert(button_to_buttons(button));
+        self.update_pressed();
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.held.remove(button_to_buttons(button));
+        self.update_pressed();
+    }
 
-            let direction_keys = Buttons::UP | Buttons::DOWN | Buttons::LEFT | Buttons::RIGHT;
-            self.previously_unset_direction_pressed = direction_keys.contains(button);
+    // the button is pressed and released again every few frames while it is held down
+    pub fn press_turbo(&mut self, button: Button) {
+        if self.turbo.is_empty() {
+            self.turbo_counter = 0;
         }
+        self.set_last_direction(button);
+        self.turbo.insert(button_to_buttons(button));
+        self.update_pressed();
+    }
 
-        self.released.remove(button);
+    pub fn release_turbo(&mut self, button: Button) {
+        self.turbo.remove(button_to_buttons(button));
+        self.update_pressed();
     }
 
-    pub fn release(&mut self, button: Button) {
-        let button = button_to_buttons(button);
-        self.released.insert(button);
+    // the number of frames a turbo button stays pressed, and then released
+    pub fn set_turbo_frames(&mut self, frames: u32) {
+        self.turbo_frames = frames.max(1);
+    }
+
+    pub fn get_turbo_frames(&self) -> u32 {
+        self.turbo_frames
+    }
+
+    pub fn set_opposing_directions(&mut self, opposing_directions: OpposingDirections) {
+        self.opposing_directions = opposing_directions;
+        self.update_pressed();
+    }
+
+    pub fn get_opposing_directions(&self) -> OpposingDirections {
+        self.opposing_directions
+    }
+
+    // called at every VBlank, the turbo buttons run on the emulated frames
+    pub fn next_frame(&mut self) {
+        if !self.turbo.is_empty() {
+            self.turbo_counter = self.turbo_counter.wrapping_add(1);
+            self.update_pressed();
+        }
+    }
+
+    // lets go of every button, the settings are kept
+    pub fn reset(&mut self) {
+        self.held = Buttons::empty();
+        self.turbo = Buttons::empty();
+        self.turbo_counter = 0;
+        self.last_horizontal = Buttons::empty();
+        self.last_vertical = Buttons::empty();
+        self.released = Buttons::all();
+        self.previously_unset_button_pressed = false;
+        self.previously_unset_direction_pressed = false;
+    }
+
+    fn set_last_direction(&mut self, button: Button) {
+        match button {
+            Button::Left | Button::Right => self.last_horizontal = button_to_buttons(button),
+            Button::Up | Button::Down => self.last_vertical = button_to_buttons(button),
+            _ => (),
+        }
+    }
+
+    fn update_pressed(&mut self) {
+        let mut pressed = self.held;
+        if (self.turbo_counter / self.turbo_frames) & 1 == 0 {
+            pressed |= self.turbo;
+        }
+        pressed = self.filter_opposing(
+            pressed,
+            Buttons::LEFT | Buttons::RIGHT,
+            self.last_horizontal,
+        );
+        pressed = self.filter_opposing(pressed, Buttons::UP | Buttons::DOWN, self.last_vertical);
+        self.set_pressed(pressed);
+    }
+
+    fn filter_opposing(&self, pressed: Buttons, directions: Buttons, last: Buttons) -> Buttons {
+        if !pressed.contains(directions) {
+            return pressed;
+        }
+        match self.opposing_directions {
+            OpposingDirections::Allow => pressed,
+            OpposingDirections::LastWins => pressed - (directions - last),
+            OpposingDirections::Cancel => pressed - directions,
+        }
+    }
+
+    // a button that wasn't pressed before can trigger the joypad interrupt
+    fn set_pressed(&mut self, pressed: Buttons) {
+        let newly_pressed = pressed & self.released;
+        let action_keys = Buttons::A | Buttons::B | Buttons::START | Buttons::SELECT;
+        if newly_pressed.intersects(action_keys) {
+            self.previously_unset_button_pressed = true;
+        }
+        let direction_keys = Buttons::UP | Buttons::DOWN | Buttons::LEFT | Buttons::RIGHT;
+        if newly_pressed.intersects(direction_keys) {
+            self.previously_unset_direction_pressed = true;
+        }
+        self.released = !pressed;
+    }
+
+    // one bit per button, set while the game sees it held down. right is bit 0, followed by
+    // left, up, down, A, B, select and start
+    pub fn get_state(&self) -> u8 {
+        !self.released.bits()
+    }
+
+    // the state is taken as it is, without turbo or the opposing direction policy, so a
+    // movie plays back exactly what the game saw
+    pub fn set_state(&mut self, state: u8) {
+        self.held = Buttons::from_bits_truncate(state);
+        self.turbo = Buttons::empty();
+        self.set_pressed(self.held);
     }
 }
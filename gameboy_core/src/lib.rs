@@ -1,46 +1,289 @@
+pub mod audio_recorder;
 mod bit_utils;
 pub mod button;
 pub mod controller_event;
 mod cpu;
 pub mod emulator;
+pub mod frame_buffer;
+pub mod gbs_player;
 mod gpu;
 mod joypad;
 mod mmu;
+pub mod movie;
 pub mod rtc;
+mod save_state;
 pub mod sound;
 mod timer;
+pub mod vgm;
 
+pub use crate::audio_recorder::AudioRecorder;
 pub use crate::button::Button;
 pub use crate::controller_event::ControllerEvent;
-pub use crate::emulator::step_result::StepResult;
+pub use crate::emulator::step_result::{StepEvents, StepResult};
 pub use crate::emulator::traits::{PixelMapper, RTC};
 
 use crate::emulator::Emulator;
+pub use crate::frame_buffer::{FrameBuffer, PixelFormat};
+pub use crate::gbs_player::{GbsFile, GbsPlayer};
 pub use crate::gpu::cgb_color::CGBColor;
 pub use crate::gpu::color::Color;
-pub use crate::joypad::Controller;
+pub use crate::gpu::color_correction::ColorCorrection;
+pub use crate::joypad::{Controller, OpposingDirections};
 pub use crate::mmu::cartridge::Cartridge;
+use crate::movie::{crc32, MovieRtc};
+pub use crate::movie::{Movie, MovieAnchor};
 pub use crate::rtc::Rtc;
+use crate::save_state::{StateReader, StateWriter};
+pub use crate::sound::audio_channel::{AudioChannel, AUDIO_CHANNELS};
+pub use crate::sound::rate_control::RateControl;
+pub use crate::vgm::{VgmPlayer, VgmWriter};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+type RamChangeCallback = Box<dyn FnMut(usize, u8)>;
+
+enum MovieMode {
+    Recording,
+    Playing,
+}
+
 pub struct Gameboy {
     emulator: Emulator,
     controller: Controller,
+    frame_buffer: FrameBuffer,
+    frame_audio: Vec<f32>,
+    frame_channel_audio: [Vec<f32>; 4],
+    vgm_writer: Option<Rc<RefCell<VgmWriter>>>,
+    // kept for building the emulator again on a reset
+    rtc: Rc<dyn RTC>,
+    ram_change_callback: Rc<RefCell<RamChangeCallback>>,
+    // frames run since the power was turned on, the clock of a movie
+    frames: Rc<Cell<u64>>,
+    movie: Option<Movie>,
+    movie_mode: MovieMode,
+    movie_frame: usize,
+    movie_desync_frame: Option<usize>,
 }
 impl Gameboy {
     /// Loads game from rom. Needs a Real Time Clock
     pub fn from_rom(rom: Vec<u8>, rtc: Box<dyn RTC>) -> Result<Gameboy, String> {
         let cartridge = Cartridge::from_rom(rom)?;
-        Ok(Gameboy {
-            emulator: Emulator::from_cartridge(cartridge, rtc),
+        let rtc: Rc<dyn RTC> = Rc::from(rtc);
+        let mut gameboy = Gameboy {
+            emulator: Emulator::from_cartridge(cartridge, Box::new(rtc.clone())),
             controller: Controller::new(),
-        })
+            frame_buffer: FrameBuffer::new(PixelFormat::Rgba8888),
+            frame_audio: Vec::new(),
+            frame_channel_audio: Default::default(),
+            vgm_writer: None,
+            rtc,
+            ram_change_callback: Rc::new(RefCell::new(Box::new(|_, _| {}))),
+            frames: Rc::new(Cell::new(0)),
+            movie: None,
+            movie_mode: MovieMode::Recording,
+            movie_frame: 0,
+            movie_desync_frame: None,
+        };
+        gameboy.forward_ram_changes();
+        Ok(gameboy)
+    }
+    fn forward_ram_changes(&mut self) {
+        let ram_change_callback = self.ram_change_callback.clone();
+        self.emulator
+            .set_ram_change_callback(Box::new(move |address, value| {
+                (ram_change_callback.borrow_mut())(address, value)
+            }));
+    }
+    /// Restarts the game as if the power was cycled. The cartridge RAM and the settings are
+    /// kept, a VGM log is stopped
+    pub fn reset(&mut self) -> Result<(), String> {
+        let rtc = Box::new(self.rtc.clone());
+        self.power_cycle(rtc)
+    }
+    fn power_cycle(&mut self, rtc: Box<dyn RTC>) -> Result<(), String> {
+        let old_cartridge = self.emulator.get_cartridge();
+        let mut cartridge = Cartridge::from_rom(old_cartridge.get_rom().to_vec())?;
+        cartridge.set_ram(old_cartridge.get_ram().to_vec());
+        let (rtc_data, rtc_last_time) = old_cartridge.get_last_timestamp();
+        cartridge.set_last_timestamp(rtc_data, rtc_last_time);
+
+        let mut emulator = Emulator::from_cartridge(cartridge, rtc);
+        emulator.set_audio_sample_rate(self.emulator.get_audio_sample_rate());
+        emulator.set_color_correction(self.emulator.get_color_correction());
+        let sound = self.emulator.get_sound();
+        for channel in AUDIO_CHANNELS.iter() {
+            let new_sound = emulator.get_sound_mut();
+            new_sound.set_channel_gain(*channel, sound.get_channel_gain(*channel));
+            new_sound.set_channel_muted(*channel, sound.is_channel_muted(*channel));
+            new_sound.set_channel_solo(*channel, sound.is_channel_solo(*channel));
+        }
+        self.emulator = emulator;
+        self.forward_ram_changes();
+        self.vgm_writer = None;
+        self.controller.reset();
+        self.frames.set(0);
+        Ok(())
     }
     /// Run emulation step
     pub fn emulate(&mut self, system: &mut impl PixelMapper) -> emulator::step_result::StepResult {
-        self.emulator.emulate(system, &mut self.controller)
+        self.emulator.emulate(system, &mut self.controller).into()
+    }
+    /// Runs emulation until the next VBlank, rendering into the built-in frame buffer. A
+    /// movie is recorded or played back one of these frames at a time
+    pub fn run_frame(&mut self) -> &FrameBuffer {
+        if self.is_playing_movie() {
+            if let Some(input) = self
+                .movie
+                .as_ref()
+                .and_then(|m| m.get_input(self.movie_frame))
+            {
+                self.controller.set_state(input);
+            }
+        }
+        // the buttons held down during the frame, as the game sees them
+        let input = self.controller.get_state();
+        self.frame_audio.clear();
+        for channel_audio in self.frame_channel_audio.iter_mut() {
+            channel_audio.clear();
+        }
+        loop {
+            let events = self
+                .emulator
+                .emulate(&mut self.frame_buffer, &mut self.controller);
+            if events.contains(StepEvents::AUDIO_BUFFER_FULL) {
+                let audio_buffer = self.emulator.get_audio_buffer();
+                self.frame_audio.extend_from_slice(audio_buffer);
+                let frames = audio_buffer.len() / 2;
+                self.collect_channel_audio(frames);
+            }
+            if events.contains(StepEvents::VBLANK) {
+                let audio_samples = self.emulator.take_audio_samples();
+                self.frame_audio.extend_from_slice(audio_samples);
+                let frames = audio_samples.len() / 2;
+                self.collect_channel_audio(frames);
+                break;
+            }
+        }
+        self.frames.set(self.frames.get() + 1);
+        self.update_movie(input);
+        &self.frame_buffer
+    }
+    fn update_movie(&mut self, input: u8) {
+        let movie = match self.movie {
+            Some(ref mut movie) => movie,
+            None => return,
+        };
+        let checksum = self.emulator.get_checksum();
+        match self.movie_mode {
+            MovieMode::Recording => movie.add_frame(input, checksum),
+            MovieMode::Playing => {
+                let expected_checksum = movie.get_checksum(self.movie_frame);
+                if expected_checksum.is_some()
+                    && expected_checksum != Some(checksum)
+                    && self.movie_desync_frame.is_none()
+                {
+                    self.movie_desync_frame = Some(self.movie_frame);
+                }
+            }
+        }
+        self.movie_frame += 1;
+    }
+    fn collect_channel_audio(&mut self, frames: usize) {
+        let sound = self.emulator.get_sound();
+        for (channel_audio, channel) in self
+            .frame_channel_audio
+            .iter_mut()
+            .zip(AUDIO_CHANNELS.iter())
+        {
+            channel_audio.extend_from_slice(&sound.get_channel_buffer(*channel)[..frames]);
+        }
+    }
+    pub fn get_frame_buffer(&self) -> &FrameBuffer {
+        &self.frame_buffer
+    }
+    /// Replaces the built-in frame buffer with an empty one in the given format
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.frame_buffer = FrameBuffer::new(format);
+    }
+    /// Interleaved stereo samples produced during the last run_frame call
+    pub fn get_frame_audio(&self) -> &[f32] {
+        &self.frame_audio
     }
     pub fn get_audio_buffer(&self) -> &[f32] {
         self.emulator.get_audio_buffer()
     }
+    /// Output of a single channel during the last run_frame call, one sample between 0 and 1
+    /// per stereo frame of get_frame_audio. Taken before muting and gain, for visualisation
+    /// or recording
+    pub fn get_frame_channel_audio(&self, channel: AudioChannel) -> &[f32] {
+        &self.frame_channel_audio[channel as usize]
+    }
+    /// Output of a single channel lined up with get_audio_buffer
+    pub fn get_channel_audio_buffer(&self, channel: AudioChannel) -> &[f32] {
+        self.emulator.get_sound().get_channel_buffer(channel)
+    }
+    /// Scales the output of a channel, on top of the game's own volume settings
+    pub fn set_channel_gain(&mut self, channel: AudioChannel, gain: f32) {
+        self.emulator
+            .get_sound_mut()
+            .set_channel_gain(channel, gain)
+    }
+    pub fn get_channel_gain(&self, channel: AudioChannel) -> f32 {
+        self.emulator.get_sound().get_channel_gain(channel)
+    }
+    pub fn set_channel_muted(&mut self, channel: AudioChannel, muted: bool) {
+        self.emulator
+            .get_sound_mut()
+            .set_channel_muted(channel, muted)
+    }
+    pub fn is_channel_muted(&self, channel: AudioChannel) -> bool {
+        self.emulator.get_sound().is_channel_muted(channel)
+    }
+    /// While any channel is soloed, all the others are silent
+    pub fn set_channel_solo(&mut self, channel: AudioChannel, solo: bool) {
+        self.emulator
+            .get_sound_mut()
+            .set_channel_solo(channel, solo)
+    }
+    pub fn is_channel_solo(&self, channel: AudioChannel) -> bool {
+        self.emulator.get_sound().is_channel_solo(channel)
+    }
+    /// Sets the rate of the audio output in samples per second and per channel
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.emulator.set_audio_sample_rate(sample_rate)
+    }
+    pub fn get_audio_sample_rate(&self) -> u32 {
+        self.emulator.get_audio_sample_rate()
+    }
+    /// Speeds up or slows down the audio output by the given factor, to keep the audio queue
+    /// of the frontend filled. See RateControl
+    pub fn set_audio_rate_ratio(&mut self, ratio: f64) {
+        self.emulator.set_audio_rate_ratio(ratio)
+    }
+    /// Starts logging every APU register write, for a VGM file
+    pub fn start_vgm_log(&mut self) {
+        let vgm_writer = Rc::new(RefCell::new(VgmWriter::new(self.emulator.get_sound())));
+        let callback_vgm_writer = vgm_writer.clone();
+        self.emulator
+            .get_sound_mut()
+            .set_write_callback(Some(Box::new(move |cycles, address, value| {
+                callback_vgm_writer
+                    .borrow_mut()
+                    .log_write(cycles, address, value);
+            })));
+        self.vgm_writer = Some(vgm_writer);
+    }
+    /// Stops logging and returns the contents of the VGM file
+    pub fn stop_vgm_log(&mut self) -> Option<Vec<u8>> {
+        let vgm_writer = self.vgm_writer.take()?;
+        self.emulator.get_sound_mut().set_write_callback(None);
+        let cycles = self.emulator.get_sound().get_cycles();
+        let vgm_writer = Rc::try_unwrap(vgm_writer).ok()?.into_inner();
+        Some(vgm_writer.finish(cycles))
+    }
+    pub fn is_logging_vgm(&self) -> bool {
+        self.vgm_writer.is_some()
+    }
     pub fn get_cartridge(&self) -> &Cartridge {
         self.emulator.get_cartridge()
     }
@@ -48,12 +291,184 @@ impl Gameboy {
         self.emulator.get_cartridge_mut()
     }
     pub fn set_ram_change_callback(&mut self, f: Box<dyn FnMut(usize, u8)>) {
-        self.emulator.set_ram_change_callback(f)
+        *self.ram_change_callback.borrow_mut() = f;
     }
+    /// Sets how CGB colors are converted to RGB before being handed to the PixelMapper
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        self.emulator.set_color_correction(color_correction)
+    }
+    pub fn get_color_correction(&self) -> ColorCorrection {
+        self.emulator.get_color_correction()
+    }
+    /// Ignored while a movie is played back
     pub fn press_button(&mut self, button: Button) {
-        self.controller.press(button)
+        if !self.is_playing_movie() {
+            self.controller.press(button)
+        }
     }
     pub fn release_button(&mut self, button: Button) {
-        self.controller.release(button)
+        if !self.is_playing_movie() {
+            self.controller.release(button)
+        }
+    }
+    /// Holds the button down with turbo, it gets pressed and released again every few
+    /// frames. Ignored while a movie is played back
+    pub fn press_turbo_button(&mut self, button: Button) {
+        if !self.is_playing_movie() {
+            self.controller.press_turbo(button)
+        }
+    }
+    pub fn release_turbo_button(&mut self, button: Button) {
+        if !self.is_playing_movie() {
+            self.controller.release_turbo(button)
+        }
+    }
+    /// Sets for how many frames a turbo button stays pressed and then released
+    pub fn set_turbo_frames(&mut self, frames: u32) {
+        self.controller.set_turbo_frames(frames)
+    }
+    pub fn get_turbo_frames(&self) -> u32 {
+        self.controller.get_turbo_frames()
+    }
+    /// Sets what the game gets to see of left and right or up and down held down together
+    pub fn set_opposing_directions(&mut self, opposing_directions: OpposingDirections) {
+        self.controller.set_opposing_directions(opposing_directions)
+    }
+    pub fn get_opposing_directions(&self) -> OpposingDirections {
+        self.controller.get_opposing_directions()
+    }
+    /// Restarts the game and records the buttons of every frame from then on. The cartridge
+    /// clock starts at the given unix time and follows the emulated time, so the movie can
+    /// be played back exactly
+    pub fn start_movie_recording(&mut self, rtc_seed: u64) -> Result<(), String> {
+        let cartridge = self.emulator.get_cartridge();
+        let movie = Movie::new(
+            cartridge.get_rom(),
+            cartridge.is_cgb(),
+            rtc_seed,
+            MovieAnchor::from_cartridge(cartridge),
+        );
+        self.power_cycle(Box::new(MovieRtc::new(rtc_seed, self.frames.clone())))?;
+        self.movie = Some(movie);
+        self.movie_mode = MovieMode::Recording;
+        self.movie_frame = 0;
+        self.movie_desync_frame = None;
+        Ok(())
+    }
+    /// Records the buttons of every frame from the current state on, which the movie keeps
+    /// as a save state. The cartridge clock starts at the given unix time like on a restart
+    pub fn start_movie_recording_from_state(&mut self, rtc_seed: u64) -> Result<(), String> {
+        let state = self.save_state();
+        let cartridge = self.emulator.get_cartridge();
+        let movie = Movie::new(
+            cartridge.get_rom(),
+            cartridge.is_cgb(),
+            rtc_seed,
+            MovieAnchor::SaveState(state.clone()),
+        );
+        let rtc = MovieRtc::new(rtc_seed, self.frames.clone());
+        self.power_cycle_into_state(Box::new(rtc), &state)?;
+        self.movie = Some(movie);
+        self.movie_mode = MovieMode::Recording;
+        self.movie_frame = 0;
+        self.movie_desync_frame = None;
+        Ok(())
+    }
+    /// Restores the state the movie was recorded from and plays back its buttons. The
+    /// cartridge RAM is replaced by the one of the movie
+    pub fn start_movie_playback(&mut self, movie: Movie) -> Result<(), String> {
+        movie.check_cartridge(self.emulator.get_cartridge())?;
+        let rtc = Box::new(MovieRtc::new(movie.get_rtc_seed(), self.frames.clone()));
+        match movie.get_anchor() {
+            MovieAnchor::PowerOn {
+                rtc: rtc_data,
+                rtc_last_time,
+                ram,
+            } => {
+                let cartridge = self.emulator.get_cartridge_mut();
+                if ram.len() != cartridge.get_ram().len() {
+                    return Err("The cartridge RAM of the movie has the wrong size".to_string());
+                }
+                cartridge.set_ram(ram.clone());
+                cartridge.set_last_timestamp(*rtc_data, *rtc_last_time);
+                self.power_cycle(rtc)?;
+            }
+            MovieAnchor::SaveState(state) => self.power_cycle_into_state(rtc, state)?,
+        }
+        self.movie = Some(movie);
+        self.movie_mode = MovieMode::Playing;
+        self.movie_frame = 0;
+        self.movie_desync_frame = None;
+        Ok(())
+    }
+    /// Stops recording or playing back, and returns the movie
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take()
+    }
+    pub fn is_recording_movie(&self) -> bool {
+        self.movie.is_some() && matches!(self.movie_mode, MovieMode::Recording)
+    }
+    /// True until every frame of the movie has been played back
+    pub fn is_playing_movie(&self) -> bool {
+        match (&self.movie, &self.movie_mode) {
+            (Some(movie), MovieMode::Playing) => self.movie_frame < movie.get_frame_count(),
+            _ => false,
+        }
+    }
+    /// Frames recorded or played back so far
+    pub fn get_movie_frame(&self) -> usize {
+        self.movie_frame
+    }
+    /// The first frame that ended with a different memory checksum than when the movie was
+    /// recorded
+    pub fn get_movie_desync_frame(&self) -> Option<usize> {
+        self.movie_desync_frame
+    }
+    /// A checksum of the system RAM, the one movies compare every frame
+    pub fn get_checksum(&self) -> u32 {
+        self.emulator.get_checksum()
+    }
+    /// Saves the state of the whole system between two frames, including the cartridge RAM
+    /// and the screen. The settings and the buttons held down aren't part of it
+    pub fn save_state(&self) -> Vec<u8> {
+        let cartridge = self.emulator.get_cartridge();
+        let mut writer = StateWriter::new(crc32(cartridge.get_rom()), cartridge.is_cgb());
+        self.emulator.save_state(&mut writer);
+        self.frame_buffer.save_state(&mut writer);
+        writer.into_bytes()
+    }
+    /// Loads a state saved with the same ROM. Nothing changes when it can't be loaded. The
+    /// cartridge RAM is replaced without calling the RAM change callback, and a movie can't
+    /// be running as it couldn't reproduce the jump
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if self.movie.is_some() {
+            return Err("A save state can't be loaded during a movie".to_string());
+        }
+        let backup = self.save_state();
+        if let Err(e) = self.read_state(state) {
+            self.read_state(&backup)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+    // restarts with the clock of a movie and continues from the state it starts at. nothing
+    // changes when the state can't be loaded
+    fn power_cycle_into_state(&mut self, rtc: Box<dyn RTC>, state: &[u8]) -> Result<(), String> {
+        let backup = self.save_state();
+        self.power_cycle(rtc)?;
+        if let Err(e) = self.read_state(state) {
+            self.power_cycle(Box::new(self.rtc.clone()))?;
+            self.read_state(&backup)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+    fn read_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let cartridge = self.emulator.get_cartridge();
+        let rom_hash = crc32(cartridge.get_rom());
+        let mut reader = StateReader::new(state, rom_hash, cartridge.is_cgb())?;
+        self.emulator.load_state(&mut reader);
+        self.frame_buffer.load_state(&mut reader);
+        reader.finish()
     }
 }
@@ -1,4 +1,5 @@
 use super::cartridge::Cartridge;
+use crate::save_state::{StateReader, StateWriter};
 
 pub trait Mbc {
     fn read_byte(&self, index: u16) -> u8;
@@ -6,4 +7,7 @@ pub trait Mbc {
     fn get_cartridge(&self) -> &Cartridge;
     fn get_cartridge_mut(&mut self) -> &mut Cartridge;
     fn set_ram_change_callback(&mut self, f: Box<dyn FnMut(usize, u8)>);
+    // the registers of the controller, the cartridge RAM is saved by the memory
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader);
 }
@@ -1,5 +1,6 @@
 use super::cartridge::Cartridge;
 use super::mbc::Mbc;
+use crate::save_state::{StateReader, StateWriter};
 
 pub struct Mbc1 {
     cartridge: Cartridge,
@@ -70,7 +71,7 @@ impl Mbc for Mbc1 {
             0x4000..=0x5FFF => {
                 if self.in_ram_banking_mode {
                     self.selected_eram_bank = usize::from(value & 0x03);
-                    self.selected_eram_bank &= self.cartridge.get_ram_banks() - 1;
+                    self.selected_eram_bank &= self.cartridge.get_ram_banks().saturating_sub(1);
                 } else {
                     self.higher_rom_bank_bits = usize::from(value & 0x03);
                     self.selected_rom_bank =
@@ -121,6 +122,24 @@ impl Mbc for Mbc1 {
     fn set_ram_change_callback(&mut self, f: Box<dyn FnMut(usize, u8)>) {
         self.ram_change_callback = f;
     }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.selected_rom_bank as u16);
+        writer.write_u8(self.selected_eram_bank as u8);
+        writer.write_bool(self.in_ram_banking_mode);
+        writer.write_bool(self.external_ram_enabled);
+        writer.write_u8(self.higher_rom_bank_bits as u8);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) {
+        let rom_bank = usize::from(reader.read_u16());
+        self.selected_rom_bank = rom_bank & (self.cartridge.get_rom_banks() - 1);
+        let eram_bank = usize::from(reader.read_u8() & 0x03);
+        self.selected_eram_bank = eram_bank & self.cartridge.get_ram_banks().saturating_sub(1);
+        self.in_ram_banking_mode = reader.read_bool();
+        self.external_ram_enabled = reader.read_bool();
+        self.higher_rom_bank_bits = usize::from(reader.read_u8() & 0x03);
+    }
 }
 
 impl Mbc1 {
@@ -1,5 +1,6 @@
 use super::cartridge::Cartridge;
 use super::mbc::Mbc;
+use crate::save_state::{StateReader, StateWriter};
 
 pub struct Mbc2 {
     cartridge: Cartridge,
@@ -75,6 +76,17 @@ impl Mbc for Mbc2 {
     fn set_ram_change_callback(&mut self, f: Box<dyn FnMut(usize, u8)>) {
         self.ram_change_callback = f;
     }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.selected_rom_bank as u16);
+        writer.write_bool(self.external_ram_enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) {
+        let rom_bank = usize::from(reader.read_u16());
+        self.selected_rom_bank = rom_bank & (self.cartridge.get_rom_banks() - 1);
+        self.external_ram_enabled = reader.read_bool();
+    }
 }
 
 impl Mbc2 {
@@ -3,6 +3,7 @@ use super::mbc::Mbc;
 use crate::bit_utils;
 use crate::emulator::traits::RTC;
 use crate::rtc::Rtc;
+use crate::save_state::{StateReader, StateWriter};
 
 pub struct Mbc3 {
     cartridge: Cartridge,
@@ -68,7 +69,7 @@ impl Mbc for Mbc3 {
                 match value {
                     0x00..=0x03 => {
                         self.selected_eram_bank = value as usize;
-                        self.selected_eram_bank &= self.cartridge.get_ram_banks() - 1;
+                        self.selected_eram_bank &= self.cartridge.get_ram_banks().saturating_sub(1);
                         self.use_rtc_for_ram = false;
                     }
                     0x08..=0x0C => {
@@ -129,6 +130,41 @@ impl Mbc for Mbc3 {
     fn set_ram_change_callback(&mut self, f: Box<dyn FnMut(usize, u8)>) {
         self.ram_change_callback = f;
     }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.selected_rom_bank as u16);
+        writer.write_u8(self.selected_eram_bank as u8);
+        writer.write_bool(self.external_ram_enabled);
+        writer.write_u64(self.rtc_last_time);
+        writer.write_u8(self.rtc_register_select);
+        writer.write_bool(self.use_rtc_for_ram);
+        writer.write_u8(self.rtc_latch_data);
+        writer.write_bytes(&self.rtc_latch.to_bytes());
+        writer.write_bytes(&self.rtc_data.to_bytes());
+    }
+
+    // the clock keeps counting from the time the state was saved. a state from the future
+    // would have it count backwards, so it only starts from there
+    fn load_state(&mut self, reader: &mut StateReader) {
+        let rom_bank = usize::from(reader.read_u16());
+        self.selected_rom_bank = rom_bank & (self.cartridge.get_rom_banks() - 1);
+        let eram_bank = usize::from(reader.read_u8() & 0x03);
+        self.selected_eram_bank = eram_bank & self.cartridge.get_ram_banks().saturating_sub(1);
+        self.external_ram_enabled = reader.read_bool();
+        let rtc_last_time = reader.read_u64();
+        self.rtc_last_time = rtc_last_time.min(self.rtc.get_current_time());
+        self.rtc_last_time_cache = self.rtc_last_time;
+        self.rtc_register_select = reader.read_u8();
+        self.use_rtc_for_ram = reader.read_bool();
+        self.rtc_latch_data = reader.read_u8();
+        let mut rtc = [0; 5];
+        reader.read_bytes(&mut rtc);
+        self.rtc_latch = Rtc::from_bytes(&rtc);
+        reader.read_bytes(&mut rtc);
+        self.rtc_data = Rtc::from_bytes(&rtc);
+        self.cartridge
+            .set_last_timestamp(self.rtc_data, self.rtc_last_time);
+    }
 }
 
 impl Mbc3 {
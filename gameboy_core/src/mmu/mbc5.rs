@@ -1,5 +1,6 @@
 use super::cartridge::Cartridge;
 use super::mbc::Mbc;
+use crate::save_state::{StateReader, StateWriter};
 
 pub struct Mbc5 {
     cartridge: Cartridge,
@@ -50,7 +51,7 @@ impl Mbc for Mbc5 {
             }
             0x4000..=0x5FFF => {
                 self.selected_eram_bank = usize::from(value) & 0x0F;
-                self.selected_eram_bank &= self.cartridge.get_ram_banks() - 1;
+                self.selected_eram_bank &= self.cartridge.get_ram_banks().saturating_sub(1);
             }
             0x6000..=0x7FFF => (),
             0xA000..=0xBFFF => {
@@ -78,6 +79,22 @@ impl Mbc for Mbc5 {
     fn set_ram_change_callback(&mut self, f: Box<dyn FnMut(usize, u8)>) {
         self.ram_change_callback = f;
     }
+
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.selected_rom_bank as u16);
+        writer.write_u8(self.selected_rom_bank_high as u8);
+        writer.write_u8(self.selected_eram_bank as u8);
+        writer.write_bool(self.external_ram_enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) {
+        let rom_bank = usize::from(reader.read_u16());
+        self.selected_rom_bank = rom_bank & (self.cartridge.get_rom_banks() - 1);
+        self.selected_rom_bank_high = usize::from(reader.read_u8() & 0x01);
+        let eram_bank = usize::from(reader.read_u8() & 0x0F);
+        self.selected_eram_bank = eram_bank & self.cartridge.get_ram_banks().saturating_sub(1);
+        self.external_ram_enabled = reader.read_bool();
+    }
 }
 
 impl Mbc5 {
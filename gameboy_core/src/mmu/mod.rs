@@ -7,7 +7,9 @@ mod mbc2;
 mod mbc3;
 mod mbc5;
 mod mbc_type;
+mod oam_dma;
 mod rom_only;
+pub mod timer_state;
 use self::cartridge::Cartridge;
 use self::gpu_cycles::GpuCycles;
 use self::interrupt::Interrupt;
@@ -17,11 +19,14 @@ use self::mbc2::Mbc2;
 use self::mbc3::Mbc3;
 use self::mbc5::Mbc5;
 use self::mbc_type::MbcType;
+use self::oam_dma::OamDma;
 use self::rom_only::RomOnly;
+use self::timer_state::TimerState;
 use crate::bit_utils;
 use crate::emulator::traits::RTC;
 use crate::gpu::cgb_color::CGBColor;
 use crate::gpu::lcd_control_flag::LcdControlFlag;
+use crate::save_state::{StateReader, StateWriter};
 use crate::sound::Sound;
 
 pub const SPRITES_START_INDEX: u16 = 0xFE00;
@@ -51,6 +56,11 @@ pub const CGB_SPRITE_PALETTE_INDEX_INDEX: u16 = 0xFF6A;
 pub const CGB_SPRITE_PALETTE_DATA_INDEX: u16 = 0xFF6B;
 pub const INTERRUPT_ENABLE_INDEX: u16 = 0xFFFF;
 
+const OAM_SCAN_MODE: u8 = 0b10;
+const LCD_TRANSFER_MODE: u8 = 0b11;
+// a 16 byte block takes 8 M-cycles in normal speed and 16 M-cycles in double speed
+const HDMA_BLOCK_CYCLES: i32 = 32;
+
 const INITIAL_VALUES_FOR_FFXX: [u8; 0x100] = [
     0xCF, 0x00, 0x7E, 0xFF, 0xD3, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE1,
     0x80, 0xBF, 0xF3, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
@@ -101,8 +111,7 @@ pub struct Memory {
     pub screen_disabled: bool,
     pub lcd_status_mode: u8,
     pub gpu_cycles: GpuCycles,
-    pub div_cycles: i32,
-    pub tima_cycles: i32,
+    pub timer_state: TimerState,
     is_cgb: bool,
     vram_bank: i32,
     wram_bank: i32,
@@ -110,6 +119,8 @@ pub struct Memory {
     hdma_destination: u16,
     hdma_bytes: i32,
     hdma_enabled: bool,
+    dma_stall_cycles: i32,
+    oam_dma: OamDma,
     pub cgb_background_palettes: [[CGBColor; 4]; 8],
     pub cgb_sprite_palettes: [[CGBColor; 4]; 8],
     sound: Sound,
@@ -163,7 +174,7 @@ impl Memory {
         }
 
         // setup initial values for the sound module
-        let mut sound = Sound::new();
+        let mut sound = Sound::new(is_cgb);
         for i in 0xFF10..=0xFF3F {
             let value = if is_cgb {
                 INITIAL_VALUES_FOR_COLOR_FFXX[i - 0xFF00]
@@ -191,8 +202,7 @@ impl Memory {
             screen_disabled: false,
             lcd_status_mode: 1,
             gpu_cycles: GpuCycles::new(),
-            div_cycles: 0,
-            tima_cycles: 0,
+            timer_state: TimerState::new(u16::from(high_ram[0x04]) << 8),
             is_cgb,
             vram_bank: 0,
             wram_bank: 1,
@@ -200,6 +210,8 @@ impl Memory {
             hdma_destination,
             hdma_bytes: 0,
             hdma_enabled: false,
+            dma_stall_cycles: 0,
+            oam_dma: OamDma::new(),
             cgb_background_palettes: [[white; 4]; 8],
             cgb_sprite_palettes: [[white; 4]; 8],
             sound,
@@ -207,18 +219,24 @@ impl Memory {
     }
 
     pub fn read_byte(&self, index: u16) -> u8 {
+        if let Some(value) = self.get_oam_dma_conflict(index) {
+            return value;
+        }
+
         match index {
             0x0000..=0x7FFF => self.mbc.read_byte(index),
+            0x8000..=0x9FFF if !self.is_vram_accessible() => 0xFF,
             0x8000..=0x9FFF => self.read_cgb_lcd_ram(index, self.vram_bank),
             0xA000..=0xBFFF => self.mbc.read_byte(index),
             0xC000..=0xCFFF => self.read_cgb_wram(index - 0xC000, 0),
             0xD000..=0xDFFF => self.read_cgb_wram(index - 0xD000, self.wram_bank),
             0xE000..=0xFDFF => self.read_byte(index - 0x2000),
+            0xFE00..=0xFEFF if !self.is_oam_accessible() => 0xFF,
             0xFE00..=0xFEFF => self.oam[index as usize - 0xFE00],
             0xFF00..=0xFFFF => match index {
                 JOYPAD_INDEX => self.get_joypad_state(),
                 0xFF03 => 0xFF,
-                DIVIDER_INDEX => self.load(index),
+                DIVIDER_INDEX => (self.timer_state.divider >> 8) as u8,
                 SELECTABLE_TIMER_INDEX => self.load(index),
                 TIMER_RESET_INDEX => self.load(index),
                 TIMER_CONTROL_INDEX => self.load(index) | 0xF8,
@@ -265,16 +283,9 @@ impl Memory {
                         0xFF
                     }
                 }
-                0xFF76 => {
-                    if self.is_cgb {
-                        0x00
-                    } else {
-                        0xFF
-                    }
-                }
-                0xFF77 => {
+                0xFF76 | 0xFF77 => {
                     if self.is_cgb {
-                        0x00
+                        self.sound.read_pcm_amplitudes(index)
                     } else {
                         0xFF
                     }
@@ -311,19 +322,25 @@ impl Memory {
     }
 
     pub fn write_byte(&mut self, index: u16, value: u8) {
+        if self.get_oam_dma_conflict(index).is_some() {
+            return;
+        }
+
         match index {
             0x0000..=0x7FFF => self.mbc.write_byte(index, value),
+            0x8000..=0x9FFF if !self.is_vram_accessible() => {}
             0x8000..=0x9FFF => self.write_cgb_lcd_ram(index, value, self.vram_bank),
             0xA000..=0xBFFF => self.mbc.write_byte(index, value),
             0xC000..=0xCFFF => self.write_cgb_wram(index - 0xC000, value, 0),
             0xD000..=0xDFFF => self.write_cgb_wram(index - 0xD000, value, self.wram_bank),
             0xE000..=0xFDFF => self.write_byte(index - 0x2000, value),
+            0xFE00..=0xFEFF if !self.is_oam_accessible() => {}
             0xFE00..=0xFEFF => self.oam[index as usize - 0xFE00] = value,
             0xFF00..=0xFFFF => match index {
-                DIVIDER_INDEX => self.reset_div_cycles(),
-                SELECTABLE_TIMER_INDEX => self.store(index, value),
-                TIMER_RESET_INDEX => self.store(index, value),
-                TIMER_CONTROL_INDEX => self.store(index, value),
+                DIVIDER_INDEX => self.set_divider(0),
+                SELECTABLE_TIMER_INDEX => self.do_tima_write(value),
+                TIMER_RESET_INDEX => self.do_tma_write(value),
+                TIMER_CONTROL_INDEX => self.do_tac_write(value),
                 INTERRUPT_FLAGS_INDEX => self.store(index, value & 0x1F),
                 APU_INDEX_START..=APU_INDEX_END => self.sound.write_byte(index, value),
                 LCD_CONTROL_INDEX => self.do_lcd_control_write(value),
@@ -418,16 +435,9 @@ impl Memory {
     }
 
     pub fn do_lcd_control_write(&mut self, value: u8) {
-        let current_lcdc = LcdControlFlag::from_bits_truncate(self.load(LCD_CONTROL_INDEX));
         let new_lcdc = LcdControlFlag::from_bits_truncate(value);
         self.store(LCD_CONTROL_INDEX, value);
 
-        if !current_lcdc.contains(LcdControlFlag::WINDOW)
-            && new_lcdc.contains(LcdControlFlag::WINDOW)
-        {
-            self.reset_window_line();
-        }
-
         if new_lcdc.contains(LcdControlFlag::DISPLAY) {
             self.enable_screen();
         } else {
@@ -486,15 +496,80 @@ impl Memory {
     }
 
     pub fn do_dma_transfer(&mut self, data: u8) {
-        let address = 0x100 * u16::from(data);
-        if address >= 0x8000 && address < 0xE000 {
-            for i in 0..0xA0 {
-                let value = self.read_byte(address + i);
-                self.write_byte(SPRITES_START_INDEX + i, value);
+        // sources above 0xDFFF read from the echo of work ram
+        let source = u16::from(data) << 8;
+        let source = if source >= 0xE000 {
+            source - 0x2000
+        } else {
+            source
+        };
+        self.oam_dma.request(source);
+    }
+
+    pub fn step_oam_dma(&mut self, cycles: i32) {
+        for _ in 0..cycles {
+            if self.oam_dma.is_idle() {
+                break;
             }
+            if let Some(byte) = self.oam_dma.tick() {
+                let value = self.read_dma_source(self.oam_dma.source + byte);
+                self.oam[byte as usize] = value;
+                self.oam_dma.last_value = value;
+            }
+        }
+    }
+
+    pub fn get_oam_dma_cycles_until_event(&self) -> i32 {
+        self.oam_dma.get_cycles_until_event()
+    }
+
+    pub fn is_oam_dma_idle(&self) -> bool {
+        self.oam_dma.is_idle()
+    }
+
+    // the DMA controllers have their own path to memory that ignores the PPU locks and the
+    // bus conflicts of the CPU
+    fn read_dma_source(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF => self.read_cgb_lcd_ram(address, self.vram_bank),
+            0xC000..=0xCFFF => self.read_cgb_wram(address - 0xC000, 0),
+            0xD000..=0xDFFF => self.read_cgb_wram(address - 0xD000, self.wram_bank),
+            0xE000..=0xFFFF => self.read_dma_source(address - 0x2000),
+            _ => self.mbc.read_byte(address),
+        }
+    }
+
+    // while an OAM DMA is running the CPU can only use the IO registers and high ram. the bus
+    // the DMA reads from (the VRAM bus or the external bus) returns the byte that is currently
+    // being transferred, everything else reads as 0xFF
+    fn get_oam_dma_conflict(&self, index: u16) -> Option<u8> {
+        if !self.oam_dma.active || index >= 0xFF00 {
+            return None;
+        }
+
+        let is_vram = |address: u16| (0x8000..0xA000).contains(&address);
+        if index < SPRITES_START_INDEX && is_vram(index) == is_vram(self.oam_dma.source) {
+            Some(self.oam_dma.last_value)
+        } else {
+            Some(0xFF)
         }
     }
 
+    fn is_vram_accessible(&self) -> bool {
+        self.screen_disabled || self.lcd_status_mode != LCD_TRANSFER_MODE
+    }
+
+    fn is_oam_accessible(&self) -> bool {
+        let oam_locked = !self.screen_disabled
+            && (self.lcd_status_mode == OAM_SCAN_MODE || self.lcd_status_mode == LCD_TRANSFER_MODE);
+        !self.oam_dma.active && !oam_locked
+    }
+
+    // the PPU reads OAM directly, bypassing the locks that apply to the CPU
+    pub fn read_oam(&self, index: u16) -> u8 {
+        self.oam[(index - SPRITES_START_INDEX) as usize]
+    }
+
     pub fn compare_ly_to_lyc(&mut self) {
         if !self.screen_disabled {
             let lyc = self.load(LYC_INDEX);
@@ -534,14 +609,6 @@ impl Memory {
         self.irq48_signal = 0;
     }
 
-    pub fn reset_window_line(&mut self) {
-        let wy = self.load(WINDOW_Y_INDEX);
-
-        if (self.gpu_cycles.window_line == 0) && (self.scan_line < 144) && (self.scan_line > wy) {
-            self.gpu_cycles.window_line = 144;
-        }
-    }
-
     fn do_cgb_dma(&mut self, value: u8) {
         self.hdma_bytes = 16 + ((value & 0x7F) as i32 * 16);
 
@@ -549,27 +616,32 @@ impl Memory {
             if bit_utils::is_set(value, 7) {
                 self.store(0xFF55, value & 0x7F);
             } else {
-                self.store(0xFF55, 0xFF);
+                // cancelling keeps the remaining length around, bit 7 reports the dma as inactive
+                self.store(0xFF55, 0x80 | self.load(0xFF55));
                 self.hdma_enabled = false;
             }
         } else if bit_utils::is_set(value, 7) {
             self.hdma_enabled = true;
             self.store(0xFF55, value & 0x7F);
-            if self.lcd_status_mode == 0 {
-                let _cycles = self.do_hdma();
+            // a transfer that is started in the middle of hblank (or with the screen off) copies
+            // its first block right away instead of waiting for the next hblank
+            if self.screen_disabled || self.lcd_status_mode == 0 {
+                self.do_hdma();
             }
         } else {
-            let _cycles = self.do_gdma(value);
+            self.do_gdma();
         }
     }
 
-    pub fn do_hdma(&mut self) -> i32 {
+    // called by the GPU at the start of every hblank, the CPU is halted while the block is
+    // copied. this also happens while the CPU is in HALT
+    pub fn do_hdma(&mut self) {
         let source = self.hdma_source & 0xFFF0;
         let destination = (self.hdma_destination & 0x1FF0) | 0x8000;
 
         for i in 0..0x10 {
-            let value = self.read_byte(source + i);
-            self.write_byte(destination + i, value);
+            let value = self.read_dma_source(source + i);
+            self.write_cgb_lcd_ram(destination + i, value, self.vram_bank);
         }
 
         self.hdma_destination += 0x10;
@@ -595,16 +667,17 @@ impl Memory {
             self.hdma_enabled = false;
         }
 
-        9 * 4 // TODO: this needs to be the correct timing
+        self.add_dma_stall_cycles(1);
     }
 
-    fn do_gdma(&mut self, value: u8) -> i32 {
+    fn do_gdma(&mut self) {
         let source = self.hdma_source & 0xFFF0;
         let destination = (self.hdma_destination & 0x1FF0) | 0x8000;
 
         for i in 0..self.hdma_bytes as u16 {
-            let value = self.read_byte(source + i);
-            self.write_byte(destination + i, value);
+            let value = self.read_dma_source(source.wrapping_add(i));
+            let destination = ((destination + i) & 0x1FFF) | 0x8000;
+            self.write_cgb_lcd_ram(destination, value, self.vram_bank);
         }
 
         self.hdma_source += self.hdma_bytes as u16;
@@ -614,7 +687,29 @@ impl Memory {
             self.store(0xFF51 + i, 0xFF);
         }
 
-        1 + 8 * ((value & 0x7F) as i32 * 4) // TODO: this needs to be the right timing
+        self.add_dma_stall_cycles(self.hdma_bytes / 0x10);
+    }
+
+    // stall cycles are counted at the CPU clock, handing the bus over takes one more M-cycle
+    fn add_dma_stall_cycles(&mut self, blocks: i32) {
+        let double_speed = bit_utils::is_set(self.load(0xFF4D), 7);
+        let block_cycles = if double_speed {
+            HDMA_BLOCK_CYCLES * 2
+        } else {
+            HDMA_BLOCK_CYCLES
+        };
+        self.dma_stall_cycles += 4 + blocks * block_cycles;
+    }
+
+    // returns the cycles the CPU has to stay stalled for this step because of a running
+    // HDMA or GDMA, in chunks of at most one M-cycle so the rest of the system keeps up
+    pub fn take_dma_stall_cycles(&mut self) -> Option<i32> {
+        if self.dma_stall_cycles <= 0 {
+            return None;
+        }
+        let cycles = std::cmp::min(self.dma_stall_cycles, 4);
+        self.dma_stall_cycles -= cycles;
+        Some(cycles)
     }
 
     fn update_color_palette(&mut self, background: bool, value: u8) {
@@ -770,8 +865,70 @@ impl Memory {
         self.write_byte(INTERRUPT_FLAGS_INDEX, interrupt_flag);
     }
 
-    fn reset_div_cycles(&mut self) {
-        self.store(DIVIDER_INDEX, 0);
+    // TIMA is clocked by the falling edge of the selected divider bit AND the timer enable
+    // bit, so resetting DIV or changing TAC can increment TIMA as well
+    fn get_timer_input(&self) -> bool {
+        match self.get_timer_bit() {
+            Some(bit) => (self.timer_state.divider >> bit) & 0x01 != 0,
+            None => false,
+        }
+    }
+
+    // the divider bit that clocks TIMA, or None when the timer is disabled
+    pub fn get_timer_bit(&self) -> Option<u8> {
+        let tac = self.load(TIMER_CONTROL_INDEX);
+        if !bit_utils::is_set(tac, 2) {
+            return None;
+        }
+        let bit = match tac & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            3 => 7,
+            _ => unreachable!(),
+        };
+        Some(bit)
+    }
+
+    pub fn set_divider(&mut self, divider: u16) {
+        let timer_input = self.get_timer_input();
+        self.timer_state.divider = divider;
+        if timer_input && !self.get_timer_input() {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(&mut self) {
+        let tima = self.load(SELECTABLE_TIMER_INDEX).wrapping_add(1);
+        self.store(SELECTABLE_TIMER_INDEX, tima);
+        if tima == 0 {
+            self.timer_state.overflow_cycles = 4;
+        }
+    }
+
+    fn do_tima_write(&mut self, value: u8) {
+        // writes while TMA is being loaded are ignored, writes right after the overflow
+        // cancel the reload and the interrupt
+        if self.timer_state.reload_cycles > 0 {
+            return;
+        }
+        self.timer_state.overflow_cycles = 0;
+        self.store(SELECTABLE_TIMER_INDEX, value);
+    }
+
+    fn do_tma_write(&mut self, value: u8) {
+        self.store(TIMER_RESET_INDEX, value);
+        if self.timer_state.reload_cycles > 0 {
+            self.store(SELECTABLE_TIMER_INDEX, value);
+        }
+    }
+
+    fn do_tac_write(&mut self, value: u8) {
+        let timer_input = self.get_timer_input();
+        self.store(TIMER_CONTROL_INDEX, value);
+        if timer_input && !self.get_timer_input() {
+            self.increment_tima();
+        }
     }
 
     pub fn load(&self, index: u16) -> u8 {
@@ -805,4 +962,99 @@ impl Memory {
     pub fn get_sound_mut(&mut self) -> &mut Sound {
         &mut self.sound
     }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.wram);
+        writer.write_bytes(&self.vram);
+        writer.write_bytes(&self.oam);
+        writer.write_bytes(&self.high_ram);
+        writer.write_bytes(self.mbc.get_cartridge().get_ram());
+        self.mbc.save_state(writer);
+        writer.write_u8(self.joypad_state);
+        writer.write_u8(self.scan_line);
+        writer.write_u8(self.irq48_signal);
+        writer.write_bool(self.screen_disabled);
+        writer.write_u8(self.lcd_status_mode);
+        writer.write_i32(self.gpu_cycles.cycles_counter);
+        writer.write_i32(self.gpu_cycles.aux_cycles_counter);
+        writer.write_i32(self.gpu_cycles.screen_enable_delay_cycles);
+        writer.write_i32(self.gpu_cycles.window_line);
+        writer.write_u16(self.timer_state.divider);
+        writer.write_i32(self.timer_state.overflow_cycles);
+        writer.write_i32(self.timer_state.reload_cycles);
+        writer.write_i32(self.vram_bank);
+        writer.write_i32(self.wram_bank);
+        writer.write_u16(self.hdma_source);
+        writer.write_u16(self.hdma_destination);
+        writer.write_i32(self.hdma_bytes);
+        writer.write_bool(self.hdma_enabled);
+        writer.write_i32(self.dma_stall_cycles);
+        self.oam_dma.save_state(writer);
+        let palettes = self
+            .cgb_background_palettes
+            .iter()
+            .chain(self.cgb_sprite_palettes.iter());
+        for color in palettes.flat_map(|palette| palette.iter()) {
+            writer.write_u8(color.red);
+            writer.write_u8(color.green);
+            writer.write_u8(color.blue);
+        }
+        self.sound.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        reader.read_bytes(&mut self.wram);
+        reader.read_bytes(&mut self.vram);
+        reader.read_bytes(&mut self.oam);
+        reader.read_bytes(&mut self.high_ram);
+        reader.read_bytes(self.mbc.get_cartridge_mut().get_ram_mut());
+        self.mbc.load_state(reader);
+        self.joypad_state = reader.read_u8();
+        self.scan_line = reader.read_u8();
+        self.irq48_signal = reader.read_u8();
+        self.screen_disabled = reader.read_bool();
+        self.lcd_status_mode = reader.read_u8() & 0x03;
+        self.gpu_cycles.cycles_counter = reader.read_i32();
+        self.gpu_cycles.aux_cycles_counter = reader.read_i32();
+        self.gpu_cycles.screen_enable_delay_cycles = reader.read_i32();
+        self.gpu_cycles.window_line = reader.read_i32();
+        self.timer_state.divider = reader.read_u16();
+        self.timer_state.overflow_cycles = reader.read_i32();
+        self.timer_state.reload_cycles = reader.read_i32();
+        // the banks index into the RAM, whatever the state says
+        let vram_banks = (self.vram.len() / 0x2000) as i32;
+        let wram_banks = (self.wram.len() / 0x1000) as i32;
+        self.vram_bank = reader.read_i32().rem_euclid(vram_banks);
+        self.wram_bank = reader.read_i32().rem_euclid(wram_banks);
+        self.hdma_source = reader.read_u16();
+        self.hdma_destination = reader.read_u16();
+        self.hdma_bytes = reader.read_i32();
+        self.hdma_enabled = reader.read_bool();
+        self.dma_stall_cycles = reader.read_i32();
+        self.oam_dma.load_state(reader);
+        let palettes = self
+            .cgb_background_palettes
+            .iter_mut()
+            .chain(self.cgb_sprite_palettes.iter_mut());
+        for color in palettes.flat_map(|palette| palette.iter_mut()) {
+            color.red = reader.read_u8();
+            color.green = reader.read_u8();
+            color.blue = reader.read_u8();
+        }
+        self.sound.load_state(reader);
+    }
+
+    // FNV-1a over the RAM of the system, two runs with the same inputs end up with the
+    // same checksum
+    pub fn get_checksum(&self) -> u32 {
+        let mut checksum: u32 = 0x811C_9DC5;
+        let memories: [&[u8]; 4] = [&self.wram, &self.vram, &self.oam, &self.high_ram];
+        for memory in memories.iter() {
+            for value in memory.iter() {
+                checksum ^= u32::from(*value);
+                checksum = checksum.wrapping_mul(0x0100_0193);
+            }
+        }
+        checksum
+    }
 }
@@ -0,0 +1,115 @@
+use crate::save_state::{StateReader, StateWriter};
+
+const OAM_DMA_LENGTH: u16 = 0xA0;
+const OAM_DMA_START_DELAY: i32 = 4;
+const OAM_DMA_BYTE_CYCLES: i32 = 4;
+// upper bound for the time between two updates while there is no transfer
+const IDLE_CYCLES: i32 = 0x10000;
+
+// state of the OAM DMA controller, one byte is copied every M-cycle
+pub struct OamDma {
+    pub active: bool,
+    pub source: u16,
+    pub byte: u16,
+    pub last_value: u8,
+    cycles: i32,
+    pending_source: Option<u16>,
+    start_delay: i32,
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            active: false,
+            source: 0,
+            byte: 0,
+            last_value: 0xFF,
+            cycles: 0,
+            pending_source: None,
+            start_delay: 0,
+        }
+    }
+
+    // the write to 0xFF46 lands at the end of its M-cycle, the transfer starts after the
+    // next one. a transfer that is already running keeps going until then
+    pub fn request(&mut self, source: u16) {
+        self.pending_source = Some(source);
+        self.start_delay = OAM_DMA_START_DELAY;
+    }
+
+    // advances the controller by one cycle and returns the index of the byte that has to be
+    // copied on this cycle, if any
+    pub fn tick(&mut self) -> Option<u16> {
+        if let Some(source) = self.pending_source {
+            self.start_delay -= 1;
+            if self.start_delay <= 0 {
+                self.pending_source = None;
+                self.active = true;
+                self.source = source;
+                self.byte = 0;
+                self.cycles = 0;
+                return None;
+            }
+        }
+
+        if !self.active {
+            return None;
+        }
+
+        self.cycles += 1;
+        if self.cycles < OAM_DMA_BYTE_CYCLES {
+            return None;
+        }
+        self.cycles = 0;
+
+        let byte = self.byte;
+        self.byte += 1;
+        if self.byte == OAM_DMA_LENGTH {
+            self.active = false;
+        }
+        Some(byte)
+    }
+
+    // the next event is the end of the transfer, a restart moves it back
+    pub fn get_cycles_until_event(&self) -> i32 {
+        let transfer_cycles = i32::from(OAM_DMA_LENGTH) * OAM_DMA_BYTE_CYCLES;
+        if self.pending_source.is_some() {
+            self.start_delay.max(1) + transfer_cycles
+        } else if self.active {
+            i32::from(OAM_DMA_LENGTH - self.byte) * OAM_DMA_BYTE_CYCLES - self.cycles
+        } else {
+            IDLE_CYCLES
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        !self.active && self.pending_source.is_none()
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.active);
+        writer.write_u16(self.source);
+        writer.write_u16(self.byte);
+        writer.write_u8(self.last_value);
+        writer.write_i32(self.cycles);
+        writer.write_bool(self.pending_source.is_some());
+        writer.write_u16(self.pending_source.unwrap_or(0));
+        writer.write_i32(self.start_delay);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.active = reader.read_bool();
+        self.source = reader.read_u16();
+        self.byte = reader.read_u16().min(OAM_DMA_LENGTH - 1);
+        self.last_value = reader.read_u8();
+        self.cycles = reader.read_i32();
+        let has_pending_source = reader.read_bool();
+        let pending_source = reader.read_u16();
+        self.pending_source = if has_pending_source {
+            Some(pending_source)
+        } else {
+            None
+        };
+        self.start_delay = reader.read_i32();
+    }
+}
@@ -1,5 +1,6 @@
 use super::cartridge::Cartridge;
 use super::mbc::Mbc;
+use crate::save_state::{StateReader, StateWriter};
 
 pub struct RomOnly {
     cartridge: Cartridge,
@@ -51,6 +52,10 @@ impl Mbc for RomOnly {
     fn set_ram_change_callback(&mut self, f: Box<dyn FnMut(usize, u8)>) {
         self.ram_change_callback = f;
     }
+
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    fn load_state(&mut self, _reader: &mut StateReader) {}
 }
 
 impl RomOnly {
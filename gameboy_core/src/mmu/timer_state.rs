@@ -0,0 +1,19 @@
+#[derive(Default)]
+pub struct TimerState {
+    // DIV is the upper byte of this counter, TIMA is clocked by one of its bits
+    pub divider: u16,
+    // cycles until TMA is loaded into TIMA after an overflow
+    pub overflow_cycles: i32,
+    // cycles left of the M-cycle in which TMA is being loaded into TIMA
+    pub reload_cycles: i32,
+}
+
+impl TimerState {
+    pub fn new(divider: u16) -> TimerState {
+        TimerState {
+            divider,
+            overflow_cycles: 0,
+            reload_cycles: 0,
+        }
+    }
+}
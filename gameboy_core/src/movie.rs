@@ -0,0 +1,260 @@
+use crate::emulator::traits::RTC;
+use crate::mmu::cartridge::Cartridge;
+use crate::rtc::Rtc;
+use std::cell::Cell;
+use std::rc::Rc;
+
+// a movie file, all numbers are little endian:
+//
+// 0x00  4  "GBMV"
+// 0x04  2  format version, 1
+// 0x06  1  model, 0 for DMG and 1 for CGB
+// 0x07  1  anchor, 0 for power-on and 1 for a save state
+// 0x08  4  CRC32 of the ROM
+// 0x0C  8  RTC seed, the unix time in seconds the cartridge clock starts at
+// 0x14  4  number of frames
+// 0x18  4  length of the anchor data
+// 0x1C     anchor data
+//          power-on: the 5 RTC registers, the 8 byte RTC timestamp and the cartridge RAM
+//          save state: the save state the movie starts from
+//
+// followed by 5 bytes for every frame: the buttons held down during the frame and a
+// checksum of the memory at its end. the buttons are bit 0 right, then left, up, down, A,
+// B, select and start
+const MAGIC: &[u8; 4] = b"GBMV";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 0x1C;
+const FRAME_SIZE: usize = 5;
+const FRAME_CYCLES: u64 = 70224;
+const CLOCK_RATE: u64 = 4_194_304;
+
+const MODEL_DMG: u8 = 0;
+const MODEL_CGB: u8 = 1;
+const ANCHOR_POWER_ON: u8 = 0;
+const ANCHOR_SAVE_STATE: u8 = 1;
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn read_u32(bytes: &[u8], index: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[index..index + 4]);
+    u32::from_le_bytes(value)
+}
+
+// the state the recording starts from
+pub enum MovieAnchor {
+    // the game is started fresh, with the battery backed RAM and clock it had
+    PowerOn {
+        rtc: Rtc,
+        rtc_last_time: u64,
+        ram: Vec<u8>,
+    },
+    SaveState(Vec<u8>),
+}
+
+impl MovieAnchor {
+    pub fn from_cartridge(cartridge: &Cartridge) -> MovieAnchor {
+        let (rtc, rtc_last_time) = cartridge.get_last_timestamp();
+        MovieAnchor::PowerOn {
+            rtc,
+            rtc_last_time,
+            ram: cartridge.get_ram().to_vec(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MovieAnchor::PowerOn {
+                rtc,
+                rtc_last_time,
+                ram,
+            } => {
+                let mut bytes = rtc.to_bytes().to_vec();
+                bytes.extend_from_slice(&rtc_last_time.to_le_bytes());
+                bytes.extend_from_slice(ram);
+                bytes
+            }
+            MovieAnchor::SaveState(state) => state.clone(),
+        }
+    }
+}
+
+// the inputs of a recorded session, enough to play it back exactly
+pub struct Movie {
+    is_cgb: bool,
+    rom_hash: u32,
+    rtc_seed: u64,
+    anchor: MovieAnchor,
+    inputs: Vec<u8>,
+    checksums: Vec<u32>,
+}
+
+impl Movie {
+    pub fn new(rom: &[u8], is_cgb: bool, rtc_seed: u64, anchor: MovieAnchor) -> Movie {
+        Movie {
+            is_cgb,
+            rom_hash: crc32(rom),
+            rtc_seed,
+            anchor,
+            inputs: Vec::new(),
+            checksums: Vec::new(),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+            return Err("Not a movie file".to_string());
+        }
+        let version = u16::from_le_bytes([bytes[0x04], bytes[0x05]]);
+        if version != VERSION {
+            return Err(format!("Unsupported movie version: {}", version));
+        }
+        let is_cgb = match bytes[0x06] {
+            MODEL_DMG => false,
+            MODEL_CGB => true,
+            model => return Err(format!("Unknown model: {}", model)),
+        };
+        let mut rtc_seed = [0; 8];
+        rtc_seed.copy_from_slice(&bytes[0x0C..0x14]);
+        let frames = read_u32(bytes, 0x14) as usize;
+        let anchor_size = read_u32(bytes, 0x18) as usize;
+        let frames_start = HEADER_SIZE + anchor_size;
+        if bytes.len() < frames_start + frames * FRAME_SIZE {
+            return Err("Movie file is truncated".to_string());
+        }
+
+        let anchor_data = &bytes[HEADER_SIZE..frames_start];
+        let anchor = match bytes[0x07] {
+            ANCHOR_POWER_ON => {
+                if anchor_data.len() < 13 {
+                    return Err("Movie file is truncated".to_string());
+                }
+                let mut rtc_last_time = [0; 8];
+                rtc_last_time.copy_from_slice(&anchor_data[5..13]);
+                MovieAnchor::PowerOn {
+                    rtc: Rtc::from_bytes(&anchor_data[0..5]),
+                    rtc_last_time: u64::from_le_bytes(rtc_last_time),
+                    ram: anchor_data[13..].to_vec(),
+                }
+            }
+            ANCHOR_SAVE_STATE => MovieAnchor::SaveState(anchor_data.to_vec()),
+            anchor => return Err(format!("Unknown movie anchor: {}", anchor)),
+        };
+
+        let mut inputs = Vec::with_capacity(frames);
+        let mut checksums = Vec::with_capacity(frames);
+        for frame in bytes[frames_start..].chunks(FRAME_SIZE).take(frames) {
+            inputs.push(frame[0]);
+            checksums.push(read_u32(frame, 1));
+        }
+
+        Ok(Movie {
+            is_cgb,
+            rom_hash: read_u32(bytes, 0x08),
+            rtc_seed: u64::from_le_bytes(rtc_seed),
+            anchor,
+            inputs,
+            checksums,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let anchor_data = self.anchor.to_bytes();
+        let mut bytes =
+            Vec::with_capacity(HEADER_SIZE + anchor_data.len() + self.inputs.len() * FRAME_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.push(if self.is_cgb { MODEL_CGB } else { MODEL_DMG });
+        bytes.push(match self.anchor {
+            MovieAnchor::PowerOn { .. } => ANCHOR_POWER_ON,
+            MovieAnchor::SaveState(_) => ANCHOR_SAVE_STATE,
+        });
+        bytes.extend_from_slice(&self.rom_hash.to_le_bytes());
+        bytes.extend_from_slice(&self.rtc_seed.to_le_bytes());
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(anchor_data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&anchor_data);
+        for (input, checksum) in self.inputs.iter().zip(self.checksums.iter()) {
+            bytes.push(*input);
+            bytes.extend_from_slice(&checksum.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        self.is_cgb
+    }
+
+    pub fn get_rom_hash(&self) -> u32 {
+        self.rom_hash
+    }
+
+    pub fn get_rtc_seed(&self) -> u64 {
+        self.rtc_seed
+    }
+
+    pub fn get_anchor(&self) -> &MovieAnchor {
+        &self.anchor
+    }
+
+    pub fn get_frame_count(&self) -> usize {
+        self.inputs.len()
+    }
+
+    // the buttons held down during the frame, in the bit order of Controller::get_state
+    pub fn get_input(&self, frame: usize) -> Option<u8> {
+        self.inputs.get(frame).cloned()
+    }
+
+    pub fn get_checksum(&self, frame: usize) -> Option<u32> {
+        self.checksums.get(frame).cloned()
+    }
+
+    pub fn add_frame(&mut self, input: u8, checksum: u32) {
+        self.inputs.push(input);
+        self.checksums.push(checksum);
+    }
+
+    // checks that the movie was made with this ROM on the same model
+    pub fn check_cartridge(&self, cartridge: &Cartridge) -> Result<(), String> {
+        if crc32(cartridge.get_rom()) != self.rom_hash {
+            return Err(format!(
+                "The movie was recorded with a different ROM (CRC32 {:08X})",
+                self.rom_hash
+            ));
+        }
+        if cartridge.is_cgb() != self.is_cgb {
+            return Err("The movie was recorded on a different model".to_string());
+        }
+        Ok(())
+    }
+}
+
+// the cartridge clock of a movie, it advances with the emulated frames instead of the time
+// of the machine the movie is played on
+pub struct MovieRtc {
+    seed: u64,
+    frames: Rc<Cell<u64>>,
+}
+
+impl MovieRtc {
+    pub fn new(seed: u64, frames: Rc<Cell<u64>>) -> MovieRtc {
+        MovieRtc { seed, frames }
+    }
+}
+
+impl RTC for MovieRtc {
+    fn get_current_time(&self) -> u64 {
+        self.seed + self.frames.get() * FRAME_CYCLES / CLOCK_RATE
+    }
+}
@@ -0,0 +1,163 @@
+// a save state, all numbers are little endian:
+//
+// 0x00  4  "GBSS"
+// 0x04  2  format version, 1
+// 0x06  1  model, 0 for DMG and 1 for CGB
+// 0x07  1  unused
+// 0x08  4  CRC32 of the ROM
+// 0x0C     the state of the emulator, every component writes its fields in turn
+//
+// the settings of the frontend like the sample rate, the color correction and the mixing
+// controls aren't part of it, neither are the buttons held down
+const MAGIC: &[u8; 4] = b"GBSS";
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 0x0C;
+
+const MODEL_DMG: u8 = 0;
+const MODEL_CGB: u8 = 1;
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u32, is_cgb: bool) -> StateWriter {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.push(if is_cgb { MODEL_CGB } else { MODEL_DMG });
+        bytes.push(0);
+        bytes.extend_from_slice(&rom_hash.to_le_bytes());
+        StateWriter { bytes }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.bytes.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // the length isn't written, it has to be known when reading
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// reads the fields back in the order they were written. reading past the end gives zeros,
+// finish tells whether the state had the expected size
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    truncated: bool,
+}
+
+impl<'a> StateReader<'a> {
+    // checks that the state was saved with the same ROM on the same model
+    pub fn new(bytes: &'a [u8], rom_hash: u32, is_cgb: bool) -> Result<StateReader<'a>, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+            return Err("Not a save state".to_string());
+        }
+        let version = u16::from_le_bytes([bytes[0x04], bytes[0x05]]);
+        if version != VERSION {
+            return Err(format!("Unsupported save state version: {}", version));
+        }
+        let model = if is_cgb { MODEL_CGB } else { MODEL_DMG };
+        if bytes[0x06] != model {
+            return Err("The save state was made on a different model".to_string());
+        }
+        let state_rom_hash =
+            u32::from_le_bytes([bytes[0x08], bytes[0x09], bytes[0x0A], bytes[0x0B]]);
+        if state_rom_hash != rom_hash {
+            return Err(format!(
+                "The save state was made with a different ROM (CRC32 {:08X})",
+                state_rom_hash
+            ));
+        }
+        Ok(StateReader {
+            bytes,
+            position: HEADER_SIZE,
+            truncated: false,
+        })
+    }
+
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.position + length > self.bytes.len() {
+            self.truncated = true;
+            return None;
+        }
+        let bytes = &self.bytes[self.position..self.position + length];
+        self.position += length;
+        Some(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> u8 {
+        self.take(1).map_or(0, |bytes| bytes[0])
+    }
+
+    pub fn read_bool(&mut self) -> bool {
+        self.read_u8() != 0
+    }
+
+    pub fn read_u16(&mut self) -> u16 {
+        let mut value = [0; 2];
+        self.read_bytes(&mut value);
+        u16::from_le_bytes(value)
+    }
+
+    pub fn read_u32(&mut self) -> u32 {
+        let mut value = [0; 4];
+        self.read_bytes(&mut value);
+        u32::from_le_bytes(value)
+    }
+
+    pub fn read_i32(&mut self) -> i32 {
+        let mut value = [0; 4];
+        self.read_bytes(&mut value);
+        i32::from_le_bytes(value)
+    }
+
+    pub fn read_u64(&mut self) -> u64 {
+        let mut value = [0; 8];
+        self.read_bytes(&mut value);
+        u64::from_le_bytes(value)
+    }
+
+    // fills the whole slice
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) {
+        if let Some(state_bytes) = self.take(bytes.len()) {
+            bytes.copy_from_slice(state_bytes);
+        }
+    }
+
+    pub fn finish(&self) -> Result<(), String> {
+        if self.truncated {
+            Err("Save state is truncated".to_string())
+        } else if self.position != self.bytes.len() {
+            Err("Save state is too long".to_string())
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,14 @@
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AudioChannel {
+    Pulse1 = 0,
+    Pulse2 = 1,
+    Wave = 2,
+    Noise = 3,
+}
+
+pub const AUDIO_CHANNELS: [AudioChannel; 4] = [
+    AudioChannel::Pulse1,
+    AudioChannel::Pulse2,
+    AudioChannel::Wave,
+    AudioChannel::Noise,
+];
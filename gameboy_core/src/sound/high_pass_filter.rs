@@ -0,0 +1,27 @@
+use crate::sound::resampler::CLOCK_RATE;
+
+// how much charge the output capacitor of the DMG keeps per cycle
+const CHARGE_FACTOR: f64 = 0.999_958;
+
+// the capacitor on the output removes the DC offset of the DACs, so a channel that stops
+// at a non zero level slowly fades to silence instead of staying there
+pub struct HighPassFilter {
+    capacitor: f32,
+    charge_factor: f32,
+}
+
+impl HighPassFilter {
+    pub fn new(sample_rate: u32) -> HighPassFilter {
+        let cycles_per_sample = f64::from(CLOCK_RATE) / f64::from(sample_rate);
+        HighPassFilter {
+            capacitor: 0.0,
+            charge_factor: CHARGE_FACTOR.powf(cycles_per_sample) as f32,
+        }
+    }
+
+    pub fn apply(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+        output
+    }
+}
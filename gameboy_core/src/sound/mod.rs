@@ -1,11 +1,20 @@
 use crate::bit_utils;
+use crate::save_state::{StateReader, StateWriter};
 
+pub mod audio_channel;
+pub mod high_pass_filter;
 mod noise_channel;
 mod pulse_channel;
+pub mod rate_control;
+mod resampler;
+pub mod wav_writer;
 mod wave_channel;
 
+use self::audio_channel::AudioChannel;
+use self::high_pass_filter::HighPassFilter;
 use self::noise_channel::NoiseChannel;
 use self::pulse_channel::PulseChannel;
+use self::resampler::Resampler;
 use self::wave_channel::WaveChannel;
 
 const READ_BYTE_OR_MASKS: [u8; 23] = [
@@ -13,6 +22,10 @@ const READ_BYTE_OR_MASKS: [u8; 23] = [
     0xff, 0x00, 0x00, 0xbf, 0x00, 0x00, 0x70,
 ];
 const SAMPLE_SIZE: usize = 4096;
+const FRAME_SEQUENCER_CYCLES: i32 = 8192;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+type WriteCallback = Box<dyn FnMut(u64, u16, u8)>;
 
 pub struct Sound {
     wave_channel: WaveChannel,
@@ -29,14 +42,33 @@ pub struct Sound {
     power_control: bool,
     frame_sequence_count_down: i32,
     frame_sequencer: u8,
-    down_sample_count: i32,
     buffer_fill_amount: usize,
+    sample_rate: u32,
+    left_resampler: Resampler,
+    right_resampler: Resampler,
+    left_filter: HighPassFilter,
+    right_filter: HighPassFilter,
+    left_level: f32,
+    right_level: f32,
+    // mixing controls on top of NR50/NR51, for listening to the channels in isolation
+    channel_gains: [f32; 4],
+    channel_mutes: [bool; 4],
+    channel_solos: [bool; 4],
+    // the output of every channel before the mixer, lined up with the audio buffer
+    channel_resamplers: [Resampler; 4],
+    channel_levels: [f32; 4],
+    channel_buffers: [[f32; SAMPLE_SIZE / 2]; 4],
+    // total cycles the APU has run, for timestamping the register writes
+    cycles: u64,
+    // last value written to each of 0xFF10-0xFF3F
+    registers: [u8; 0x30],
+    write_callback: Option<WriteCallback>,
 }
 
 impl Sound {
-    pub fn new() -> Sound {
+    pub fn new(is_cgb: bool) -> Sound {
         Sound {
-            wave_channel: WaveChannel::new(),
+            wave_channel: WaveChannel::new(is_cgb),
             pulse_channel_1: PulseChannel::new(),
             pulse_channel_2: PulseChannel::new(),
             noise_channel: NoiseChannel::new(),
@@ -48,21 +80,66 @@ impl Sound {
             left_enables: [false; 4],
             right_enables: [false; 4],
             power_control: false,
-            frame_sequence_count_down: 8192,
+            frame_sequence_count_down: FRAME_SEQUENCER_CYCLES,
             frame_sequencer: 0,
-            down_sample_count: 95,
             buffer_fill_amount: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            left_resampler: Resampler::new(DEFAULT_SAMPLE_RATE),
+            right_resampler: Resampler::new(DEFAULT_SAMPLE_RATE),
+            left_filter: HighPassFilter::new(DEFAULT_SAMPLE_RATE),
+            right_filter: HighPassFilter::new(DEFAULT_SAMPLE_RATE),
+            left_level: 0.0,
+            right_level: 0.0,
+            channel_gains: [1.0; 4],
+            channel_mutes: [false; 4],
+            channel_solos: [false; 4],
+            channel_resamplers: Sound::build_channel_resamplers(DEFAULT_SAMPLE_RATE),
+            channel_levels: [0.0; 4],
+            channel_buffers: [[0.0; SAMPLE_SIZE / 2]; 4],
+            cycles: 0,
+            registers: [0; 0x30],
+            write_callback: None,
         }
     }
 
+    fn build_channel_resamplers(sample_rate: u32) -> [Resampler; 4] {
+        [
+            Resampler::new(sample_rate),
+            Resampler::new(sample_rate),
+            Resampler::new(sample_rate),
+            Resampler::new(sample_rate),
+        ]
+    }
+
+    // the cycles are split up at every frame sequencer step and whenever a channel changes its
+    // output, so this can catch up on any number of cycles at once. the audio buffer is filled
+    // once at most, the samples after that wait in the resamplers for the next step
     pub fn step(&mut self, cycles: i32) -> bool {
         let mut audio_buffer_full = false;
+        self.cycles += cycles as u64;
         let mut cycles = cycles;
-        while cycles != 0 {
-            cycles -= 1;
-            self.frame_sequence_count_down -= 1;
+        while cycles > 0 {
+            let chunk = cycles
+                .min(self.frame_sequence_count_down)
+                .min(self.pulse_channel_1.get_cycles_until_change())
+                .min(self.pulse_channel_2.get_cycles_until_change())
+                .min(self.wave_channel.get_cycles_until_change())
+                .min(self.noise_channel.get_cycles_until_change());
+            cycles -= chunk;
+
+            self.pulse_channel_1.step(chunk);
+            self.pulse_channel_2.step(chunk);
+            self.wave_channel.step(chunk);
+            self.noise_channel.step(chunk);
+            self.left_resampler.advance(chunk);
+            self.right_resampler.advance(chunk);
+            for resampler in self.channel_resamplers.iter_mut() {
+                resampler.advance(chunk);
+            }
+
+            self.frame_sequence_count_down -= chunk;
             if self.frame_sequence_count_down == 0 {
-                self.frame_sequence_count_down = 8192;
+                self.frame_sequence_count_down = FRAME_SEQUENCER_CYCLES;
                 match self.frame_sequencer {
                     0 | 4 => {
                         self.pulse_channel_1.length_click();
@@ -90,86 +167,128 @@ impl Sound {
                 }
             }
 
-            self.pulse_channel_1.step();
-            self.pulse_channel_2.step();
-            self.wave_channel.step();
-            self.noise_channel.step();
-
-            self.down_sample_count -= 1;
-            if self.down_sample_count <= 0 {
-                self.down_sample_count = 95;
-
-                // left
-                let mut bufferin_0 = 0.0;
-                let mut bufferin_1;
-                let volume = (128 * self.vin_l_volume as i32) / 7;
-                if self.left_enables[0] {
-                    bufferin_1 = (self.pulse_channel_1.get_output_vol() as f32) / 100.0;
-                    Sound::mix_audio(&mut bufferin_0, bufferin_1, volume);
-                }
-                if self.left_enables[1] {
-                    bufferin_1 = (self.pulse_channel_2.get_output_vol() as f32) / 100.0;
-                    Sound::mix_audio(&mut bufferin_0, bufferin_1, volume);
-                }
-                if self.left_enables[2] {
-                    bufferin_1 = (self.wave_channel.get_output_vol() as f32) / 100.0;
-                    Sound::mix_audio(&mut bufferin_0, bufferin_1, volume);
-                }
-                if self.left_enables[3] {
-                    bufferin_1 = (self.noise_channel.get_output_vol() as f32) / 100.0;
-                    Sound::mix_audio(&mut bufferin_0, bufferin_1, volume);
-                }
-                self.audio_buffer[self.buffer_fill_amount] = bufferin_0;
+            self.update_levels();
+            if !audio_buffer_full && self.read_samples() {
+                audio_buffer_full = true;
+            }
+        }
+        audio_buffer_full
+    }
 
-                // right
-                bufferin_0 = 0.0;
-                if self.right_enables[0] {
-                    bufferin_1 = (self.pulse_channel_1.get_output_vol() as f32) / 100.0;
-                    Sound::mix_audio(&mut bufferin_0, bufferin_1, volume);
-                }
-                if self.right_enables[1] {
-                    bufferin_1 = (self.pulse_channel_2.get_output_vol() as f32) / 100.0;
-                    Sound::mix_audio(&mut bufferin_0, bufferin_1, volume);
-                }
-                if self.right_enables[2] {
-                    bufferin_1 = (self.wave_channel.get_output_vol() as f32) / 100.0;
-                    Sound::mix_audio(&mut bufferin_0, bufferin_1, volume);
-                }
-                if self.right_enables[3] {
-                    bufferin_1 = (self.noise_channel.get_output_vol() as f32) / 100.0;
-                    Sound::mix_audio(&mut bufferin_0, bufferin_1, volume);
-                }
-                self.audio_buffer[self.buffer_fill_amount + 1] = bufferin_0;
-                self.buffer_fill_amount += 2;
+    // mixes the channels and hands any change of the output levels to the resamplers
+    fn update_levels(&mut self) {
+        let outputs = self.get_channel_outputs();
+        let any_solo = self.channel_solos.iter().any(|solo| *solo);
+        let mut left_level = 0.0;
+        let mut right_level = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            let channel_level = output / 15.0;
+            if channel_level != self.channel_levels[i] {
+                self.channel_resamplers[i].add_delta(channel_level - self.channel_levels[i]);
+                self.channel_levels[i] = channel_level;
             }
 
+            if self.channel_mutes[i] || (any_solo && !self.channel_solos[i]) {
+                continue;
+            }
+            let output = output / 100.0 * self.channel_gains[i];
+            if self.left_enables[i] {
+                left_level += output;
+            }
+            if self.right_enables[i] {
+                right_level += output;
+            }
+        }
+        left_level *= f32::from(self.vin_l_volume) / 7.0;
+        right_level *= f32::from(self.vin_r_volume) / 7.0;
+
+        if left_level != self.left_level {
+            self.left_resampler.add_delta(left_level - self.left_level);
+            self.left_level = left_level;
+        }
+        if right_level != self.right_level {
+            self.right_resampler
+                .add_delta(right_level - self.right_level);
+            self.right_level = right_level;
+        }
+    }
+
+    fn get_channel_outputs(&self) -> [f32; 4] {
+        [
+            f32::from(self.pulse_channel_1.get_output_vol()),
+            f32::from(self.pulse_channel_2.get_output_vol()),
+            f32::from(self.wave_channel.get_output_vol()),
+            f32::from(self.noise_channel.get_output_vol()),
+        ]
+    }
+
+    // moves the finished samples into the audio buffer until it's full, returns true when
+    // it got full. the buffer gets handed out before it's filled again
+    fn read_samples(&mut self) -> bool {
+        let mut audio_buffer_full = false;
+        while !audio_buffer_full && self.left_resampler.get_samples_available() > 0 {
+            for (channel_buffer, resampler) in self
+                .channel_buffers
+                .iter_mut()
+                .zip(self.channel_resamplers.iter_mut())
+            {
+                channel_buffer[self.buffer_fill_amount / 2] = resampler.read_sample();
+            }
+            let left = self.left_resampler.read_sample();
+            let right = self.right_resampler.read_sample();
+            self.audio_buffer[self.buffer_fill_amount] = self.left_filter.apply(left);
+            self.audio_buffer[self.buffer_fill_amount + 1] = self.right_filter.apply(right);
+            self.buffer_fill_amount += 2;
+
             if self.buffer_fill_amount >= SAMPLE_SIZE {
                 self.buffer_fill_amount = 0;
                 audio_buffer_full = true;
-                break;
             }
         }
         audio_buffer_full
     }
 
-    // copied from the SDL2 SDL_MixAudioFormat function
-    // adapted for mixing two individual floats
-    fn mix_audio(dst: &mut f32, src: f32, volume: i32) {
-        let fmax_volume = 1.0f32 / 128f32;
-        let fvolume = volume as f32;
-        let max_audioval = std::f32::MAX as f64;
-        let min_audioval = std::f32::MIN as f64;
-
-        let src1 = src * fvolume * fmax_volume;
-        let src2 = *dst;
-
-        let mut dst_sample = src1 as f64 + src2 as f64;
-        if dst_sample > max_audioval {
-            dst_sample = max_audioval;
-        } else if dst_sample < min_audioval {
-            dst_sample = min_audioval;
+    pub fn get_cycles_until_buffer_full(&self) -> i32 {
+        let samples_left = (SAMPLE_SIZE - self.buffer_fill_amount) / 2;
+        self.left_resampler
+            .get_cycles_until_samples(samples_left)
+            .max(1)
+    }
+
+    // starts over with an empty audio buffer at the new rate
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.left_resampler = Resampler::new(sample_rate);
+        self.right_resampler = Resampler::new(sample_rate);
+        self.left_filter = HighPassFilter::new(sample_rate);
+        self.right_filter = HighPassFilter::new(sample_rate);
+        self.left_level = 0.0;
+        self.right_level = 0.0;
+        self.channel_resamplers = Sound::build_channel_resamplers(sample_rate);
+        self.channel_levels = [0.0; 4];
+        self.buffer_fill_amount = 0;
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // nudges the output rate away from the sample rate, see RateControl
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        let sample_rate = f64::from(self.sample_rate) * ratio;
+        self.left_resampler.set_sample_rate(sample_rate);
+        self.right_resampler.set_sample_rate(sample_rate);
+        for resampler in self.channel_resamplers.iter_mut() {
+            resampler.set_sample_rate(sample_rate);
         }
-        *dst = dst_sample as f32;
+    }
+
+    // hands out the samples of a partially filled buffer, so they can be played before the
+    // buffer is full
+    pub fn take_audio_samples(&mut self) -> &[f32] {
+        let fill_amount = self.buffer_fill_amount;
+        self.buffer_fill_amount = 0;
+        &self.audio_buffer[..fill_amount]
     }
 
     pub fn read_byte(&self, address: u16) -> u8 {
@@ -227,17 +346,59 @@ impl Sound {
             0xFF30..=0xFF3F => self.wave_channel.read_byte(address),
             _ => panic!("unknown address: {:04X}", address),
         };
-        if address >= 0xFF10 && address < 0xFF27 {
+        if (0xFF10..=0xFF26).contains(&address) {
             value | READ_BYTE_OR_MASKS[(address - 0xFF10) as usize]
         } else {
             value
         }
     }
 
+    // PCM12 and PCM34 of the CGB, the current digital output of two channels per register
+    pub fn read_pcm_amplitudes(&self, address: u16) -> u8 {
+        let (low, high) = match address {
+            0xFF76 => (
+                self.get_pcm_amplitude(AudioChannel::Pulse1),
+                self.get_pcm_amplitude(AudioChannel::Pulse2),
+            ),
+            0xFF77 => (
+                self.get_pcm_amplitude(AudioChannel::Wave),
+                self.get_pcm_amplitude(AudioChannel::Noise),
+            ),
+            _ => panic!("unknown address: {:04X}", address),
+        };
+        low | (high << 4)
+    }
+
+    fn get_pcm_amplitude(&self, channel: AudioChannel) -> u8 {
+        match channel {
+            AudioChannel::Pulse1 if self.pulse_channel_1.get_status() => {
+                self.pulse_channel_1.get_output_vol()
+            }
+            AudioChannel::Pulse2 if self.pulse_channel_2.get_status() => {
+                self.pulse_channel_2.get_output_vol()
+            }
+            AudioChannel::Wave if self.wave_channel.get_status() => {
+                self.wave_channel.get_output_vol()
+            }
+            AudioChannel::Noise if self.noise_channel.get_status() => {
+                self.noise_channel.get_output_vol()
+            }
+            _ => 0,
+        }
+    }
+
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        if let Some(ref mut write_callback) = self.write_callback {
+            write_callback(self.cycles, address, value);
+        }
+        self.write_register(address, value);
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
         if !self.power_control && address != 0xFF26 && address < 0xFF30 {
             return;
         }
+        self.registers[(address - 0xFF10) as usize] = value;
         match address {
             0xFF10..=0xFF14 => self.pulse_channel_1.write_byte(address, value),
             0xFF15 => (),
@@ -263,7 +424,7 @@ impl Sound {
             0xFF26 => {
                 if !bit_utils::is_set(value, 7) {
                     for i in 0xFF10..=0xFF25 {
-                        self.write_byte(i, 0);
+                        self.write_register(i, 0);
                     }
                     self.pulse_channel_1.reset_length_counter();
                     self.pulse_channel_2.reset_length_counter();
@@ -275,6 +436,7 @@ impl Sound {
                     // reset the wave table
                     for i in 0xFF30..=0xFF3F {
                         self.wave_channel.write_byte(i, 0);
+                        self.registers[i as usize - 0xFF10] = 0;
                     }
                     self.power_control = true;
                 }
@@ -285,13 +447,98 @@ impl Sound {
         }
     }
 
+    // the state of the APU itself, the output side with its buffers and mixing controls is
+    // left alone
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        self.pulse_channel_1.save_state(writer);
+        self.pulse_channel_2.save_state(writer);
+        self.wave_channel.save_state(writer);
+        self.noise_channel.save_state(writer);
+        writer.write_bool(self.vin_l_enable);
+        writer.write_u8(self.vin_l_volume);
+        writer.write_bool(self.vin_r_enable);
+        writer.write_u8(self.vin_r_volume);
+        for enable in self.left_enables.iter().chain(self.right_enables.iter()) {
+            writer.write_bool(*enable);
+        }
+        writer.write_bool(self.power_control);
+        writer.write_i32(self.frame_sequence_count_down);
+        writer.write_u8(self.frame_sequencer);
+        writer.write_bytes(&self.registers);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.pulse_channel_1.load_state(reader);
+        self.pulse_channel_2.load_state(reader);
+        self.wave_channel.load_state(reader);
+        self.noise_channel.load_state(reader);
+        self.vin_l_enable = reader.read_bool();
+        self.vin_l_volume = reader.read_u8() & 0x07;
+        self.vin_r_enable = reader.read_bool();
+        self.vin_r_volume = reader.read_u8() & 0x07;
+        for enable in self
+            .left_enables
+            .iter_mut()
+            .chain(self.right_enables.iter_mut())
+        {
+            *enable = reader.read_bool();
+        }
+        self.power_control = reader.read_bool();
+        self.frame_sequence_count_down = reader.read_i32().clamp(1, FRAME_SEQUENCER_CYCLES);
+        self.frame_sequencer = reader.read_u8() & 0x07;
+        reader.read_bytes(&mut self.registers);
+    }
+
+    // called with the APU cycle count, the address and the value of every register write
+    pub fn set_write_callback(&mut self, write_callback: Option<WriteCallback>) {
+        self.write_callback = write_callback;
+    }
+
+    pub fn get_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn get_registers(&self) -> &[u8] {
+        &self.registers
+    }
+
     pub fn get_audio_buffer(&self) -> &[f32] {
         self.audio_buffer.as_ref()
     }
+
+    // band-limited mono samples between 0 and 1, lined up with the frames of the audio buffer
+    pub fn get_channel_buffer(&self, channel: AudioChannel) -> &[f32] {
+        self.channel_buffers[channel as usize].as_ref()
+    }
+
+    pub fn set_channel_gain(&mut self, channel: AudioChannel, gain: f32) {
+        self.channel_gains[channel as usize] = gain;
+    }
+
+    pub fn get_channel_gain(&self, channel: AudioChannel) -> f32 {
+        self.channel_gains[channel as usize]
+    }
+
+    pub fn set_channel_muted(&mut self, channel: AudioChannel, muted: bool) {
+        self.channel_mutes[channel as usize] = muted;
+    }
+
+    pub fn is_channel_muted(&self, channel: AudioChannel) -> bool {
+        self.channel_mutes[channel as usize]
+    }
+
+    // while any channel is soloed, only the soloed channels can be heard
+    pub fn set_channel_solo(&mut self, channel: AudioChannel, solo: bool) {
+        self.channel_solos[channel as usize] = solo;
+    }
+
+    pub fn is_channel_solo(&self, channel: AudioChannel) -> bool {
+        self.channel_solos[channel as usize]
+    }
 }
 
 impl Default for Sound {
     fn default() -> Sound {
-        Sound::new()
+        Sound::new(false)
     }
 }
@@ -1,4 +1,5 @@
 use crate::bit_utils;
+use crate::save_state::{StateReader, StateWriter};
 
 const DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
 
@@ -47,10 +48,10 @@ impl NoiseChannel {
         }
     }
 
-    pub fn step(&mut self) {
-        self.timer -= 1;
-        if self.timer <= 0 {
-            self.timer = DIVISORS[self.divisor_code as usize] << self.clock_shift;
+    pub fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += DIVISORS[self.divisor_code as usize] << self.clock_shift;
             let result = (self.lfsr & 0x1) ^ ((self.lfsr >> 1) & 0x1);
             self.lfsr >>= 1;
             self.lfsr |= result << 14;
@@ -160,6 +161,15 @@ impl NoiseChannel {
         self.enabled && self.dac_enabled
     }
 
+    // the output only changes when the timer runs out while the channel is playing
+    pub fn get_cycles_until_change(&self) -> i32 {
+        if self.get_status() {
+            self.timer.max(1)
+        } else {
+            i32::MAX
+        }
+    }
+
     pub fn reset_length_counter(&mut self) {
         self.length_counter = 0;
     }
@@ -167,4 +177,46 @@ impl NoiseChannel {
     pub fn get_output_vol(&self) -> u8 {
         self.output_vol
     }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.length_load);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.volume_load);
+        writer.write_bool(self.envelope_add_mode);
+        writer.write_i32(self.envelope_period);
+        writer.write_u8(self.envelope_period_load);
+        writer.write_u8(self.length_counter);
+        writer.write_u8(self.divisor_code);
+        writer.write_bool(self.width_mode);
+        writer.write_u8(self.clock_shift);
+        writer.write_bool(self.length_enable);
+        writer.write_bool(self.trigger_bit);
+        writer.write_bool(self.dac_enabled);
+        writer.write_bool(self.enabled);
+        writer.write_i32(self.timer);
+        writer.write_bool(self.envelope_running);
+        writer.write_u16(self.lfsr);
+        writer.write_u8(self.output_vol);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.length_load = reader.read_u8();
+        self.volume = reader.read_u8();
+        self.volume_load = reader.read_u8();
+        self.envelope_add_mode = reader.read_bool();
+        self.envelope_period = reader.read_i32();
+        self.envelope_period_load = reader.read_u8();
+        self.length_counter = reader.read_u8();
+        self.divisor_code = reader.read_u8() & 0x07;
+        self.width_mode = reader.read_bool();
+        self.clock_shift = reader.read_u8() & 0x0F;
+        self.length_enable = reader.read_bool();
+        self.trigger_bit = reader.read_bool();
+        self.dac_enabled = reader.read_bool();
+        self.enabled = reader.read_bool();
+        self.timer = reader.read_i32();
+        self.envelope_running = reader.read_bool();
+        self.lfsr = reader.read_u16();
+        self.output_vol = reader.read_u8();
+    }
 }
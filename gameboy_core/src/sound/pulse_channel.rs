@@ -1,4 +1,5 @@
 use crate::bit_utils;
+use crate::save_state::{StateReader, StateWriter};
 
 const DUTY_TABLE: [[bool; 8]; 4] = [
     [false, false, false, false, false, false, false, true],
@@ -62,10 +63,10 @@ impl PulseChannel {
         }
     }
 
-    pub fn step(&mut self) {
-        self.timer -= 1;
-        if self.timer <= 0 {
-            self.timer = (2048 - self.timer_load as i32) * 4;
+    pub fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += (2048 - self.timer_load as i32) * 4;
             self.sequence_pointer = (self.sequence_pointer + 1) & 0x07;
         }
         if self.enabled && self.dac_enabled {
@@ -219,6 +220,15 @@ impl PulseChannel {
         self.enabled && self.dac_enabled
     }
 
+    // the output only changes when the timer runs out while the channel is playing
+    pub fn get_cycles_until_change(&self) -> i32 {
+        if self.get_status() {
+            self.timer.max(1)
+        } else {
+            i32::MAX
+        }
+    }
+
     pub fn reset_length_counter(&mut self) {
         self.length_counter = 0;
     }
@@ -226,4 +236,56 @@ impl PulseChannel {
     pub fn get_output_vol(&self) -> u8 {
         self.output_vol
     }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.sweep_shift);
+        writer.write_bool(self.sweep_negate);
+        writer.write_u8(self.sweep_period_load);
+        writer.write_u8(self.length_load);
+        writer.write_u8(self.duty);
+        writer.write_i32(self.envelope_period);
+        writer.write_u8(self.envelope_period_load);
+        writer.write_bool(self.envelope_add_mode);
+        writer.write_u8(self.volume_load);
+        writer.write_u8(self.volume);
+        writer.write_u16(self.timer_load);
+        writer.write_bool(self.length_enable);
+        writer.write_bool(self.trigger_bit);
+        writer.write_u8(self.length_counter);
+        writer.write_bool(self.dac_enabled);
+        writer.write_bool(self.enabled);
+        writer.write_i32(self.timer);
+        writer.write_bool(self.envelope_running);
+        writer.write_u16(self.sweep_shadow);
+        writer.write_bool(self.sweep_enable);
+        writer.write_i32(self.sweep_period);
+        writer.write_u8(self.output_vol);
+        writer.write_u32(self.sequence_pointer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.sweep_shift = reader.read_u8();
+        self.sweep_negate = reader.read_bool();
+        self.sweep_period_load = reader.read_u8();
+        self.length_load = reader.read_u8();
+        self.duty = reader.read_u8() & 0x03;
+        self.envelope_period = reader.read_i32();
+        self.envelope_period_load = reader.read_u8();
+        self.envelope_add_mode = reader.read_bool();
+        self.volume_load = reader.read_u8();
+        self.volume = reader.read_u8();
+        self.timer_load = reader.read_u16() & 0x07FF;
+        self.length_enable = reader.read_bool();
+        self.trigger_bit = reader.read_bool();
+        self.length_counter = reader.read_u8();
+        self.dac_enabled = reader.read_bool();
+        self.enabled = reader.read_bool();
+        self.timer = reader.read_i32();
+        self.envelope_running = reader.read_bool();
+        self.sweep_shadow = reader.read_u16();
+        self.sweep_enable = reader.read_bool();
+        self.sweep_period = reader.read_i32();
+        self.output_vol = reader.read_u8();
+        self.sequence_pointer = reader.read_u32() & 0x07;
+    }
 }
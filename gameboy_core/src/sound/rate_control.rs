@@ -0,0 +1,32 @@
+// the frontend's refresh rate and audio clock never exactly match the gameboy's 59.73 Hz, so
+// the audio output rate gets nudged a little to keep the queue at its target fill level.
+// the deviation is small enough that the change in pitch can't be heard
+pub struct RateControl {
+    target_fill: usize,
+    max_deviation: f64,
+}
+
+impl RateControl {
+    pub fn new(target_fill: usize, max_deviation: f64) -> RateControl {
+        RateControl {
+            target_fill,
+            max_deviation,
+        }
+    }
+
+    // ratio to pass to Gameboy::set_audio_rate_ratio for the given number of samples per
+    // channel waiting to be played. an empty queue speeds up the output, a queue twice the
+    // target size slows it down by the maximum deviation
+    pub fn get_ratio(&self, fill: usize) -> f64 {
+        if self.target_fill == 0 {
+            return 1.0;
+        }
+        let target_fill = self.target_fill as f64;
+        let difference = (target_fill - fill as f64) / target_fill;
+        1.0 + self.max_deviation * difference.clamp(-1.0, 1.0)
+    }
+
+    pub fn get_target_fill(&self) -> usize {
+        self.target_fill
+    }
+}
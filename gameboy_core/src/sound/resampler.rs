@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// the APU runs at the system clock, which doesn't change in CGB double speed mode
+pub const CLOCK_RATE: u32 = 4_194_304;
+
+const FRAC_BITS: u32 = 32;
+const FRAC_MASK: u64 = (1 << FRAC_BITS) - 1;
+const PHASE_BITS: u32 = 5;
+const PHASES: usize = 1 << PHASE_BITS;
+const KERNEL_WIDTH: usize = 16;
+// a bit below the nyquist frequency of the output, so the transition band of the kernel
+// doesn't fold back into the audible range
+const CUTOFF: f64 = 0.45;
+
+// band-limited step synthesis. instead of sampling the channel outputs, every change of the
+// output level is added as a band-limited step at its exact position between two output
+// samples, so square waves don't alias no matter what the output rate is
+pub struct Resampler {
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    buffer: VecDeque<f32>,
+    // position of the current cycle in output samples, relative to the start of the buffer
+    offset: u64,
+    // output samples per cycle
+    factor: u64,
+    integrator: f32,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Resampler {
+        Resampler {
+            kernel: Resampler::build_kernel(),
+            buffer: VecDeque::from(vec![0.0; KERNEL_WIDTH * 2]),
+            offset: 0,
+            factor: (u64::from(sample_rate) << FRAC_BITS) / u64::from(CLOCK_RATE),
+            integrator: 0.0,
+        }
+    }
+
+    // windowed sinc impulses, one for each fraction of a sample a step can start at. every
+    // impulse sums up to 1 so the integrated steps land exactly on the new level
+    fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+        let mut kernel = vec![[0.0; KERNEL_WIDTH]; PHASES];
+        for (phase, impulse) in kernel.iter_mut().enumerate() {
+            let center = (KERNEL_WIDTH / 2) as f64 - 1.0 + phase as f64 / PHASES as f64;
+            let mut values = [0.0f64; KERNEL_WIDTH];
+            for (i, value) in values.iter_mut().enumerate() {
+                let x = i as f64 - center;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    let angle = PI * 2.0 * CUTOFF * x;
+                    angle.sin() / angle
+                };
+                // blackman window over the width of the kernel
+                let position = (x + (KERNEL_WIDTH / 2) as f64) / KERNEL_WIDTH as f64;
+                let window = if position <= 0.0 || position >= 1.0 {
+                    0.0
+                } else {
+                    0.42 - 0.5 * (PI * 2.0 * position).cos() + 0.08 * (PI * 4.0 * position).cos()
+                };
+                *value = sinc * window;
+            }
+            let sum: f64 = values.iter().sum();
+            for (i, value) in values.iter().enumerate() {
+                impulse[i] = (value / sum) as f32;
+            }
+        }
+        kernel
+    }
+
+    // adds a change of the input level at the current position
+    pub fn add_delta(&mut self, delta: f32) {
+        let index = (self.offset >> FRAC_BITS) as usize;
+        let phase = ((self.offset & FRAC_MASK) >> (FRAC_BITS - PHASE_BITS)) as usize;
+        if self.buffer.len() < index + KERNEL_WIDTH {
+            self.buffer.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        let impulse = &self.kernel[phase];
+        for (sample, value) in self
+            .buffer
+            .range_mut(index..index + KERNEL_WIDTH)
+            .zip(impulse.iter())
+        {
+            *sample += delta * value;
+        }
+    }
+
+    // changes the rate without dropping any of the samples in progress
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.factor = (sample_rate * (1u64 << FRAC_BITS) as f64 / f64::from(CLOCK_RATE)) as u64;
+    }
+
+    pub fn advance(&mut self, cycles: i32) {
+        self.offset += self.factor * cycles as u64;
+    }
+
+    // finished output samples, no step added from now on can reach back to them
+    pub fn get_samples_available(&self) -> usize {
+        (self.offset >> FRAC_BITS) as usize
+    }
+
+    pub fn get_cycles_until_samples(&self, samples: usize) -> i32 {
+        let target = (samples as u64) << FRAC_BITS;
+        if target <= self.offset {
+            return 0;
+        }
+        let cycles = (target - self.offset - 1) / self.factor + 1;
+        cycles.min(i32::MAX as u64) as i32
+    }
+
+    pub fn read_sample(&mut self) -> f32 {
+        self.integrator += self.buffer.pop_front().unwrap_or(0.0);
+        self.buffer.push_back(0.0);
+        self.offset -= 1 << FRAC_BITS;
+        self.integrator
+    }
+}
@@ -0,0 +1,70 @@
+use std::io::{Seek, SeekFrom, Write};
+
+const HEADER_SIZE: u32 = 44;
+const BYTES_PER_SAMPLE: u16 = 2;
+
+// writes 16-bit PCM WAV files. the sizes in the header are filled in by finish, until then
+// they are left at 0
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, channels: u16, sample_rate: u32) -> Result<WavWriter<W>, String> {
+        let block_align = channels * BYTES_PER_SAMPLE;
+        let byte_rate = sample_rate * u32::from(block_align);
+
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_all(&header).map_err(|e| format!("{:?}", e))?;
+
+        Ok(WavWriter {
+            writer,
+            data_size: 0,
+        })
+    }
+
+    // interleaved samples between -1 and 1
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<(), String> {
+        let mut data = Vec::with_capacity(samples.len() * BYTES_PER_SAMPLE as usize);
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        self.writer
+            .write_all(&data)
+            .map_err(|e| format!("{:?}", e))?;
+        self.data_size += data.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, String> {
+        let riff_size = HEADER_SIZE - 8 + self.data_size;
+        self.writer
+            .seek(SeekFrom::Start(4))
+            .and_then(|_| self.writer.write_all(&riff_size.to_le_bytes()))
+            .and_then(|_| {
+                self.writer
+                    .seek(SeekFrom::Start(u64::from(HEADER_SIZE) - 4))
+            })
+            .and_then(|_| self.writer.write_all(&self.data_size.to_le_bytes()))
+            .and_then(|_| self.writer.seek(SeekFrom::End(0)))
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(self.writer)
+    }
+}
@@ -1,6 +1,8 @@
 use crate::bit_utils;
+use crate::save_state::{StateReader, StateWriter};
 
 pub struct WaveChannel {
+    is_cgb: bool,
     dac_enabled: bool,
     length_load: u8,
     timer_load: u16,
@@ -16,8 +18,9 @@ pub struct WaveChannel {
 }
 
 impl WaveChannel {
-    pub fn new() -> WaveChannel {
+    pub fn new(is_cgb: bool) -> WaveChannel {
         WaveChannel {
+            is_cgb,
             dac_enabled: false,
             length_load: 0,
             timer_load: 0,
@@ -33,10 +36,10 @@ impl WaveChannel {
         }
     }
 
-    pub fn step(&mut self) {
-        self.timer -= 1;
-        if self.timer <= 0 {
-            self.timer = (2048 - self.timer_load as i32) * 2;
+    pub fn step(&mut self, cycles: i32) {
+        self.timer -= cycles;
+        while self.timer <= 0 {
+            self.timer += (2048 - self.timer_load as i32) * 2;
             self.position_counter = (self.position_counter + 1) & 0x1F;
             if self.enabled && self.dac_enabled {
                 let position = self.position_counter / 2;
@@ -81,7 +84,10 @@ impl WaveChannel {
                 let trigger_bit = if self.trigger_bit { 1 } else { 0 };
                 ((self.timer_load >> 8) & 0x07) as u8 | (length_enable << 6) | (trigger_bit << 7)
             }
-            0xFF30..=0xFF3F => self.wave_table[(address - 0xFF30) as usize],
+            0xFF30..=0xFF3F => match self.get_wave_table_index(address) {
+                Some(index) => self.wave_table[index],
+                None => 0xFF,
+            },
             _ => panic!("unknown address: {:04X}", address),
         }
     }
@@ -97,14 +103,51 @@ impl WaveChannel {
                 self.length_enable = bit_utils::is_set(value, 6);
                 self.trigger_bit = bit_utils::is_set(value, 7);
                 if self.trigger_bit {
+                    // on the DMG, retriggering right as the channel reads a sample
+                    // overwrites the start of the wave table
+                    if !self.is_cgb && self.get_status() && self.timer <= 2 {
+                        self.corrupt_wave_table();
+                    }
                     self.trigger();
                 }
             }
-            0xFF30..=0xFF3F => self.wave_table[(address - 0xFF30) as usize] = value,
+            0xFF30..=0xFF3F => {
+                if let Some(index) = self.get_wave_table_index(address) {
+                    self.wave_table[index] = value;
+                }
+            }
             _ => panic!("unknown address: {:04X}", address),
         }
     }
 
+    // while the channel is playing, the CPU can only reach the byte the channel is reading.
+    // the DMG only lets the access through right when the channel reads the byte
+    fn get_wave_table_index(&self, address: u16) -> Option<usize> {
+        if !self.get_status() {
+            return Some((address - 0xFF30) as usize);
+        }
+        let period = (2048 - self.timer_load as i32) * 2;
+        if self.is_cgb || period - self.timer < 2 {
+            Some((self.position_counter / 2) as usize)
+        } else {
+            None
+        }
+    }
+
+    // the byte about to be read gets copied to the first byte, or its aligned block of 4
+    // bytes to the first 4 bytes
+    fn corrupt_wave_table(&mut self) {
+        let position = (((self.position_counter + 1) & 0x1F) / 2) as usize;
+        if position < 4 {
+            self.wave_table[0] = self.wave_table[position];
+        } else {
+            let start = position & !0x03;
+            for i in 0..4 {
+                self.wave_table[i] = self.wave_table[start + i];
+            }
+        }
+    }
+
     fn trigger(&mut self) {
         self.enabled = true;
         if self.length_counter == 0 {
@@ -118,6 +161,15 @@ impl WaveChannel {
         self.enabled && self.dac_enabled
     }
 
+    // the output only changes when the timer runs out while the channel is playing
+    pub fn get_cycles_until_change(&self) -> i32 {
+        if self.get_status() {
+            self.timer.max(1)
+        } else {
+            i32::MAX
+        }
+    }
+
     pub fn reset_length_counter(&mut self) {
         self.length_counter = 0;
     }
@@ -125,4 +177,34 @@ impl WaveChannel {
     pub fn get_output_vol(&self) -> u8 {
         self.output_vol
     }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.dac_enabled);
+        writer.write_u8(self.length_load);
+        writer.write_u16(self.timer_load);
+        writer.write_bool(self.length_enable);
+        writer.write_bool(self.trigger_bit);
+        writer.write_u8(self.volume_code);
+        writer.write_bytes(&self.wave_table);
+        writer.write_u16(self.length_counter);
+        writer.write_bool(self.enabled);
+        writer.write_i32(self.timer);
+        writer.write_u8(self.position_counter);
+        writer.write_u8(self.output_vol);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) {
+        self.dac_enabled = reader.read_bool();
+        self.length_load = reader.read_u8();
+        self.timer_load = reader.read_u16() & 0x07FF;
+        self.length_enable = reader.read_bool();
+        self.trigger_bit = reader.read_bool();
+        self.volume_code = reader.read_u8() & 0x03;
+        reader.read_bytes(&mut self.wave_table);
+        self.length_counter = reader.read_u16();
+        self.enabled = reader.read_bool();
+        self.timer = reader.read_i32();
+        self.position_counter = reader.read_u8() & 0x1F;
+        self.output_vol = reader.read_u8();
+    }
 }
@@ -1,6 +1,9 @@
 use crate::mmu::interrupt::Interrupt;
 use crate::mmu::{self, Memory};
 
+// upper bound for the time between two updates while the timer has nothing to do
+const IDLE_CYCLES: i32 = 0x10000;
+
 pub struct Timer {}
 
 impl Timer {
@@ -8,44 +11,67 @@ impl Timer {
         Timer {}
     }
 
+    // the cycles are split up whenever the selected divider bit flips and at the steps of an
+    // overflow, so this can catch up on any number of cycles at once
     pub fn update(&mut self, cycles: i32, memory: &mut Memory) {
-        memory.div_cycles += cycles;
-
-        let div_cycles = 256;
-
-        while memory.div_cycles >= div_cycles {
-            memory.div_cycles -= div_cycles;
-            let mut div = memory.load(mmu::DIVIDER_INDEX);
-            div = div.wrapping_add(1);
-            memory.store(mmu::DIVIDER_INDEX, div);
-        }
-
-        let tac = memory.load(mmu::TIMER_CONTROL_INDEX);
-
-        if (tac & 0x4) != 0 {
-            memory.tima_cycles += cycles;
-
-            let freq = match tac & 0x03 {
-                0 => 1024,
-                1 => 16,
-                2 => 64,
-                3 => 256,
-                _ => unreachable!(),
-            };
-
-            while memory.tima_cycles >= freq {
-                memory.tima_cycles -= freq;
-                let mut tima = memory.load(mmu::SELECTABLE_TIMER_INDEX);
+        let mut cycles = cycles;
+        while cycles > 0 {
+            let mut chunk = cycles;
+            if let Some(bit) = memory.get_timer_bit() {
+                let half_period = 1 << bit;
+                let divider = i32::from(memory.timer_state.divider);
+                chunk = chunk.min(half_period - (divider & (half_period - 1)));
+            }
+            if memory.timer_state.reload_cycles > 0 {
+                chunk = chunk.min(memory.timer_state.reload_cycles);
+            }
+            if memory.timer_state.overflow_cycles > 0 {
+                chunk = chunk.min(memory.timer_state.overflow_cycles);
+            }
+            cycles -= chunk;
 
-                tima = tima.wrapping_add(1);
+            if memory.timer_state.reload_cycles > 0 {
+                memory.timer_state.reload_cycles -= chunk;
+            }
 
-                if tima == 0 {
-                    tima = memory.load(mmu::TIMER_RESET_INDEX);
+            // TIMA reads as 0 for one M-cycle after it overflows, then TMA is loaded
+            if memory.timer_state.overflow_cycles > 0 {
+                memory.timer_state.overflow_cycles -= chunk;
+                if memory.timer_state.overflow_cycles == 0 {
+                    let tma = memory.load(mmu::TIMER_RESET_INDEX);
+                    memory.store(mmu::SELECTABLE_TIMER_INDEX, tma);
                     memory.request_interrupt(Interrupt::Timer);
+                    memory.timer_state.reload_cycles = 4;
                 }
+            }
 
-                memory.store(mmu::SELECTABLE_TIMER_INDEX, tima);
+            let divider = memory.timer_state.divider.wrapping_add(chunk as u16);
+            memory.set_divider(divider);
+        }
+    }
+
+    // cycles until the timer requests its next interrupt
+    pub fn get_cycles_until_event(&self, memory: &Memory) -> i32 {
+        if memory.timer_state.overflow_cycles > 0 {
+            return memory.timer_state.overflow_cycles;
+        }
+        match (
+            memory.get_timer_bit(),
+            Timer::get_cycles_until_falling_edge(memory),
+        ) {
+            (Some(bit), Some(edge_cycles)) => {
+                let period = 2 << bit;
+                let increments = 0xFF - i32::from(memory.load(mmu::SELECTABLE_TIMER_INDEX));
+                (edge_cycles + increments * period + 4).min(IDLE_CYCLES)
             }
+            _ => IDLE_CYCLES,
         }
     }
+
+    fn get_cycles_until_falling_edge(memory: &Memory) -> Option<i32> {
+        memory.get_timer_bit().map(|bit| {
+            let period = 2 << bit;
+            period - (i32::from(memory.timer_state.divider) & (period - 1))
+        })
+    }
 }
@@ -0,0 +1,285 @@
+use crate::sound::Sound;
+
+// VGM 1.61, the first version with Game Boy support
+const VERSION: u32 = 0x161;
+const HEADER_SIZE: usize = 0x100;
+const EOF_OFFSET_INDEX: usize = 0x04;
+const VERSION_INDEX: usize = 0x08;
+const TOTAL_SAMPLES_INDEX: usize = 0x18;
+const LOOP_OFFSET_INDEX: usize = 0x1C;
+const DATA_OFFSET_INDEX: usize = 0x34;
+const DMG_CLOCK_INDEX: usize = 0x80;
+// waits are counted in samples at this rate, no matter the rate of the output
+const VGM_SAMPLE_RATE: u64 = 44100;
+const CLOCK_RATE: u64 = 4_194_304;
+
+const DMG_WRITE: u8 = 0xB3;
+const WAIT: u8 = 0x61;
+const WAIT_60TH: u8 = 0x62;
+const WAIT_50TH: u8 = 0x63;
+const END_OF_SOUND_DATA: u8 = 0x66;
+const DATA_BLOCK: u8 = 0x67;
+const MAX_WAIT: u64 = 0xFFFF;
+
+fn cycles_to_samples(cycles: u64) -> u64 {
+    cycles * VGM_SAMPLE_RATE / CLOCK_RATE
+}
+
+fn samples_to_cycles(samples: u64) -> u64 {
+    samples * CLOCK_RATE / VGM_SAMPLE_RATE
+}
+
+fn read_u32(bytes: &[u8], index: usize) -> u32 {
+    u32::from(bytes[index])
+        | (u32::from(bytes[index + 1]) << 8)
+        | (u32::from(bytes[index + 2]) << 16)
+        | (u32::from(bytes[index + 3]) << 24)
+}
+
+fn write_u32(bytes: &mut [u8], index: usize, value: u32) {
+    bytes[index..index + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// turns APU register writes into the commands of a VGM file. the log starts with the state
+// of the registers at the time it was created, the channels only start playing with their
+// next trigger
+pub struct VgmWriter {
+    commands: Vec<u8>,
+    start_cycles: u64,
+    samples: u64,
+}
+
+impl VgmWriter {
+    pub fn new(sound: &Sound) -> VgmWriter {
+        let mut vgm_writer = VgmWriter {
+            commands: Vec::new(),
+            start_cycles: sound.get_cycles(),
+            samples: 0,
+        };
+        let registers = sound.get_registers();
+        // the power has to be on for the other registers to be written
+        vgm_writer.add_command(0xFF26, registers[0x16]);
+        for (i, value) in registers.iter().enumerate() {
+            let address = 0xFF10 + i as u16;
+            match address {
+                0xFF26..=0xFF2F => (),
+                // the trigger bit would restart the channel
+                0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => vgm_writer.add_command(address, value & 0x7F),
+                _ => vgm_writer.add_command(address, *value),
+            }
+        }
+        vgm_writer
+    }
+
+    pub fn log_write(&mut self, cycles: u64, address: u16, value: u8) {
+        self.wait_until(cycles);
+        self.add_command(address, value);
+    }
+
+    fn add_command(&mut self, address: u16, value: u8) {
+        self.commands
+            .extend_from_slice(&[DMG_WRITE, (address - 0xFF10) as u8, value]);
+    }
+
+    fn wait_until(&mut self, cycles: u64) {
+        let samples = cycles_to_samples(cycles.saturating_sub(self.start_cycles));
+        while self.samples < samples {
+            let wait = (samples - self.samples).min(MAX_WAIT);
+            self.commands.push(WAIT);
+            self.commands
+                .extend_from_slice(&(wait as u16).to_le_bytes());
+            self.samples += wait;
+        }
+    }
+
+    // the whole file, with the log running until the given cycle count
+    pub fn finish(mut self, cycles: u64) -> Vec<u8> {
+        self.wait_until(cycles);
+        self.commands.push(END_OF_SOUND_DATA);
+
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(b"Vgm ");
+        let eof_offset = (HEADER_SIZE + self.commands.len() - EOF_OFFSET_INDEX) as u32;
+        write_u32(&mut bytes, EOF_OFFSET_INDEX, eof_offset);
+        write_u32(&mut bytes, VERSION_INDEX, VERSION);
+        write_u32(&mut bytes, TOTAL_SAMPLES_INDEX, self.samples as u32);
+        let data_offset = (HEADER_SIZE - DATA_OFFSET_INDEX) as u32;
+        write_u32(&mut bytes, DATA_OFFSET_INDEX, data_offset);
+        write_u32(&mut bytes, DMG_CLOCK_INDEX, CLOCK_RATE as u32);
+        bytes.extend_from_slice(&self.commands);
+        bytes
+    }
+}
+
+// plays back the Game Boy part of a VGM file on an APU of its own. the commands of other
+// chips are skipped
+pub struct VgmPlayer {
+    sound: Sound,
+    data: Vec<u8>,
+    data_offset: usize,
+    loop_offset: Option<usize>,
+    position: usize,
+    // how far the commands have been processed, and how far the APU has run
+    samples: u64,
+    cycles: u64,
+    finished: bool,
+    // a loop without any waits in it would never let the APU run
+    loop_samples: u64,
+    audio: Vec<f32>,
+}
+
+impl VgmPlayer {
+    pub fn from_bytes(bytes: &[u8]) -> Result<VgmPlayer, String> {
+        if bytes.len() < 0x40 || &bytes[0..4] != b"Vgm " {
+            return Err("Not a VGM file".to_string());
+        }
+        let version = read_u32(bytes, VERSION_INDEX);
+        if version < VERSION || bytes.len() < DMG_CLOCK_INDEX + 4 {
+            return Err(format!("VGM version {:X} has no Game Boy support", version));
+        }
+        if read_u32(bytes, DMG_CLOCK_INDEX) == 0 {
+            return Err("VGM file has no Game Boy data".to_string());
+        }
+        let data_offset = DATA_OFFSET_INDEX + read_u32(bytes, DATA_OFFSET_INDEX) as usize;
+        let loop_offset = match read_u32(bytes, LOOP_OFFSET_INDEX) {
+            0 => None,
+            offset => Some(LOOP_OFFSET_INDEX + offset as usize),
+        };
+        if data_offset > bytes.len() {
+            return Err(format!("Invalid VGM data offset: 0x{:X}", data_offset));
+        }
+
+        Ok(VgmPlayer {
+            sound: Sound::new(false),
+            data: bytes.to_vec(),
+            data_offset,
+            loop_offset,
+            position: data_offset,
+            samples: 0,
+            cycles: 0,
+            finished: false,
+            loop_samples: 0,
+            audio: Vec::new(),
+        })
+    }
+
+    // for the sample rate and the channel controls
+    pub fn get_sound_mut(&mut self) -> &mut Sound {
+        &mut self.sound
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // starts over from the beginning with a silent APU, keeping the sample rate
+    pub fn restart(&mut self) {
+        let sample_rate = self.sound.get_sample_rate();
+        self.sound = Sound::new(false);
+        self.sound.set_sample_rate(sample_rate);
+        self.position = self.data_offset;
+        self.samples = 0;
+        self.cycles = 0;
+        self.finished = false;
+        self.loop_samples = 0;
+    }
+
+    // runs the commands for the given number of cycles and returns the interleaved stereo
+    // samples the APU produced. files with a loop point keep playing forever
+    pub fn run(&mut self, cycles: u64) -> &[f32] {
+        self.audio.clear();
+        let end_cycles = self.cycles + cycles;
+        while self.cycles < end_cycles {
+            let command_cycles = samples_to_cycles(self.samples);
+            if command_cycles <= self.cycles && !self.finished {
+                self.run_command();
+                continue;
+            }
+            let next_cycles = if self.finished {
+                end_cycles
+            } else {
+                command_cycles.min(end_cycles)
+            };
+            self.step_sound(next_cycles - self.cycles);
+        }
+        self.audio
+            .extend_from_slice(self.sound.take_audio_samples());
+        &self.audio
+    }
+
+    // a wait can be longer than the audio buffer, so the buffer is read whenever it gets full
+    fn step_sound(&mut self, cycles: u64) {
+        self.cycles += cycles;
+        let mut cycles = cycles;
+        while cycles > 0 {
+            let chunk = cycles.min(self.sound.get_cycles_until_buffer_full() as u64);
+            cycles -= chunk;
+            if self.sound.step(chunk as i32) {
+                self.audio.extend_from_slice(self.sound.get_audio_buffer());
+            }
+        }
+    }
+
+    fn run_command(&mut self) {
+        let command = match self.data.get(self.position) {
+            Some(command) => *command,
+            None => {
+                self.end_of_data();
+                return;
+            }
+        };
+        let data = &self.data;
+        let position = self.position;
+        let operand = |index: usize| data.get(position + index).cloned().unwrap_or(0);
+        let length = match command {
+            DMG_WRITE => {
+                let register = operand(1);
+                if register < 0x30 {
+                    self.sound
+                        .write_byte(0xFF10 + u16::from(register), operand(2));
+                }
+                3
+            }
+            WAIT => {
+                self.samples += u64::from(operand(1)) | (u64::from(operand(2)) << 8);
+                3
+            }
+            WAIT_60TH => {
+                self.samples += 735;
+                1
+            }
+            WAIT_50TH => {
+                self.samples += 882;
+                1
+            }
+            0x70..=0x7F => {
+                self.samples += u64::from(command & 0x0F) + 1;
+                1
+            }
+            END_OF_SOUND_DATA => {
+                self.end_of_data();
+                return;
+            }
+            DATA_BLOCK => {
+                let size = u32::from_le_bytes([operand(3), operand(4), operand(5), operand(6)]);
+                7 + size as usize
+            }
+            0x30..=0x3F | 0x4F | 0x50 => 2,
+            0x40..=0x4E | 0x51..=0x5F | 0xA0..=0xBF => 3,
+            0xC0..=0xDF => 4,
+            0xE0..=0xFF => 5,
+            _ => 1,
+        };
+        self.position += length;
+    }
+
+    fn end_of_data(&mut self) {
+        match self.loop_offset {
+            Some(loop_offset) if self.samples > self.loop_samples => {
+                self.position = loop_offset;
+                self.loop_samples = self.samples;
+            }
+            _ => self.finished = true,
+        }
+    }
+}
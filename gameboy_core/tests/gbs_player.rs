@@ -0,0 +1,128 @@
+use gameboy_core::{GbsFile, GbsPlayer};
+use std::io::Cursor;
+
+const LOAD_ADDRESS: u16 = 0x0400;
+const PLAY_OFFSET: usize = 0x20;
+
+// INIT turns the APU on and starts pulse channel 1 on both sides, PLAY returns right away
+fn build_gbs() -> Vec<u8> {
+    let mut bytes = vec![0; 0x70];
+    bytes[0..3].copy_from_slice(b"GBS");
+    bytes[0x03] = 1;
+    bytes[0x04] = 2;
+    bytes[0x05] = 1;
+    let play_address = LOAD_ADDRESS + PLAY_OFFSET as u16;
+    for (index, word) in [
+        (0x06, LOAD_ADDRESS),
+        (0x08, LOAD_ADDRESS),
+        (0x0A, play_address),
+        (0x0C, 0xFFFE),
+    ]
+    .iter()
+    {
+        bytes[*index..*index + 2].copy_from_slice(&word.to_le_bytes());
+    }
+    bytes[0x10..0x14].copy_from_slice(b"Test");
+
+    let mut code = vec![0; PLAY_OFFSET + 1];
+    let init = [
+        0x3E, 0x80, 0xE0, 0x26, // NR52
+        0x3E, 0x77, 0xE0, 0x24, // NR50
+        0x3E, 0xFF, 0xE0, 0x25, // NR51
+        0x3E, 0x80, 0xE0, 0x11, // NR11
+        0x3E, 0xF0, 0xE0, 0x12, // NR12
+        0x3E, 0x00, 0xE0, 0x13, // NR13
+        0x3E, 0x87, 0xE0, 0x14, // NR14
+        0xC9,
+    ];
+    code[..init.len()].copy_from_slice(&init);
+    code[PLAY_OFFSET] = 0xC9;
+    bytes.extend_from_slice(&code);
+    bytes
+}
+
+#[test]
+fn parses_the_header() {
+    let file = GbsFile::from_bytes(&build_gbs()).unwrap();
+    assert_eq!(file.get_song_count(), 2);
+    assert_eq!(file.get_first_song(), 0);
+    assert_eq!(file.get_title(), "Test");
+}
+
+#[test]
+fn rejects_a_bad_magic() {
+    let mut bytes = build_gbs();
+    bytes[0] = b'X';
+    assert!(GbsFile::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn rejects_version_2() {
+    let mut bytes = build_gbs();
+    bytes[0x03] = 2;
+    assert!(GbsFile::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn rejects_no_songs() {
+    let mut bytes = build_gbs();
+    bytes[0x04] = 0;
+    bytes[0x05] = 0;
+    assert!(GbsFile::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn rejects_a_load_address_below_0x400() {
+    let mut bytes = build_gbs();
+    bytes[0x06..0x08].copy_from_slice(&0x03FFu16.to_le_bytes());
+    assert!(GbsFile::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn rejects_a_first_song_past_the_song_count() {
+    let mut bytes = build_gbs();
+    bytes[0x05] = 3;
+    assert!(GbsFile::from_bytes(&bytes).is_err());
+}
+
+#[test]
+fn keeps_titles_that_are_not_ascii() {
+    let mut bytes = build_gbs();
+    for byte in bytes[0x10..0x30].iter_mut() {
+        *byte = 0xE9;
+    }
+    let file = GbsFile::from_bytes(&bytes).unwrap();
+    assert_eq!(file.get_title().chars().count(), 32);
+    assert!(GbsPlayer::new(file).is_ok());
+}
+
+#[test]
+fn renders_a_wav_of_the_requested_length() {
+    let file = GbsFile::from_bytes(&build_gbs()).unwrap();
+    let mut player = GbsPlayer::new(file).unwrap();
+    let sample_rate = player.get_gameboy().get_audio_sample_rate();
+    let mut wav = Vec::new();
+    player.render_to_wav(Cursor::new(&mut wav), 2).unwrap();
+
+    assert_eq!(&wav[0..4], b"RIFF");
+    let read_u32 = |index: usize| {
+        let mut value = [0; 4];
+        value.copy_from_slice(&wav[index..index + 4]);
+        u32::from_le_bytes(value)
+    };
+    assert_eq!(read_u32(24), sample_rate);
+    let data_size = read_u32(40) as usize;
+    assert_eq!(wav.len(), 44 + data_size);
+
+    // 16-bit stereo, within a video frame of the two seconds
+    let frames = data_size / 4;
+    let expected_frames = sample_rate as usize * 2;
+    let frame_samples = sample_rate as usize / 59;
+    assert!(
+        frames + frame_samples >= expected_frames && frames <= expected_frames + frame_samples,
+        "{} frames instead of {}",
+        frames,
+        expected_frames
+    );
+    assert!(wav[44..].iter().any(|byte| *byte != 0));
+}
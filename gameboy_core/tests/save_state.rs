@@ -0,0 +1,75 @@
+use gameboy_core::{Button, Gameboy, RTC};
+
+struct FixedRtc;
+
+impl RTC for FixedRtc {
+    fn get_current_time(&self) -> u64 {
+        0
+    }
+}
+
+// an MBC1 cartridge with 8KB of RAM that keeps mixing the joypad and DIV into WRAM
+fn build_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    let code = [
+        0x21, 0x00, 0xC0, // ld hl, $C000
+        0x3E, 0x20, 0xE0, 0x00, // select the directions
+        0xF0, 0x00, 0x86, 0x77, // add the joypad to (hl)
+        0xF0, 0x04, 0xAE, 0xEA, 0x01, 0xC0, // xor DIV into $C001
+        0x18, 0xF0, // loop
+    ];
+    rom[0x150..0x150 + code.len()].copy_from_slice(&code);
+    rom
+}
+
+fn run_frames(gameboy: &mut Gameboy, frames: usize) {
+    for _ in 0..frames {
+        gameboy.run_frame();
+    }
+}
+
+#[test]
+fn replays_the_same_frames_after_loading() {
+    let mut gameboy = Gameboy::from_rom(build_rom(), Box::new(FixedRtc)).unwrap();
+    gameboy.press_button(Button::Right);
+    run_frames(&mut gameboy, 20);
+    let state = gameboy.save_state();
+
+    run_frames(&mut gameboy, 30);
+    let pixels = gameboy.get_frame_buffer().get_pixels().to_vec();
+    let checksum = gameboy.get_checksum();
+
+    gameboy.load_state(&state).unwrap();
+    run_frames(&mut gameboy, 30);
+    assert_eq!(gameboy.get_frame_buffer().get_pixels(), &pixels[..]);
+    assert_eq!(gameboy.get_checksum(), checksum);
+}
+
+#[test]
+fn rejects_a_truncated_state() {
+    let mut gameboy = Gameboy::from_rom(build_rom(), Box::new(FixedRtc)).unwrap();
+    run_frames(&mut gameboy, 10);
+    let state = gameboy.save_state();
+    run_frames(&mut gameboy, 10);
+    let checksum = gameboy.get_checksum();
+
+    assert!(gameboy.load_state(&state[..state.len() - 1]).is_err());
+    assert_eq!(gameboy.get_checksum(), checksum);
+}
+
+#[test]
+fn rejects_a_state_from_another_rom() {
+    let mut other_rom = build_rom();
+    other_rom[0x7000] = 0x01;
+    let mut other = Gameboy::from_rom(other_rom, Box::new(FixedRtc)).unwrap();
+    run_frames(&mut other, 10);
+
+    let mut gameboy = Gameboy::from_rom(build_rom(), Box::new(FixedRtc)).unwrap();
+    run_frames(&mut gameboy, 20);
+    let checksum = gameboy.get_checksum();
+    assert!(gameboy.load_state(&other.save_state()).is_err());
+    assert_eq!(gameboy.get_checksum(), checksum);
+}
@@ -2,6 +2,8 @@
 extern crate clap;
 
 use clap::{App, Arg};
+use gameboy_core::sound::wav_writer::WavWriter;
+use gameboy_core::{GbsFile, GbsPlayer, Movie, VgmPlayer};
 use std::fs::File;
 use std::io::Read;
 
@@ -16,14 +18,100 @@ fn main() -> Result<(), String> {
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("sample rate")
+                .help("audio output rate in Hz")
+                .long("sample-rate")
+                .takes_value(true)
+                .possible_values(&["32000", "44100", "48000", "96000"])
+                .default_value("44100"),
+        )
+        .arg(
+            Arg::with_name("track")
+                .help("song of a GBS file to start with, counted from 1")
+                .long("track")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("render")
+                .help("renders a GBS track or a VGM file to a WAV file instead of playing it")
+                .long("render")
+                .takes_value(true)
+                .value_name("wav filename"),
+        )
+        .arg(
+            Arg::with_name("seconds")
+                .help("length of the rendered track, VGM files stop at their end")
+                .long("seconds")
+                .takes_value(true)
+                .default_value("120"),
+        )
+        .arg(
+            Arg::with_name("movie")
+                .help("plays back a movie recorded with the M key")
+                .long("movie")
+                .takes_value(true)
+                .value_name("movie filename"),
+        )
         .get_matches();
 
     let rom_filename = matches.value_of("rom filename").unwrap();
+    let sample_rate = value_t!(matches, "sample rate", i32).map_err(|e| format!("{:?}", e))?;
     let mut file = File::open(rom_filename).map_err(|e| format!("{:?}", e))?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)
         .map_err(|e| format!("{:?}", e))?;
-    gameboy_opengl::start(buffer)?;
+
+    if buffer.starts_with(b"GBS") {
+        let track = match matches.value_of("track") {
+            Some(_) => {
+                let track = value_t!(matches, "track", u8).map_err(|e| format!("{:?}", e))?;
+                Some(track.max(1) - 1)
+            }
+            None => None,
+        };
+        match matches.value_of("render") {
+            Some(wav_filename) => {
+                let seconds = value_t!(matches, "seconds", u32).map_err(|e| format!("{:?}", e))?;
+                let mut player = GbsPlayer::new(GbsFile::from_bytes(&buffer)?)?;
+                player
+                    .get_gameboy_mut()
+                    .set_audio_sample_rate(sample_rate as u32);
+                if let Some(track) = track {
+                    player.select_song(track)?;
+                }
+                let wav_file = File::create(wav_filename).map_err(|e| format!("{:?}", e))?;
+                player.render_to_wav(wav_file, seconds)?;
+            }
+            None => gameboy_opengl::start_gbs(buffer, sample_rate, track)?,
+        }
+    } else if buffer.starts_with(b"Vgm ") {
+        let wav_filename = matches
+            .value_of("render")
+            .ok_or_else(|| "VGM files can only be rendered, use --render".to_string())?;
+        let seconds = value_t!(matches, "seconds", u64).map_err(|e| format!("{:?}", e))?;
+        let mut player = VgmPlayer::from_bytes(&buffer)?;
+        player.get_sound_mut().set_sample_rate(sample_rate as u32);
+        let wav_file = File::create(wav_filename).map_err(|e| format!("{:?}", e))?;
+        let mut wav_writer = WavWriter::new(wav_file, 2, sample_rate as u32)?;
+        // one second at a time
+        for _ in 0..seconds {
+            if player.is_finished() {
+                break;
+            }
+            wav_writer.write_samples(player.run(4_194_304))?;
+        }
+        wav_writer.finish()?;
+    } else {
+        let movie = match matches.value_of("movie") {
+            Some(movie_filename) => {
+                let movie = std::fs::read(movie_filename).map_err(|e| format!("{:?}", e))?;
+                Some(Movie::from_bytes(&movie)?)
+            }
+            None => None,
+        };
+        gameboy_opengl::start(buffer, sample_rate, movie)?;
+    }
 
     Ok(())
 }
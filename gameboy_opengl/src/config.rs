@@ -0,0 +1,353 @@
+use directories::BaseDirs;
+use gameboy_core::{AudioChannel, Button, OpposingDirections};
+use sdl2::controller;
+use sdl2::keyboard::Keycode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+// the settings of the native frontend, read from config.toml in the config directory. a
+// file with the defaults is written on the first start, so there is something to edit.
+// keys and game controller buttons are named like SDL names them, e.g. "Z", "Space",
+// "Left Shift" or "F1" for keys and "a", "dpup" or "leftshoulder" for controller buttons
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub input: InputSettings,
+    pub speed: SpeedSettings,
+    pub keys: KeyBindings,
+    pub hotkeys: HotkeyBindings,
+    pub controller: ControllerBindings,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputSettings {
+    // how many frames a turbo button stays pressed and then released
+    pub turbo_frames: u32,
+    // "allow", "last-wins" or "cancel" for left and right or up and down held down together
+    pub opposing_directions: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeedSettings {
+    // how many times the normal speed the game runs at while the keys are held down. 0
+    // runs it as fast as it goes
+    pub fast_forward: f64,
+    pub slow_motion: f64,
+    // "mute" or "stretch" to play the audio along at the speed, which changes its pitch
+    pub audio: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub up: Vec<String>,
+    pub down: Vec<String>,
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+    pub a: Vec<String>,
+    pub b: Vec<String>,
+    pub start: Vec<String>,
+    pub select: Vec<String>,
+    pub turbo_a: Vec<String>,
+    pub turbo_b: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct HotkeyBindings {
+    pub pause: Vec<String>,
+    pub fast_forward: Vec<String>,
+    pub slow_motion: Vec<String>,
+    // pauses and runs a single frame
+    pub frame_advance: Vec<String>,
+    pub screenshot: Vec<String>,
+    pub reset: Vec<String>,
+    pub color_correction: Vec<String>,
+    // with shift, every channel is recorded to its own file as well
+    pub record_audio: Vec<String>,
+    pub log_vgm: Vec<String>,
+    // with shift, the movie starts from the current state instead of a restart
+    pub record_movie: Vec<String>,
+    pub mute_pulse1: Vec<String>,
+    pub mute_pulse2: Vec<String>,
+    pub mute_wave: Vec<String>,
+    pub mute_noise: Vec<String>,
+    // one key for every save state slot, in order. with shift the slot is loaded
+    pub state_slots: Vec<String>,
+    // brings back the state from before the last load
+    pub undo_load_state: Vec<String>,
+}
+
+// the face buttons are named after their position on an Xbox controller, "a" is the bottom
+// one and "b" the right one, which is where B and A are on the Game Boy
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControllerBindings {
+    // how far the left stick has to be pushed to press a direction, from 0 to 1
+    pub deadzone: f32,
+    pub up: Vec<String>,
+    pub down: Vec<String>,
+    pub left: Vec<String>,
+    pub right: Vec<String>,
+    pub a: Vec<String>,
+    pub b: Vec<String>,
+    pub start: Vec<String>,
+    pub select: Vec<String>,
+    pub turbo_a: Vec<String>,
+    pub turbo_b: Vec<String>,
+}
+
+fn keys(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+impl Default for InputSettings {
+    fn default() -> InputSettings {
+        InputSettings {
+            turbo_frames: 2,
+            opposing_directions: "last-wins".to_string(),
+        }
+    }
+}
+
+impl Default for SpeedSettings {
+    fn default() -> SpeedSettings {
+        SpeedSettings {
+            fast_forward: 4.0,
+            slow_motion: 0.5,
+            audio: "mute".to_string(),
+        }
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings {
+            up: keys(&["Up"]),
+            down: keys(&["Down"]),
+            left: keys(&["Left"]),
+            right: keys(&["Right"]),
+            a: keys(&["Z"]),
+            b: keys(&["X"]),
+            start: keys(&["Space"]),
+            select: keys(&["Return"]),
+            turbo_a: keys(&["A"]),
+            turbo_b: keys(&["S"]),
+        }
+    }
+}
+
+impl Default for HotkeyBindings {
+    fn default() -> HotkeyBindings {
+        HotkeyBindings {
+            pause: keys(&["P"]),
+            fast_forward: keys(&["Tab"]),
+            slow_motion: keys(&["Q"]),
+            frame_advance: keys(&["N"]),
+            screenshot: keys(&["F12"]),
+            reset: keys(&["Backspace"]),
+            color_correction: keys(&["C"]),
+            record_audio: keys(&["R"]),
+            log_vgm: keys(&["V"]),
+            record_movie: keys(&["M"]),
+            mute_pulse1: keys(&["1"]),
+            mute_pulse2: keys(&["2"]),
+            mute_wave: keys(&["3"]),
+            mute_noise: keys(&["4"]),
+            state_slots: keys(&["F1", "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10"]),
+            undo_load_state: keys(&["F11"]),
+        }
+    }
+}
+
+impl Default for ControllerBindings {
+    fn default() -> ControllerBindings {
+        ControllerBindings {
+            deadzone: 0.5,
+            up: keys(&["dpup"]),
+            down: keys(&["dpdown"]),
+            left: keys(&["dpleft"]),
+            right: keys(&["dpright"]),
+            a: keys(&["b"]),
+            b: keys(&["a"]),
+            start: keys(&["start"]),
+            select: keys(&["back"]),
+            turbo_a: keys(&["y"]),
+            turbo_b: keys(&["x"]),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Action {
+    Button(Button),
+    TurboButton(Button),
+    Pause,
+    FastForward,
+    SlowMotion,
+    FrameAdvance,
+    Screenshot,
+    Reset,
+    ColorCorrection,
+    RecordAudio,
+    LogVgm,
+    RecordMovie,
+    ToggleChannel(AudioChannel),
+    // counted from 0
+    StateSlot(usize),
+    UndoLoadState,
+}
+
+fn get_config_path() -> Option<PathBuf> {
+    let base_dir = BaseDirs::new()?;
+    let path_buf = base_dir
+        .config_dir()
+        .join("gameboy_emulator")
+        .join("config.toml");
+    Some(path_buf)
+}
+
+impl Config {
+    pub fn load() -> Result<Config, String> {
+        let config_path = match get_config_path() {
+            Some(config_path) => config_path,
+            None => return Ok(Config::default()),
+        };
+        if !config_path.exists() {
+            let config = Config::default();
+            if let Some(config_dir) = config_path.parent() {
+                fs::create_dir_all(config_dir).map_err(|e| format!("{:?}", e))?;
+            }
+            let contents = toml::to_string(&config).map_err(|e| format!("{:?}", e))?;
+            fs::write(&config_path, contents).map_err(|e| format!("{:?}", e))?;
+            return Ok(config);
+        }
+        let contents = fs::read_to_string(&config_path).map_err(|e| format!("{:?}", e))?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config file {}: {}", config_path.display(), e))
+    }
+
+    pub fn get_opposing_directions(&self) -> Result<OpposingDirections, String> {
+        match self.input.opposing_directions.as_str() {
+            "allow" => Ok(OpposingDirections::Allow),
+            "last-wins" => Ok(OpposingDirections::LastWins),
+            "cancel" => Ok(OpposingDirections::Cancel),
+            name => Err(format!(
+                "Unknown opposing directions policy in config: {}",
+                name
+            )),
+        }
+    }
+
+    // whether the audio is played at the speed of fast-forward and slow motion
+    pub fn stretches_audio(&self) -> Result<bool, String> {
+        match self.speed.audio.as_str() {
+            "mute" => Ok(false),
+            "stretch" => Ok(true),
+            name => Err(format!("Unknown speed audio mode in config: {}", name)),
+        }
+    }
+
+    // every key with the action it triggers
+    pub fn get_key_actions(&self) -> Result<HashMap<Keycode, Action>, String> {
+        let keys = &self.keys;
+        let hotkeys = &self.hotkeys;
+        let bindings = [
+            (&keys.up, Action::Button(Button::Up)),
+            (&keys.down, Action::Button(Button::Down)),
+            (&keys.left, Action::Button(Button::Left)),
+            (&keys.right, Action::Button(Button::Right)),
+            (&keys.a, Action::Button(Button::A)),
+            (&keys.b, Action::Button(Button::B)),
+            (&keys.start, Action::Button(Button::Start)),
+            (&keys.select, Action::Button(Button::Select)),
+            (&keys.turbo_a, Action::TurboButton(Button::A)),
+            (&keys.turbo_b, Action::TurboButton(Button::B)),
+            (&hotkeys.pause, Action::Pause),
+            (&hotkeys.fast_forward, Action::FastForward),
+            (&hotkeys.slow_motion, Action::SlowMotion),
+            (&hotkeys.frame_advance, Action::FrameAdvance),
+            (&hotkeys.screenshot, Action::Screenshot),
+            (&hotkeys.reset, Action::Reset),
+            (&hotkeys.color_correction, Action::ColorCorrection),
+            (&hotkeys.record_audio, Action::RecordAudio),
+            (&hotkeys.log_vgm, Action::LogVgm),
+            (&hotkeys.record_movie, Action::RecordMovie),
+            (
+                &hotkeys.mute_pulse1,
+                Action::ToggleChannel(AudioChannel::Pulse1),
+            ),
+            (
+                &hotkeys.mute_pulse2,
+                Action::ToggleChannel(AudioChannel::Pulse2),
+            ),
+            (
+                &hotkeys.mute_wave,
+                Action::ToggleChannel(AudioChannel::Wave),
+            ),
+            (
+                &hotkeys.mute_noise,
+                Action::ToggleChannel(AudioChannel::Noise),
+            ),
+            (&hotkeys.undo_load_state, Action::UndoLoadState),
+        ];
+        let slot_bindings = hotkeys
+            .state_slots
+            .iter()
+            .enumerate()
+            .map(|(slot, name)| (name, Action::StateSlot(slot)));
+        let key_bindings = bindings
+            .iter()
+            .flat_map(|(names, action)| names.iter().map(move |name| (name, *action)))
+            .chain(slot_bindings);
+
+        let mut key_actions = HashMap::new();
+        for (name, action) in key_bindings {
+            let keycode = Keycode::from_name(name)
+                .ok_or_else(|| format!("Unknown key in config: {}", name))?;
+            if key_actions.insert(keycode, action).is_some() {
+                return Err(format!("Key is bound twice in config: {}", name));
+            }
+        }
+        Ok(key_actions)
+    }
+
+    // every game controller button with the action it triggers, only buttons are bound
+    pub fn get_controller_actions(&self) -> Result<HashMap<controller::Button, Action>, String> {
+        let controller = &self.controller;
+        let bindings = [
+            (&controller.up, Action::Button(Button::Up)),
+            (&controller.down, Action::Button(Button::Down)),
+            (&controller.left, Action::Button(Button::Left)),
+            (&controller.right, Action::Button(Button::Right)),
+            (&controller.a, Action::Button(Button::A)),
+            (&controller.b, Action::Button(Button::B)),
+            (&controller.start, Action::Button(Button::Start)),
+            (&controller.select, Action::Button(Button::Select)),
+            (&controller.turbo_a, Action::TurboButton(Button::A)),
+            (&controller.turbo_b, Action::TurboButton(Button::B)),
+        ];
+
+        let mut controller_actions = HashMap::new();
+        for (names, action) in bindings.iter() {
+            for name in names.iter() {
+                let controller_button = controller::Button::from_string(name)
+                    .ok_or_else(|| format!("Unknown controller button in config: {}", name))?;
+                if controller_actions
+                    .insert(controller_button, *action)
+                    .is_some()
+                {
+                    return Err(format!(
+                        "Controller button is bound twice in config: {}",
+                        name
+                    ));
+                }
+            }
+        }
+        Ok(controller_actions)
+    }
+}
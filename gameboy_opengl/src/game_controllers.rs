@@ -0,0 +1,147 @@
+use crate::config::{Action, Config};
+use gameboy_core::{Button, Gameboy};
+use sdl2::controller::{self, Axis, GameController};
+use sdl2::event::Event;
+use sdl2::GameControllerSubsystem;
+use std::collections::HashMap;
+
+// what a controller holds down, so unplugging it only releases its own buttons
+#[derive(Default)]
+struct HeldInputs {
+    buttons: Vec<controller::Button>,
+    // the directions the left stick holds down
+    stick_x: Option<Button>,
+    stick_y: Option<Button>,
+}
+
+// the connected game controllers. SDL reports the controllers plugged in at the start as
+// added devices too, so they are all opened from the events
+pub struct GameControllers {
+    subsystem: GameControllerSubsystem,
+    controllers: Vec<GameController>,
+    actions: HashMap<controller::Button, Action>,
+    deadzone: i16,
+    // by the instance id of the controller
+    held: HashMap<u32, HeldInputs>,
+}
+
+impl GameControllers {
+    pub fn new(
+        subsystem: GameControllerSubsystem,
+        config: &Config,
+    ) -> Result<GameControllers, String> {
+        let deadzone = config.controller.deadzone.clamp(0.0, 1.0) * f32::from(i16::MAX);
+        Ok(GameControllers {
+            subsystem,
+            controllers: Vec::new(),
+            actions: config.get_controller_actions()?,
+            deadzone: deadzone as i16,
+            held: HashMap::new(),
+        })
+    }
+
+    pub fn handle_event(&mut self, event: &Event, emulator: &mut Gameboy) {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. }
+                if self.subsystem.is_game_controller(which) =>
+            {
+                match self.subsystem.open(which) {
+                    Ok(controller) => self.controllers.push(controller),
+                    Err(e) => println!("Could not open game controller: {}", e),
+                }
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers
+                    .retain(|controller| controller.instance_id() != which);
+                // whatever it held down would stay pressed otherwise
+                if let Some(held) = self.held.remove(&which) {
+                    for button in held.buttons.iter() {
+                        if let Some(action) = self.actions.get(button) {
+                            GameControllers::release(*action, emulator);
+                        }
+                    }
+                    for button in held.stick_x.iter().chain(held.stick_y.iter()) {
+                        emulator.release_button(*button);
+                    }
+                }
+            }
+            Event::ControllerButtonDown { which, button, .. } => {
+                match self.actions.get(&button) {
+                    Some(Action::Button(button)) => emulator.press_button(*button),
+                    Some(Action::TurboButton(button)) => emulator.press_turbo_button(*button),
+                    _ => return,
+                }
+                let buttons = &mut self.held.entry(which).or_default().buttons;
+                if !buttons.contains(&button) {
+                    buttons.push(button);
+                }
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                if let Some(held) = self.held.get_mut(&which) {
+                    held.buttons.retain(|held_button| *held_button != button);
+                }
+                if let Some(action) = self.actions.get(&button) {
+                    GameControllers::release(*action, emulator);
+                }
+            }
+            Event::ControllerAxisMotion {
+                which,
+                axis: Axis::LeftX,
+                value,
+                ..
+            } => {
+                let direction = self.get_stick_direction(value, Button::Left, Button::Right);
+                let held = self.held.entry(which).or_default();
+                GameControllers::update_stick(&mut held.stick_x, direction, emulator);
+            }
+            Event::ControllerAxisMotion {
+                which,
+                axis: Axis::LeftY,
+                value,
+                ..
+            } => {
+                let direction = self.get_stick_direction(value, Button::Up, Button::Down);
+                let held = self.held.entry(which).or_default();
+                GameControllers::update_stick(&mut held.stick_y, direction, emulator);
+            }
+            _ => (),
+        }
+    }
+
+    fn release(action: Action, emulator: &mut Gameboy) {
+        match action {
+            Action::Button(button) => emulator.release_button(button),
+            Action::TurboButton(button) => emulator.release_turbo_button(button),
+            _ => (),
+        }
+    }
+
+    fn get_stick_direction(
+        &self,
+        value: i16,
+        negative: Button,
+        positive: Button,
+    ) -> Option<Button> {
+        if value < -self.deadzone {
+            Some(negative)
+        } else if value > self.deadzone {
+            Some(positive)
+        } else {
+            None
+        }
+    }
+
+    // only the changes are passed on, so the stick at rest doesn't release the d-pad
+    fn update_stick(stick: &mut Option<Button>, direction: Option<Button>, emulator: &mut Gameboy) {
+        if *stick == direction {
+            return;
+        }
+        if let Some(button) = *stick {
+            emulator.release_button(button);
+        }
+        if let Some(button) = direction {
+            emulator.press_button(button);
+        }
+        *stick = direction;
+    }
+}
@@ -0,0 +1,104 @@
+use crate::config::{Action, Config};
+use crate::{get_queued_samples, AUDIO_LATENCY_MS, MAX_RATE_DEVIATION};
+use gameboy_core::{GbsFile, GbsPlayer, RateControl};
+use sdl2::audio::AudioSpecDesired;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use std::time::Duration;
+
+// plays a GBS file with a window that shows the current song, left and right switch songs
+pub fn start_gbs(gbs: Vec<u8>, sample_rate: i32, song: Option<u8>) -> Result<(), String> {
+    let mut player = GbsPlayer::new(GbsFile::from_bytes(&gbs)?)?;
+    let key_actions = Config::load()?.get_key_actions()?;
+
+    let sdl_context = sdl2::init()?;
+    let audio_subsystem = sdl_context.audio()?;
+    let desired_spec = AudioSpecDesired {
+        freq: Some(sample_rate),
+        channels: Some(2),
+        samples: Some(1024),
+    };
+    let device = audio_subsystem.open_queue(None, &desired_spec)?;
+    device.resume();
+
+    let sample_rate = device.spec().freq as usize;
+    player
+        .get_gameboy_mut()
+        .set_audio_sample_rate(sample_rate as u32);
+    if let Some(song) = song {
+        player.select_song(song)?;
+    }
+    let rate_control = RateControl::new(sample_rate * AUDIO_LATENCY_MS / 1000, MAX_RATE_DEVIATION);
+
+    let video_subsystem = sdl_context.video()?;
+    let window = video_subsystem
+        .window(&get_window_title(&player), 480, 120)
+        .position_centered()
+        .build()
+        .map_err(|e| format!("{:?}", e))?;
+    let mut canvas = window
+        .into_canvas()
+        .present_vsync()
+        .build()
+        .map_err(|e| format!("{:?}", e))?;
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+
+    let mut event_pump = sdl_context.event_pump()?;
+    'player_loop: loop {
+        while get_queued_samples(&device) > rate_control.get_target_fill() * 2 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        device.queue(player.run_frame());
+        let ratio = rate_control.get_ratio(get_queued_samples(&device));
+        player.get_gameboy_mut().set_audio_rate_ratio(ratio);
+
+        canvas.clear();
+        canvas.present();
+
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => break 'player_loop,
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    repeat: false,
+                    ..
+                } => {
+                    match keycode {
+                        Keycode::Right => player.next_song()?,
+                        Keycode::Left => player.previous_song()?,
+                        _ => {
+                            if let Some(Action::ToggleChannel(channel)) = key_actions.get(&keycode)
+                            {
+                                let gameboy = player.get_gameboy_mut();
+                                let muted = gameboy.is_channel_muted(*channel);
+                                gameboy.set_channel_muted(*channel, !muted);
+                            }
+                            continue;
+                        }
+                    }
+                    // the queued audio belongs to the previous song
+                    device.clear();
+                    canvas
+                        .window_mut()
+                        .set_title(&get_window_title(&player))
+                        .map_err(|e| format!("{:?}", e))?;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn get_window_title(player: &GbsPlayer) -> String {
+    let file = player.get_file();
+    format!(
+        "{} - {} ({}/{})",
+        file.get_title(),
+        file.get_author(),
+        player.get_song() + 1,
+        file.get_song_count()
+    )
+}
@@ -1,29 +1,48 @@
+mod config;
+mod game_controllers;
+mod gbs_player;
 mod native_rtc;
-mod screen;
+mod speed;
 
+pub use crate::gbs_player::start_gbs;
+
+use crate::config::{Action, Config};
+use crate::game_controllers::GameControllers;
 use crate::native_rtc::NativeRTC;
-use crate::screen::Screen;
+use crate::speed::{AudioStretcher, FramePacer, FRAME_DURATION};
 use directories::BaseDirs;
-use gameboy_core::{Button, Cartridge, Gameboy, Rtc, StepResult};
-use sdl2::audio::AudioSpecDesired;
+use gameboy_core::{
+    AudioChannel, AudioRecorder, Cartridge, FrameBuffer, Gameboy, Movie, RateControl, Rtc,
+    AUDIO_CHANNELS,
+};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::Mod;
 use sdl2::pixels::{Color, PixelFormatEnum};
 use std::cell::RefCell;
-use std::fs::{self, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// the save state slot the state before a load is kept in
+const UNDO_STATE_SLOT: &str = "undo";
+// how far the audio is queued ahead of the playback
+const AUDIO_LATENCY_MS: usize = 50;
+const MAX_RATE_DEVIATION: f64 = 0.005;
+const SCREEN_WIDTH: u32 = 160;
+const SCREEN_HEIGHT: u32 = 144;
 
-pub fn start(rom: Vec<u8>) -> Result<(), String> {
+pub fn start(rom: Vec<u8>, sample_rate: i32, movie: Option<Movie>) -> Result<(), String> {
+    let config = Config::load()?;
     let sdl_context = sdl2::init()?;
 
     let audio_subsystem = sdl_context.audio()?;
     let desired_spec = AudioSpecDesired {
-        freq: Some(44_100),
+        freq: Some(sample_rate),
         channels: Some(2),
-        samples: Some(4096),
+        samples: Some(1024),
     };
     let device = audio_subsystem.open_queue(None, &desired_spec)?;
     device.resume();
@@ -45,7 +64,7 @@ pub fn start(rom: Vec<u8>) -> Result<(), String> {
         .map_err(|e| format!("{:?}", e))?;
     let texture_creator = canvas.texture_creator();
     let mut texture = texture_creator
-        .create_texture_streaming(PixelFormatEnum::RGB24, 160, 144)
+        .create_texture_streaming(PixelFormatEnum::RGBA32, 160, 144)
         .map_err(|e| format!("{:?}", e))?;
 
     canvas.set_draw_color(Color::RGB(0, 0, 0));
@@ -53,6 +72,12 @@ pub fn start(rom: Vec<u8>) -> Result<(), String> {
 
     let rtc = Box::new(NativeRTC::new());
     let mut emulator = Gameboy::from_rom(rom, rtc)?;
+    // the device might not support the requested rate
+    let sample_rate = device.spec().freq as usize;
+    emulator.set_audio_sample_rate(sample_rate as u32);
+    emulator.set_turbo_frames(config.input.turbo_frames);
+    emulator.set_opposing_directions(config.get_opposing_directions()?);
+    let rate_control = RateControl::new(sample_rate * AUDIO_LATENCY_MS / 1000, MAX_RATE_DEVIATION);
 
     load_ram_save_data(emulator.get_cartridge_mut()).map_err(|e| format!("{:?}", e))?;
     load_timestamp_data(emulator.get_cartridge_mut()).map_err(|e| format!("{:?}", e))?;
@@ -67,35 +92,85 @@ pub fn start(rom: Vec<u8>) -> Result<(), String> {
             *ram_changed.borrow_mut() = true;
         }));
     }
-    let mut screen = Screen::new();
+
+    // a movie brings its own cartridge RAM, which must not end up in the save file
+    let playing_movie = movie.is_some();
+    if let Some(movie) = movie {
+        emulator.start_movie_playback(movie)?;
+    }
+    let mut movie_desync_reported = false;
+
+    let mut audio_recorder: Option<AudioRecorder<File>> = None;
+    let key_actions = config.get_key_actions()?;
+    let mut game_controllers = GameControllers::new(sdl_context.game_controller()?, &config)?;
+    let stretch_audio = config.stretches_audio()?;
+    let mut audio_stretcher = AudioStretcher::new();
+    let mut frame_pacer = FramePacer::new();
+    let mut paused = false;
+    let mut frame_advance = false;
+    let mut fast_forward = false;
+    let mut slow_motion = false;
 
     let mut event_pump = sdl_context.event_pump()?;
     'game_loop: loop {
-        loop {
-            let step_result = emulator.emulate(&mut screen);
-            match step_result {
-                StepResult::VBlank => {
-                    let frame_buffer = screen.get_frame_buffer();
-                    texture.with_lock(None, |buffer, _| buffer.clone_from_slice(frame_buffer))?;
-                    canvas.clear();
-                    canvas.copy(&texture, None, None)?;
-                    canvas.present();
-                    std::thread::sleep(Duration::from_millis(10));
-                    break;
-                }
-                StepResult::AudioBufferFull => {
-                    let audio_buffer = emulator.get_audio_buffer();
-                    while device.size() > (audio_buffer.len() * std::mem::size_of::<f32>()) as u32 {
+        let speed = if fast_forward {
+            config.speed.fast_forward
+        } else if slow_motion {
+            config.speed.slow_motion
+        } else {
+            1.0
+        };
+        if frame_advance {
+            run_frame(&mut emulator, &mut audio_recorder)?;
+            frame_advance = false;
+        } else if !paused {
+            if speed > 0.0 && (speed == 1.0 || stretch_audio) {
+                // the audio paces the emulation, it only waits when the queue is far ahead.
+                // the rate control keeps it from getting there when the display is a bit
+                // faster. at other speeds every frame has less or more audio to wait for
+                for _ in 0..speed.ceil() as usize {
+                    run_frame(&mut emulator, &mut audio_recorder)?;
+                    while get_queued_samples(&device) > rate_control.get_target_fill() * 2 {
                         std::thread::sleep(Duration::from_millis(1));
                     }
-                    device.queue(audio_buffer);
-                    break;
+                    if speed == 1.0 {
+                        device.queue(emulator.get_frame_audio());
+                    } else {
+                        device.queue(audio_stretcher.stretch(emulator.get_frame_audio(), speed));
+                    }
+                    emulator
+                        .set_audio_rate_ratio(rate_control.get_ratio(get_queued_samples(&device)));
+                }
+            } else if speed > 0.0 {
+                for _ in 0..frame_pacer.wait_for_frames(speed) {
+                    run_frame(&mut emulator, &mut audio_recorder)?;
+                }
+            } else {
+                // as fast as it goes, the screen is updated as often as at the normal speed
+                let start = Instant::now();
+                while start.elapsed() < FRAME_DURATION {
+                    run_frame(&mut emulator, &mut audio_recorder)?;
+                }
+            }
+        }
+        let frame_buffer = emulator.get_frame_buffer();
+        texture
+            .update(None, frame_buffer.get_pixels(), frame_buffer.get_pitch())
+            .map_err(|e| format!("{:?}", e))?;
+        canvas.clear();
+        canvas.copy(&texture, None, None)?;
+        canvas.present();
+
+        if playing_movie {
+            if let Some(frame) = emulator.get_movie_desync_frame() {
+                if !movie_desync_reported {
+                    println!("Movie desynced at frame {}", frame);
+                    movie_desync_reported = true;
                 }
-                StepResult::Nothing => (),
             }
         }
 
-        if *ram_changed.borrow() && emulator.get_cartridge().has_battery() {
+        if *ram_changed.borrow() && emulator.get_cartridge().has_battery() && !playing_movie {
             if let Some(ref mut ram_save_file) = ram_save_file {
                 save_ram_data(emulator.get_cartridge(), ram_save_file)
                     .map_err(|e| format!("{:?}", e))?;
@@ -103,7 +178,7 @@ pub fn start(rom: Vec<u8>) -> Result<(), String> {
             *ram_changed.borrow_mut() = false;
         }
 
-        if emulator.get_cartridge().has_rtc() {
+        if emulator.get_cartridge().has_rtc() && !playing_movie {
             if let Some(ref mut timestamp_save_file) = timestamp_save_file {
                 save_timestamp_data(emulator.get_cartridge(), timestamp_save_file)
                     .map_err(|e| format!("{:?}", e))?;
@@ -115,40 +190,136 @@ pub fn start(rom: Vec<u8>) -> Result<(), String> {
                 Event::Quit { .. } => break 'game_loop,
                 Event::KeyDown {
                     keycode: Some(keycode),
+                    keymod,
+                    repeat,
                     ..
-                } => {
-                    if let Some(button) = keycode_to_button(keycode) {
-                        emulator.press_button(button);
+                } => match key_actions.get(&keycode) {
+                    Some(Action::Button(button)) => emulator.press_button(*button),
+                    Some(Action::TurboButton(button)) => emulator.press_turbo_button(*button),
+                    Some(_) if repeat => (),
+                    Some(Action::Pause) => paused = !paused,
+                    Some(Action::FastForward) => fast_forward = true,
+                    Some(Action::SlowMotion) => slow_motion = true,
+                    Some(Action::FrameAdvance) => {
+                        paused = true;
+                        frame_advance = true;
                     }
-                }
+                    Some(Action::Screenshot) => save_screenshot(&emulator)?,
+                    // a reset can't be reproduced by a movie
+                    Some(Action::Reset) if !emulator.is_recording_movie() && !playing_movie => {
+                        emulator.reset()?
+                    }
+                    Some(Action::ColorCorrection) => {
+                        let color_correction = emulator.get_color_correction().next();
+                        emulator.set_color_correction(color_correction);
+                    }
+                    Some(Action::RecordAudio) => match audio_recorder.take() {
+                        Some(recorder) => recorder.finish()?,
+                        None => {
+                            // shift also records every channel to its own file
+                            let with_channel_tracks =
+                                keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                            audio_recorder =
+                                Some(start_audio_recording(&emulator, with_channel_tracks)?);
+                        }
+                    },
+                    Some(Action::LogVgm) => match emulator.stop_vgm_log() {
+                        Some(vgm) => {
+                            let path = get_recording_file_path(&emulator, ".vgm")?;
+                            fs::write(path, vgm).map_err(|e| format!("{:?}", e))?;
+                        }
+                        None => emulator.start_vgm_log(),
+                    },
+                    Some(Action::RecordMovie) => {
+                        if emulator.is_recording_movie() {
+                            save_movie(&mut emulator)?;
+                        } else if !playing_movie {
+                            let rtc_seed = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .map_err(|e| format!("{:?}", e))?
+                                .as_secs();
+                            if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                                emulator.start_movie_recording_from_state(rtc_seed)?;
+                            } else {
+                                emulator.start_movie_recording(rtc_seed)?;
+                            }
+                        }
+                    }
+                    Some(Action::ToggleChannel(channel)) => {
+                        let muted = emulator.is_channel_muted(*channel);
+                        emulator.set_channel_muted(*channel, !muted);
+                    }
+                    Some(Action::StateSlot(slot)) => {
+                        let slot = (slot + 1).to_string();
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            // the state before the load can be brought back. a load that
+                            // fails keeps the one from before
+                            let state = emulator.save_state();
+                            let thumbnail = emulator.get_frame_buffer().clone();
+                            if load_state_slot(&mut emulator, &slot) {
+                                write_state_slot(&emulator, UNDO_STATE_SLOT, &state, &thumbnail)?;
+                            }
+                        } else {
+                            save_state_slot(&emulator, &slot)?;
+                            println!("Saved state {}", slot);
+                        }
+                    }
+                    Some(Action::UndoLoadState) => {
+                        load_state_slot(&mut emulator, UNDO_STATE_SLOT);
+                    }
+                    _ => (),
+                },
                 Event::KeyUp {
                     keycode: Some(keycode),
                     ..
-                } => {
-                    if let Some(button) = keycode_to_button(keycode) {
-                        emulator.release_button(button);
-                    }
-                }
-                _ => (),
+                } => match key_actions.get(&keycode) {
+                    Some(Action::Button(button)) => emulator.release_button(*button),
+                    Some(Action::TurboButton(button)) => emulator.release_turbo_button(*button),
+                    Some(Action::FastForward) => fast_forward = false,
+                    Some(Action::SlowMotion) => slow_motion = false,
+                    _ => (),
+                },
+                event => game_controllers.handle_event(&event, &mut emulator),
             };
         }
     }
 
+    if let Some(audio_recorder) = audio_recorder {
+        audio_recorder.finish()?;
+    }
+    if let Some(vgm) = emulator.stop_vgm_log() {
+        let path = get_recording_file_path(&emulator, ".vgm")?;
+        fs::write(path, vgm).map_err(|e| format!("{:?}", e))?;
+    }
+    if emulator.is_recording_movie() {
+        save_movie(&mut emulator)?;
+    }
+
     Ok(())
 }
 
-fn keycode_to_button(keycode: Keycode) -> Option<Button> {
-    match keycode {
-        Keycode::Z => Some(Button::A),
-        Keycode::X => Some(Button::B),
-        Keycode::Space => Some(Button::Start),
-        Keycode::Return => Some(Button::Select),
-        Keycode::Up => Some(Button::Up),
-        Keycode::Down => Some(Button::Down),
-        Keycode::Left => Some(Button::Left),
-        Keycode::Right => Some(Button::Right),
-        _ => None,
+fn run_frame(
+    emulator: &mut Gameboy,
+    audio_recorder: &mut Option<AudioRecorder<File>>,
+) -> Result<(), String> {
+    emulator.run_frame();
+    if let Some(ref mut audio_recorder) = audio_recorder {
+        audio_recorder.record_frame(emulator)?;
     }
+    Ok(())
+}
+
+fn save_movie(emulator: &mut Gameboy) -> Result<(), String> {
+    if let Some(movie) = emulator.stop_movie() {
+        let path = get_recording_file_path(emulator, ".gbm")?;
+        fs::write(path, movie.to_bytes()).map_err(|e| format!("{:?}", e))?;
+    }
+    Ok(())
+}
+
+// samples per channel waiting to be played
+fn get_queued_samples(device: &AudioQueue<f32>) -> usize {
+    device.size() as usize / (2 * std::mem::size_of::<f32>())
 }
 
 fn get_ram_saves_path() -> Option<PathBuf> {
@@ -160,6 +331,162 @@ fn get_ram_saves_path() -> Option<PathBuf> {
     Some(path_buf)
 }
 
+fn get_recordings_path() -> Option<PathBuf> {
+    let base_dir = BaseDirs::new()?;
+    let path_buf = base_dir
+        .config_dir()
+        .join("gameboy_emulator")
+        .join("recordings");
+    Some(path_buf)
+}
+
+fn get_screenshots_path() -> Option<PathBuf> {
+    let base_dir = BaseDirs::new()?;
+    let path_buf = base_dir
+        .config_dir()
+        .join("gameboy_emulator")
+        .join("screenshots");
+    Some(path_buf)
+}
+
+fn save_screenshot(emulator: &Gameboy) -> Result<(), String> {
+    let screenshots_path =
+        get_screenshots_path().ok_or_else(|| "no screenshots directory".to_string())?;
+    fs::create_dir_all(&screenshots_path).map_err(|e| format!("{:?}", e))?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("{:?}", e))?
+        .as_millis();
+    let name = emulator.get_cartridge().get_name();
+    let path = screenshots_path.join(format!("{}-{}.png", name, timestamp));
+    let file = File::create(path).map_err(|e| format!("{:?}", e))?;
+    write_png(file, emulator.get_frame_buffer())
+}
+
+// the save states are kept with the battery saves, every slot has a file with the time it
+// was saved followed by the state, and a screenshot as a thumbnail
+fn get_state_slot_path(emulator: &Gameboy, slot: &str, extension: &str) -> Result<PathBuf, String> {
+    let ram_saves_path = get_ram_saves_path().ok_or_else(|| "no saves directory".to_string())?;
+    fs::create_dir_all(&ram_saves_path).map_err(|e| format!("{:?}", e))?;
+    let name = emulator.get_cartridge().get_name();
+    Ok(ram_saves_path.join(format!("{}-state-{}.{}", name, slot, extension)))
+}
+
+fn save_state_slot(emulator: &Gameboy, slot: &str) -> Result<(), String> {
+    let state = emulator.save_state();
+    write_state_slot(emulator, slot, &state, emulator.get_frame_buffer())
+}
+
+fn write_state_slot(
+    emulator: &Gameboy,
+    slot: &str,
+    state: &[u8],
+    thumbnail: &FrameBuffer,
+) -> Result<(), String> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("{:?}", e))?
+        .as_secs();
+    let mut bytes = timestamp.to_le_bytes().to_vec();
+    bytes.extend_from_slice(state);
+    fs::write(get_state_slot_path(emulator, slot, "state")?, bytes)
+        .map_err(|e| format!("{:?}", e))?;
+    let file = File::create(get_state_slot_path(emulator, slot, "png")?)
+        .map_err(|e| format!("{:?}", e))?;
+    write_png(file, thumbnail)
+}
+
+// an empty slot or a state that doesn't fit the game only gets reported. the battery save
+// isn't touched, it keeps the cartridge RAM until the game writes to it again
+fn load_state_slot(emulator: &mut Gameboy, slot: &str) -> bool {
+    let result = get_state_slot_path(emulator, slot, "state").and_then(|path| {
+        let bytes = fs::read(path).map_err(|_| "the slot is empty".to_string())?;
+        if bytes.len() < 8 {
+            return Err("Not a save state".to_string());
+        }
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&bytes[0..8]);
+        emulator.load_state(&bytes[8..])?;
+        Ok(u64::from_le_bytes(timestamp))
+    });
+    match result {
+        Ok(timestamp) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(timestamp);
+            println!(
+                "Loaded state {}, saved {} ago",
+                slot,
+                format_age(now.saturating_sub(timestamp))
+            );
+            true
+        }
+        Err(e) => {
+            println!("Could not load state {}: {}", slot, e);
+            false
+        }
+    }
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{} seconds", seconds),
+        60..=3599 => format!("{} minutes", seconds / 60),
+        3600..=86399 => format!("{} hours", seconds / 3600),
+        _ => format!("{} days", seconds / 86400),
+    }
+}
+
+// the frame buffer of the native frontend is always RGBA
+fn write_png<W: Write>(writer: W, frame_buffer: &FrameBuffer) -> Result<(), String> {
+    let mut encoder = png::Encoder::new(writer, SCREEN_WIDTH, SCREEN_HEIGHT);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut png_writer = encoder.write_header().map_err(|e| format!("{:?}", e))?;
+    png_writer
+        .write_image_data(frame_buffer.get_pixels())
+        .map_err(|e| format!("{:?}", e))
+}
+
+// the recordings are named after the game and the time they were started
+fn get_recording_file_path(emulator: &Gameboy, suffix: &str) -> Result<PathBuf, String> {
+    let recordings_path =
+        get_recordings_path().ok_or_else(|| "no recordings directory".to_string())?;
+    fs::create_dir_all(&recordings_path).map_err(|e| format!("{:?}", e))?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("{:?}", e))?
+        .as_secs();
+    let name = emulator.get_cartridge().get_name();
+    Ok(recordings_path.join(format!("{}-{}{}", name, timestamp, suffix)))
+}
+
+fn start_audio_recording(
+    emulator: &Gameboy,
+    with_channel_tracks: bool,
+) -> Result<AudioRecorder<File>, String> {
+    let create_file = |suffix: &str| {
+        let path = get_recording_file_path(emulator, &format!("{}.wav", suffix))?;
+        File::create(path).map_err(|e| format!("{:?}", e))
+    };
+
+    let file = create_file("")?;
+    let mut channel_files = Vec::new();
+    if with_channel_tracks {
+        for channel in AUDIO_CHANNELS.iter() {
+            let suffix = match channel {
+                AudioChannel::Pulse1 => "-pulse1",
+                AudioChannel::Pulse2 => "-pulse2",
+                AudioChannel::Wave => "-wave",
+                AudioChannel::Noise => "-noise",
+            };
+            channel_files.push(create_file(suffix)?);
+        }
+    }
+    AudioRecorder::with_channel_tracks(emulator, file, channel_files)
+}
+
 fn load_ram_save_data(cartridge: &mut Cartridge) -> std::io::Result<()> {
     if cartridge.has_battery() {
         if let Some(ram_saves_dir) = get_ram_saves_path() {
@@ -0,0 +1,92 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 70224 cycles at 4194304 Hz
+pub const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+// after a stall the pacer starts over instead of running the missed frames all at once
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// paces the emulation by the clock, for when it runs at another speed with the audio muted
+// and the audio queue can't do it
+pub struct FramePacer {
+    next_frame_time: Instant,
+}
+
+impl FramePacer {
+    pub fn new() -> FramePacer {
+        FramePacer {
+            next_frame_time: Instant::now(),
+        }
+    }
+
+    // waits for the next frame to be due and returns how many frames are due by then
+    pub fn wait_for_frames(&mut self, speed: f64) -> usize {
+        let now = Instant::now();
+        if self.next_frame_time > now {
+            thread::sleep(self.next_frame_time - now);
+        }
+        let now = Instant::now();
+        if now > self.next_frame_time + MAX_LAG {
+            self.next_frame_time = now;
+        }
+        let frame_duration = FRAME_DURATION.div_f64(speed);
+        let mut frames = 0;
+        while self.next_frame_time <= now {
+            self.next_frame_time += frame_duration;
+            frames += 1;
+        }
+        frames
+    }
+}
+
+// plays the audio at the speed of the emulation by resampling it, like a tape played
+// faster or slower. the pitch goes up and down with the speed
+pub struct AudioStretcher {
+    // where the next output sample is taken from, in frames of the next input. -1 is the
+    // last frame of the previous input
+    position: f64,
+    previous: [f32; 2],
+    samples: Vec<f32>,
+}
+
+impl AudioStretcher {
+    pub fn new() -> AudioStretcher {
+        AudioStretcher {
+            position: 0.0,
+            previous: [0.0; 2],
+            samples: Vec::new(),
+        }
+    }
+
+    // takes and returns interleaved stereo samples
+    pub fn stretch(&mut self, samples: &[f32], speed: f64) -> &[f32] {
+        self.samples.clear();
+        let frames = samples.len() / 2;
+        if frames == 0 {
+            return &self.samples;
+        }
+
+        let previous = self.previous;
+        let get_sample = |frame: isize, channel: usize| {
+            if frame < 0 {
+                previous[channel]
+            } else {
+                samples[frame as usize * 2 + channel]
+            }
+        };
+        while self.position < (frames - 1) as f64 {
+            let frame = self.position.floor();
+            let fraction = (self.position - frame) as f32;
+            let frame = frame as isize;
+            for channel in 0..2 {
+                let a = get_sample(frame, channel);
+                let b = get_sample(frame + 1, channel);
+                self.samples.push(a + (b - a) * fraction);
+            }
+            self.position += speed;
+        }
+        self.position -= frames as f64;
+        self.previous = [samples[frames * 2 - 2], samples[frames * 2 - 1]];
+        &self.samples
+    }
+}
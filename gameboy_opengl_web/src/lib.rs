@@ -6,12 +6,12 @@ extern crate stdweb;
 #[macro_use]
 extern crate serde_derive;
 
-mod screen;
 mod web_rtc;
 
-use crate::screen::Screen;
 use crate::web_rtc::WebRTC;
-use gameboy_core::{Button, Cartridge, ControllerEvent, Gameboy, Rtc, StepResult};
+use gameboy_core::{
+    Button, Cartridge, ControllerEvent, FrameBuffer, Gameboy, PixelFormat, Rtc, StepResult,
+};
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::mpsc;
@@ -26,7 +26,7 @@ use stdweb::Value;
 
 struct EmulatorState {
     gameboy: Gameboy,
-    screen: Screen,
+    frame_buffer: FrameBuffer,
     controller_receiver: mpsc::Receiver<ControllerEvent>,
     should_save_to_local: Rc<RefCell<bool>>,
     ram_str: Rc<RefCell<String>>,
@@ -38,7 +38,7 @@ struct EmulatorState {
 impl EmulatorState {
     pub fn emulate_until_vblank_or_audio(&mut self) -> StepResult {
         let step_result = loop {
-            let step_result = self.gameboy.emulate(&mut self.screen);
+            let step_result = self.gameboy.emulate(&mut self.frame_buffer);
             match step_result {
                 StepResult::VBlank | StepResult::AudioBufferFull => {
                     break step_result;
@@ -110,7 +110,7 @@ impl EmulatorState {
     }
 
     pub fn render(&self) {
-        let frame_buffer: &[u8] = self.screen.get_frame_buffer();
+        let frame_buffer: &[u8] = self.frame_buffer.get_pixels();
         js! {
             var h = @{&self.js_ctx};
             var frame_buffer = @{TypedArray::<u8>::from(frame_buffer)};
@@ -275,12 +275,12 @@ pub fn start(rom: Vec<u8>, dom_ids: DOMInfo) -> Result<(), String> {
     let ram_str = Rc::new(RefCell::new(
         ram.iter().map(|byte| format!("{:02x}", byte)).collect(),
     ));
-    let screen = Screen::new();
+    let frame_buffer = FrameBuffer::new(PixelFormat::Rgba8888);
 
     let mut emulator_state = EmulatorState {
         //from opengl_web
         gameboy,
-        screen,
+        frame_buffer,
         controller_receiver: receiver,
         should_save_to_local,
         ram_str,